@@ -1,16 +1,25 @@
 use crate::command;
+use crate::session;
 use crate::ui;
 use color_eyre::Result;
 use futures_channel::mpsc::UnboundedSender;
 use ratatui::DefaultTerminal;
 use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
 use shared::*;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, mpsc::Receiver};
 use std::time::{Duration, Instant};
 use uuid::Uuid;
 
 const FAST_SCROLL: usize = 10;
 const DEFAULT_SCROLL: usize = 1;
+const DEFAULT_REACTION: &str = "👍";
+/// How often the member list is refreshed while visible, so that members
+/// whose presence expired disappear.
+const MEMBERS_REFRESH: Duration = Duration::from_secs(15);
+/// How often we tell the room that we are still typing.
+const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+/// How long somebody is shown as typing after their last notice.
+const TYPING_EXPIRY: Duration = Duration::from_secs(5);
 
 pub struct App {
     pub input: String,
@@ -21,9 +30,51 @@ pub struct App {
     pub auto_scroll: bool,
     pub communication: Arc<Mutex<Vec<TalkProtocol>>>,
     pub tx: UnboundedSender<TalkProtocol>,
+    pub events: Receiver<TalkProtocol>,
+    pub protocol_version: u16,
+    pub capabilities: Capabilities,
     pub username: String,
     pub room: i32,
     pub uuid: Uuid,
+    pub authenticated: bool,
+    /// The oldest message of the room has been fetched.
+    pub history_complete: bool,
+    /// Id of the message selected in Normal mode.
+    pub selected: Option<u64>,
+    /// Id of the message the input is going to answer.
+    pub reply_to: Option<u64>,
+    pub show_members: bool,
+    pub members: Vec<Member>,
+    members_requested: Instant,
+    /// Who else is typing in the room, and when we last heard of it.
+    pub typing: Vec<(Uuid, String, Instant)>,
+    typing_sent: Option<Instant>,
+    pub view: View,
+    /// All direct messages we know of, oldest first.
+    pub directs: Vec<DirectMessage>,
+    /// Who plain input goes to in the direct message view.
+    pub direct_partner: Option<(Uuid, String)>,
+    pub unread_directs: usize,
+    /// Name and topic of the current room, if it has a name.
+    pub room_info: Option<Room>,
+    /// Room name we asked the server about, joined once it answers.
+    pub pending_room: Option<String>,
+    /// Whether the room browser popup is open.
+    pub show_rooms: bool,
+    pub rooms: Vec<RoomSummary>,
+    pub rooms_selected: usize,
+    /// Kicked or banned from the current room, so it isn't rejoined behind
+    /// the user's back.
+    pub evicted: bool,
+    /// Whether the search results popup is open.
+    pub show_search: bool,
+    pub search_query: String,
+    pub search_hits: Vec<SearchHit>,
+    pub search_selected: usize,
+    /// Search hit we are paging back through the history for.
+    pub jump_to: Option<u64>,
+    /// Scroll the selected message into view on the next draw.
+    pub scroll_to_selected: bool,
 }
 
 pub enum InputMode {
@@ -31,10 +82,17 @@ pub enum InputMode {
     Editing,
 }
 
+#[derive(PartialEq)]
+pub enum View {
+    Room,
+    Direct,
+}
+
 impl App {
     pub fn new(
         transmit: UnboundedSender<TalkProtocol>,
         com: Arc<Mutex<Vec<TalkProtocol>>>,
+        events: Receiver<TalkProtocol>,
     ) -> Self {
         Self {
             input: String::new(),
@@ -45,9 +103,37 @@ impl App {
             auto_scroll: true,
             character_index: 0,
             tx: transmit,
+            events,
+            protocol_version: 0,
+            capabilities: Capabilities::LEGACY,
             username: "Client".to_string(),
             room: 0,
-            uuid: Uuid::new_v4(),
+            uuid: Uuid::nil(),
+            authenticated: false,
+            history_complete: false,
+            selected: None,
+            reply_to: None,
+            show_members: false,
+            members: Vec::new(),
+            members_requested: Instant::now(),
+            typing: Vec::new(),
+            typing_sent: None,
+            view: View::Room,
+            directs: Vec::new(),
+            direct_partner: None,
+            unread_directs: 0,
+            room_info: None,
+            pending_room: None,
+            show_rooms: false,
+            rooms: Vec::new(),
+            rooms_selected: 0,
+            evicted: false,
+            show_search: false,
+            search_query: String::new(),
+            search_hits: Vec::new(),
+            search_selected: 0,
+            jump_to: None,
+            scroll_to_selected: false,
         }
     }
 
@@ -65,6 +151,26 @@ impl App {
         let index = self.byte_index();
         self.input.insert(index, new_char);
         self.move_cursor_right();
+        self.send_typing();
+    }
+
+    /// Announces that we are typing, at most once per `TYPING_THROTTLE`.
+    /// Commands are not announced.
+    fn send_typing(&mut self) {
+        if !self.authenticated
+            || self.view == View::Direct
+            || self.input.starts_with('/')
+            || !self.capabilities.contains(Capabilities::TYPING)
+            || self.typing_sent.is_some_and(|sent| sent.elapsed() < TYPING_THROTTLE)
+        {
+            return;
+        }
+        self.typing_sent = Some(Instant::now());
+        let _ = self.tx.unbounded_send(TalkProtocol::Typing {
+            room_id: self.room,
+            uuid: self.uuid,
+            username: self.username.clone(),
+        });
     }
 
     fn byte_index(&self) -> usize {
@@ -97,6 +203,7 @@ impl App {
     }
 
     fn submit_message(&mut self) {
+        self.typing_sent = None;
         let _ = command::parse(self);
         self.input.clear();
         self.reset_cursor();
@@ -104,8 +211,17 @@ impl App {
 
     pub fn run(mut self, mut terminal: DefaultTerminal) -> Result<()> {
         let tick_rate = Duration::from_millis(100);
-        let _ = command::join_initial_room(&mut self);
+        let _ = command::send_hello(&mut self);
+        let _ = command::resume_session(&mut self);
         loop {
+            while let Ok(event) = self.events.try_recv() {
+                self.handle_event(event);
+            }
+            if self.show_members && self.members_requested.elapsed() >= MEMBERS_REFRESH {
+                self.request_members();
+            }
+            self.typing
+                .retain(|(_, _, since)| since.elapsed() < TYPING_EXPIRY);
             terminal.draw(|frame| self.draw(frame))?;
 
             let last_tick = Instant::now();
@@ -113,76 +229,548 @@ impl App {
                 .checked_sub(last_tick.elapsed())
                 .unwrap_or(Duration::from_secs(0));
 
-            if event::poll(timeout)? {
-                if let Event::Key(key) = event::read()? {
-                    match self.input_mode {
-                        InputMode::Normal => match key.code {
-                            KeyCode::Char('i') => {
+            if event::poll(timeout)?
+                && let Event::Key(key) = event::read()?
+            {
+                match self.input_mode {
+                    InputMode::Normal if self.show_search => match key.code {
+                        KeyCode::Up => {
+                            self.search_selected = self.search_selected.saturating_sub(1);
+                        }
+                        KeyCode::Down if self.search_selected + 1 < self.search_hits.len() => {
+                            self.search_selected += 1;
+                        }
+                        KeyCode::Enter => {
+                            self.show_search = false;
+                            if let Some(hit) = self.search_hits.get(self.search_selected) {
+                                let message_id = hit.message.id;
+                                self.jump_to_message(message_id);
+                            }
+                        }
+                        KeyCode::Esc => self.show_search = false,
+                        _ => {}
+                    },
+                    InputMode::Normal if self.show_rooms => match key.code {
+                        KeyCode::Up => {
+                            self.rooms_selected = self.rooms_selected.saturating_sub(1);
+                        }
+                        KeyCode::Down if self.rooms_selected + 1 < self.rooms.len() => {
+                            self.rooms_selected += 1;
+                        }
+                        KeyCode::Enter => {
+                            self.show_rooms = false;
+                            if let Some(summary) = self.rooms.get(self.rooms_selected)
+                                && summary.room.id != self.room
+                            {
+                                let room_id = summary.room.id;
+                                let _ = command::switch_room(&mut self, room_id);
+                            }
+                        }
+                        KeyCode::Esc | KeyCode::Char('o') => self.show_rooms = false,
+                        _ => {}
+                    },
+                    InputMode::Normal => match key.code {
+                        KeyCode::Char('i') => {
+                            self.input_mode = InputMode::Editing;
+                        }
+                        KeyCode::Char('q') => {
+                            let _ = command::quit_app(&mut self);
+                            return Ok(());
+                        }
+                        KeyCode::Up => self.move_selection(true),
+                        KeyCode::Down => self.move_selection(false),
+                        KeyCode::Esc => self.selected = None,
+                        KeyCode::Char('d') => self.toggle_view(),
+                        KeyCode::Char('o') => self.open_rooms(),
+                        KeyCode::Char('m') => {
+                            self.show_members = !self.show_members;
+                            if self.show_members {
+                                self.request_members();
+                            }
+                        }
+                        KeyCode::Char('+') => {
+                            if let Some(selected) = self.selected {
+                                let _ = command::toggle_reaction(&mut self, selected, DEFAULT_REACTION);
+                            }
+                        }
+                        KeyCode::Char('r') => {
+                            if let Some(selected) = self.selected.take() {
+                                self.reply_to = Some(selected);
                                 self.input_mode = InputMode::Editing;
                             }
-                            KeyCode::Char('q') => {
-                                let _ = command::quit_app(&mut self);
-                                return Ok(());
+                        }
+                        KeyCode::Char('g') => {
+                            self.scroll = self.max_scroll;
+                            self.auto_scroll = true;
+                        }
+                        KeyCode::Char('G') => {
+                            self.auto_scroll = false;
+                            self.scroll = 0;
+                        }
+                        KeyCode::Char('k') => {
+                            if self.scroll < self.max_scroll {
+                                self.scroll += DEFAULT_SCROLL;
                             }
-                            KeyCode::Char('g') => {
-                                self.scroll = self.max_scroll;
+                            if self.scroll >= self.max_scroll {
                                 self.auto_scroll = true;
                             }
-                            KeyCode::Char('G') => {
-                                self.auto_scroll = false;
-                                self.scroll = 0;
-                            }
-                            KeyCode::Char('k') => {
-                                if self.scroll < self.max_scroll {
-                                    self.scroll += DEFAULT_SCROLL;
-                                }
-                                if self.scroll >= self.max_scroll {
-                                    self.auto_scroll = true;
-                                }
+                        }
+                        KeyCode::Char('K') => {
+                            if self.max_scroll >= FAST_SCROLL && self.scroll < self.max_scroll - FAST_SCROLL {
+                                self.scroll += FAST_SCROLL;
+                            } else {
+                                self.scroll = self.max_scroll;
                             }
-                            KeyCode::Char('K') => {
-                                if self.max_scroll >= FAST_SCROLL && self.scroll < self.max_scroll - FAST_SCROLL {
-                                    self.scroll += FAST_SCROLL;
-                                } else {
-                                    self.scroll = self.max_scroll;
-                                }
-                                if self.scroll >= self.max_scroll {
-                                    self.auto_scroll = true;
-                                }
+                            if self.scroll >= self.max_scroll {
+                                self.auto_scroll = true;
                             }
-                            KeyCode::Char('j') => {
-                                self.auto_scroll = false;
-                                if self.scroll > 0 {
-                                    self.scroll -= DEFAULT_SCROLL;
-                                }
+                        }
+                        KeyCode::Char('j') => {
+                            self.auto_scroll = false;
+                            if self.scroll > 0 {
+                                self.scroll -= DEFAULT_SCROLL;
                             }
-                            KeyCode::Char('J') => {
-                                self.auto_scroll = false;
-                                if self.scroll > FAST_SCROLL {
-                                    self.scroll -= FAST_SCROLL;
-                                } else {
-                                    self.scroll = 0;
-                                }
+                        }
+                        KeyCode::Char('J') => {
+                            self.auto_scroll = false;
+                            if self.scroll > FAST_SCROLL {
+                                self.scroll -= FAST_SCROLL;
+                            } else {
+                                self.scroll = 0;
                             }
-                            _ => {}
-                        },
-                        InputMode::Editing if key.kind == KeyEventKind::Press => match key.code {
-                            KeyCode::Enter => self.submit_message(),
-                            KeyCode::Char(to_insert) => self.enter_char(to_insert),
-                            KeyCode::Backspace => self.delete_char(),
-                            KeyCode::Left => self.move_cursor_left(),
-                            KeyCode::Right => self.move_cursor_right(),
-                            KeyCode::Esc => self.input_mode = InputMode::Normal,
-                            _ => {}
-                        },
-                        InputMode::Editing => {}
+                        }
+                        _ => {}
+                    },
+                    InputMode::Editing if key.kind == KeyEventKind::Press => match key.code {
+                        KeyCode::Enter => self.submit_message(),
+                        KeyCode::Char(to_insert) => self.enter_char(to_insert),
+                        KeyCode::Backspace => self.delete_char(),
+                        KeyCode::Left => self.move_cursor_left(),
+                        KeyCode::Right => self.move_cursor_right(),
+                        KeyCode::Esc => {
+                            self.reply_to = None;
+                            self.input_mode = InputMode::Normal;
+                        }
+                        _ => {}
+                    },
+                    InputMode::Editing => {}
+                }
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: TalkProtocol) {
+        match event {
+            TalkProtocol::Welcome {
+                version,
+                capabilities,
+            } => {
+                self.protocol_version = version;
+                self.capabilities = capabilities;
+                if !is_supported_version(version) {
+                    self.push_local_error(format!(
+                        "Server speaks unsupported protocol version {}",
+                        version
+                    ));
+                }
+            }
+            TalkProtocol::Authenticated { uuid, username } => {
+                self.uuid = uuid;
+                self.username = username;
+                self.authenticated = true;
+                let _ = command::join_initial_room(self);
+                let _ = command::fetch_directs(self);
+            }
+            TalkProtocol::SessionToken { token, .. } => {
+                if let Err(error) = session::save_token(&token) {
+                    self.push_local_error(error.to_string());
+                }
+            }
+            TalkProtocol::Error { code, .. } => match code {
+                ErrorCode::Unauthorized => self.authenticated = false,
+                // The server lost track of us, e.g. after a failover
+                ErrorCode::NotInRoom if self.authenticated && !self.evicted => {
+                    let _ = command::join_initial_room(self);
+                    let _ = command::fetch_newer(self);
+                }
+                _ => {}
+            },
+            TalkProtocol::SessionExpired => {
+                let _ = session::clear_token();
+                self.push_local_error("Your session expired, please /login again".to_string());
+            }
+            TalkProtocol::ServerShuttingDown { reconnect_after } => {
+                self.push_local_error(format!(
+                    "The server is shutting down, start the client again in {} seconds to reconnect",
+                    reconnect_after.div_ceil(1000)
+                ));
+            }
+            TalkProtocol::History {
+                text,
+                has_more,
+                after_id,
+                ..
+            } => {
+                let mut communication = self.communication.lock().expect("Communication Vector");
+                // Pages may overlap with messages that arrived live meanwhile
+                let text: Vec<TalkProtocol> = text
+                    .into_iter()
+                    .filter(|msg| {
+                        msg.id()
+                            .is_none_or(|id| !communication.iter().any(|m| m.id() == Some(id)))
+                    })
+                    .collect();
+                if after_id.is_some() {
+                    for msg in text {
+                        insert_by_seq(&mut communication, msg);
+                    }
+                    drop(communication);
+                    if has_more {
+                        let _ = command::fetch_newer(self);
+                    }
+                } else {
+                    communication.splice(0..0, text);
+                    self.history_complete = !has_more;
+                    drop(communication);
+                    if let Some(message_id) = self.jump_to.take() {
+                        self.jump_to_message(message_id);
                     }
                 }
             }
+            TalkProtocol::MessageEdited {
+                message_id,
+                text,
+                edited_at,
+                ..
+            } => {
+                self.update_message(message_id, |message| {
+                    message.text = text;
+                    message.edited_at = Some(edited_at);
+                });
+            }
+            TalkProtocol::MessageDeleted {
+                message_id,
+                deleted_at,
+                ..
+            } => {
+                self.update_message(message_id, |message| {
+                    message.text.clear();
+                    message.deleted_at = Some(deleted_at);
+                    message.reactions.clear();
+                });
+            }
+            TalkProtocol::Typing {
+                room_id,
+                uuid,
+                username,
+            } if room_id == self.room && uuid != self.uuid => {
+                self.typing.retain(|(other, _, _)| *other != uuid);
+                self.typing.push((uuid, username, Instant::now()));
+            }
+            TalkProtocol::DirectMessage { message } => {
+                let partner = if message.sender == self.uuid {
+                    (message.recipient, message.recipient_name.clone())
+                } else {
+                    if self.view != View::Direct {
+                        self.unread_directs += 1;
+                    }
+                    (message.sender, message.sender_name.clone())
+                };
+                self.direct_partner = Some(partner);
+                self.directs.push(message);
+            }
+            TalkProtocol::DirectHistory { messages, .. } => {
+                let known: Vec<u64> = self.directs.iter().map(|m| m.id).collect();
+                let messages = messages.into_iter().filter(|m| !known.contains(&m.id));
+                self.directs.splice(0..0, messages);
+            }
+            TalkProtocol::RoomInfo { room } => {
+                if self.pending_room.as_deref() == Some(room.name.as_str()) {
+                    self.pending_room = None;
+                    if room.id != self.room {
+                        let _ = command::switch_room(self, room.id);
+                    }
+                }
+                if room.id == self.room {
+                    self.room_info = Some(room);
+                }
+            }
+            TalkProtocol::Moderation {
+                room_id,
+                action,
+                target,
+                ..
+            } if action.evicts() && target.uuid == self.uuid && room_id == self.room => {
+                self.evicted = true;
+            }
+            TalkProtocol::InviteRedeemed { room } if room.id != self.room => {
+                let _ = command::switch_room(self, room.id);
+            }
+            TalkProtocol::SearchResults {
+                room_id,
+                query,
+                hits,
+            } if room_id == self.room => {
+                self.search_query = query;
+                self.search_hits = hits;
+                self.search_selected = 0;
+                self.show_search = true;
+            }
+            TalkProtocol::RoomList { rooms } => {
+                self.rooms = rooms;
+                self.rooms_selected = self.rooms_selected.min(self.rooms.len().saturating_sub(1));
+            }
+            TalkProtocol::Members { room_id, members } if room_id == self.room => {
+                self.members = members;
+            }
+            TalkProtocol::UserJoined { .. }
+            | TalkProtocol::UserLeft { .. }
+            | TalkProtocol::UsernameChanged { .. }
+                if self.show_members =>
+            {
+                self.request_members();
+            }
+            TalkProtocol::ReactionsChanged {
+                message_id,
+                reactions,
+                ..
+            } => {
+                self.update_message(message_id, |message| message.reactions = reactions);
+            }
+            _ => {}
         }
     }
 
+    pub fn toggle_view(&mut self) {
+        self.view = match self.view {
+            View::Room => View::Direct,
+            View::Direct => View::Room,
+        };
+        self.selected = None;
+        self.auto_scroll = true;
+        if self.view == View::Direct {
+            self.unread_directs = 0;
+        }
+    }
+
+    fn open_rooms(&mut self) {
+        if !self.capabilities.contains(Capabilities::DIRECTORY) {
+            self.push_local_error("The server does not support listing rooms".to_string());
+            return;
+        }
+        self.show_rooms = true;
+        self.rooms_selected = 0;
+        let _ = self.tx.unbounded_send(TalkProtocol::ListRooms);
+    }
+
+    /// Selects a message of the room, paging back through the history until
+    /// it is loaded.
+    fn jump_to_message(&mut self, message_id: u64) {
+        let loaded = self
+            .communication
+            .lock()
+            .expect("Communication Vector")
+            .iter()
+            .any(|proto| proto.id() == Some(message_id));
+        if loaded {
+            self.selected = Some(message_id);
+            self.auto_scroll = false;
+            self.scroll_to_selected = true;
+        } else if self.history_complete {
+            self.push_local_error("That message is no longer in the history".to_string());
+        } else {
+            self.jump_to = Some(message_id);
+            let _ = command::fetch_older(self);
+        }
+    }
+
+    fn request_members(&mut self) {
+        self.members_requested = Instant::now();
+        if self.authenticated && self.capabilities.contains(Capabilities::PRESENCE) {
+            let _ = self.tx.unbounded_send(TalkProtocol::ListMembers { room_id: self.room });
+        }
+    }
+
+    /// Steps the selection through the chat messages, starting at the newest.
+    /// Moving down past the newest message clears the selection.
+    fn move_selection(&mut self, older: bool) {
+        let ids: Vec<u64> = self
+            .communication
+            .lock()
+            .expect("Communication Vector")
+            .iter()
+            .filter_map(|proto| match proto {
+                TalkProtocol::PostMessage { message } if message.deleted_at.is_none() => {
+                    Some(message.id)
+                }
+                _ => None,
+            })
+            .collect();
+        let index = self
+            .selected
+            .and_then(|selected| ids.iter().position(|id| *id == selected));
+        self.selected = match (index, older) {
+            (None, true) => ids.last().copied(),
+            (None, false) => None,
+            (Some(index), true) => Some(ids[index.saturating_sub(1)]),
+            (Some(index), false) => ids.get(index + 1).copied(),
+        };
+        self.auto_scroll = self.selected.is_none();
+    }
+
+    fn update_message(&mut self, message_id: u64, update: impl FnOnce(&mut TalkMessage)) {
+        let mut communication = self.communication.lock().expect("Communication Vector");
+        let message = communication.iter_mut().find_map(|proto| match proto {
+            TalkProtocol::PostMessage { message } if message.id == message_id => Some(message),
+            _ => None,
+        });
+        if let Some(message) = message {
+            update(message);
+        }
+    }
+
+    pub fn push_local_error(&mut self, message: String) {
+        self.communication
+            .lock()
+            .expect("Communication Vector")
+            .push(TalkProtocol::LocalError { message });
+    }
+
     fn draw(&mut self, frame: &mut ratatui::Frame) {
         ui::draw(self, frame);
     }
 }
+
+/// Publishing is not ordered across server nodes, so a message may arrive
+/// after one that was stored later. Keep the chat in sequence order, messages
+/// without a sequence number are simply appended.
+pub fn insert_by_seq(communication: &mut Vec<TalkProtocol>, msg: TalkProtocol) {
+    let index = msg
+        .seq()
+        .and_then(|seq| {
+            communication
+                .iter()
+                .rposition(|other| other.seq().is_some_and(|other| other < seq))
+                .map(|index| index + 1)
+                // Older than everything shown, goes before the first one
+                .or_else(|| communication.iter().position(|other| other.seq().is_some()))
+        })
+        .unwrap_or(communication.len());
+    communication.insert(index, msg);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(seq: u64) -> TalkProtocol {
+        TalkProtocol::PostMessage {
+            message: TalkMessage {
+                id: seq,
+                uuid: Uuid::nil(),
+                username: "alice".to_string(),
+                text: format!("message {}", seq),
+                room_id: 1,
+                unixtime: 0,
+                seq,
+                edited_at: None,
+                deleted_at: None,
+                reply_to: None,
+                reactions: Vec::new(),
+            },
+        }
+    }
+
+    fn seqs(communication: &[TalkProtocol]) -> Vec<Option<u64>> {
+        communication.iter().map(|msg| msg.seq()).collect()
+    }
+
+    fn app() -> App {
+        let (tx, _) = futures_channel::mpsc::unbounded();
+        let (_, events) = std::sync::mpsc::channel();
+        App::new(tx, Arc::new(Mutex::new(Vec::new())), events)
+    }
+
+    fn direct(id: u64, sender: (Uuid, &str), recipient: (Uuid, &str)) -> DirectMessage {
+        DirectMessage {
+            id,
+            sender: sender.0,
+            sender_name: sender.1.to_string(),
+            recipient: recipient.0,
+            recipient_name: recipient.1.to_string(),
+            text: format!("direct {}", id),
+            unixtime: 0,
+        }
+    }
+
+    #[test]
+    fn keeps_messages_in_sequence_order() {
+        let mut communication = Vec::new();
+        for seq in [2, 4, 3, 1, 5] {
+            insert_by_seq(&mut communication, message(seq));
+        }
+        assert_eq!(seqs(&communication), [1, 2, 3, 4, 5].map(Some));
+    }
+
+    #[test]
+    fn appends_messages_without_a_sequence_number() {
+        let mut communication = vec![message(1), message(3)];
+        insert_by_seq(&mut communication, TalkProtocol::SessionExpired);
+        insert_by_seq(&mut communication, message(2));
+        assert_eq!(seqs(&communication), [Some(1), Some(2), Some(3), None]);
+    }
+
+    #[test]
+    fn puts_late_old_messages_before_the_first_sequenced_one() {
+        let mut communication = vec![TalkProtocol::SessionExpired, message(2)];
+        insert_by_seq(&mut communication, message(1));
+        assert_eq!(seqs(&communication), [None, Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn direct_messages_switch_the_partner_and_count_as_unread_elsewhere() {
+        let mut app = app();
+        app.uuid = Uuid::new_v4();
+        let me = (app.uuid, "me");
+        let bob = (Uuid::new_v4(), "bob");
+        let carol = (Uuid::new_v4(), "carol");
+
+        app.handle_event(TalkProtocol::DirectMessage { message: direct(2, bob, me) });
+        assert_eq!(app.direct_partner, Some((bob.0, "bob".to_string())));
+        assert_eq!(app.unread_directs, 1);
+
+        // Our own messages aren't unread, but switch to whoever they went to
+        app.handle_event(TalkProtocol::DirectMessage { message: direct(3, me, carol) });
+        assert_eq!(app.direct_partner, Some((carol.0, "carol".to_string())));
+        assert_eq!(app.unread_directs, 1);
+
+        app.toggle_view();
+        assert_eq!(app.unread_directs, 0);
+        app.handle_event(TalkProtocol::DirectMessage { message: direct(4, bob, me) });
+        assert_eq!(app.unread_directs, 0);
+    }
+
+    #[test]
+    fn tells_when_to_reconnect_after_a_shutdown() {
+        let mut app = app();
+        app.handle_event(TalkProtocol::ServerShuttingDown { reconnect_after: 2500 });
+        let communication = app.communication.lock().unwrap();
+        let Some(TalkProtocol::LocalError { message }) = communication.last() else {
+            panic!("no notice in {:?}", communication);
+        };
+        assert!(message.contains("in 3 seconds"), "{}", message);
+    }
+
+    #[test]
+    fn direct_history_goes_before_what_arrived_live() {
+        let mut app = app();
+        let (me, bob) = ((Uuid::new_v4(), "me"), (Uuid::new_v4(), "bob"));
+        app.handle_event(TalkProtocol::DirectMessage { message: direct(3, bob, me) });
+        app.handle_event(TalkProtocol::DirectHistory {
+            messages: vec![direct(1, me, bob), direct(2, bob, me), direct(3, bob, me)],
+            has_more: false,
+        });
+        let ids: Vec<u64> = app.directs.iter().map(|m| m.id).collect();
+        assert_eq!(ids, [1, 2, 3]);
+    }
+}
@@ -1,37 +1,162 @@
 use crate::app;
-use anyhow::{Context, Result};
+use crate::session;
+use anyhow::{Context, Result, bail};
 use shared::*;
 use std::{
     num::ParseIntError,
     time::{SystemTime, UNIX_EPOCH},
 };
+use uuid::Uuid;
 
-const MESSAGE_LENGTH: usize = 250;
-const USERNAME_LENGTH: usize = 15;
 
+/// Act on another user in the current room, `ban` and `mute` take an optional
+/// number of minutes.
+const MODERATION_COMMANDS: [&str; 6] = ["kick", "ban", "mute", "unban", "promote", "demote"];
+
+/// Only informational, the server stamps events with its own clock.
 pub fn get_unix_timestamp() -> Result<u64> {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .context("unixtime")?;
-    Ok(now.as_secs())
+    Ok(now.as_millis() as u64)
 }
 
-pub fn get_first_message_timestamp(app: &mut app::App) -> Result<u64> {
-    Ok(app
-        .communication
+/// Id of the oldest message we have, older history is fetched from there on
+/// backwards. `None` fetches the latest messages.
+pub fn get_first_message_id(app: &mut app::App) -> Option<u64> {
+    app.communication
         .lock()
         .expect("Vector of communication")
         .iter()
+        .find_map(TalkProtocol::id)
+}
+
+/// Id of the newest message we have, missed messages are fetched from there
+/// on forwards.
+pub fn get_last_message_id(app: &mut app::App) -> Option<u64> {
+    app.communication
+        .lock()
+        .expect("Vector of communication")
+        .iter()
+        .rev()
+        .find_map(TalkProtocol::id)
+}
+
+/// Id of the last message we posted that still exists, which `/edit` and
+/// `/delete` act on unless another message is selected.
+pub fn get_last_own_message_id(app: &mut app::App) -> Option<u64> {
+    app.communication
+        .lock()
+        .expect("Vector of communication")
+        .iter()
+        .rev()
         .find_map(|proto| match proto {
-            TalkProtocol::Error { .. } => None,
-            TalkProtocol::LocalError { .. } => None,
-            TalkProtocol::PostMessage { message } => Some(message.unixtime),
-            TalkProtocol::UserJoined { unixtime, .. } => Some(*unixtime),
-            TalkProtocol::UserLeft { unixtime, .. } => Some(*unixtime),
-            TalkProtocol::UsernameChanged { unixtime, .. } => Some(*unixtime),
+            TalkProtocol::PostMessage { message }
+                if message.uuid == app.uuid && message.deleted_at.is_none() =>
+            {
+                Some(message.id)
+            }
             _ => None,
         })
-        .unwrap_or(get_unix_timestamp()?))
+}
+
+/// Id of the newest chat message, which `/react` acts on unless another
+/// message is selected.
+pub fn get_last_chat_message_id(app: &mut app::App) -> Option<u64> {
+    app.communication
+        .lock()
+        .expect("Vector of communication")
+        .iter()
+        .rev()
+        .find_map(|proto| match proto {
+            TalkProtocol::PostMessage { message } if message.deleted_at.is_none() => {
+                Some(message.id)
+            }
+            _ => None,
+        })
+}
+
+/// Reacts to a message with `emoji`, or takes the reaction back if we
+/// already reacted with it.
+pub fn toggle_reaction(app: &mut app::App, message_id: u64, emoji: &str) -> Result<()> {
+    if !app.capabilities.contains(Capabilities::REACTIONS) {
+        app.push_local_error("The server does not support reactions".to_string());
+        return Ok(());
+    }
+    let reacted = app
+        .communication
+        .lock()
+        .expect("Vector of communication")
+        .iter()
+        .any(|proto| match proto {
+            TalkProtocol::PostMessage { message } if message.id == message_id => message
+                .reactions
+                .iter()
+                .any(|r| r.emoji == emoji && r.users.contains(&app.uuid)),
+            _ => false,
+        });
+    let emoji = emoji.to_string();
+    let com = if reacted {
+        TalkProtocol::RemoveReaction { message_id, emoji }
+    } else {
+        TalkProtocol::AddReaction { message_id, emoji }
+    };
+    app.tx.unbounded_send(com)?;
+    Ok(())
+}
+
+/// Loads the latest direct messages after logging in.
+pub fn fetch_directs(app: &mut app::App) -> Result<()> {
+    if app.capabilities.contains(Capabilities::DIRECT) {
+        app.tx.unbounded_send(TalkProtocol::FetchDirect {
+            before_id: None,
+            limit: MAX_FETCH_LIMIT,
+        })?;
+    }
+    Ok(())
+}
+
+/// Fetches whatever was posted after the newest message we have.
+pub fn fetch_newer(app: &mut app::App) -> Result<()> {
+    if !app.capabilities.contains(Capabilities::HISTORY) {
+        return Ok(());
+    }
+    if let Some(after_id) = get_last_message_id(app) {
+        app.tx.unbounded_send(TalkProtocol::Fetch {
+            room_id: app.room,
+            limit: MAX_FETCH_LIMIT,
+            before_id: None,
+            after_id: Some(after_id),
+        })?;
+    }
+    Ok(())
+}
+
+/// Fetches a page of messages older than the oldest one we have.
+pub fn fetch_older(app: &mut app::App) -> Result<()> {
+    let before_id = get_first_message_id(app);
+    app.tx.unbounded_send(TalkProtocol::Fetch {
+        room_id: app.room,
+        limit: MAX_FETCH_LIMIT,
+        before_id,
+        after_id: None,
+    })?;
+    Ok(())
+}
+
+pub fn send_hello(app: &mut app::App) -> Result<()> {
+    app.tx.unbounded_send(TalkProtocol::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::supported(),
+    })?;
+    Ok(())
+}
+
+pub fn resume_session(app: &mut app::App) -> Result<()> {
+    if let Some(token) = session::load_token() {
+        app.tx.unbounded_send(TalkProtocol::Resume { token })?;
+    }
+    Ok(())
 }
 
 pub fn join_initial_room(app: &mut app::App) -> Result<()> {
@@ -41,6 +166,9 @@ pub fn join_initial_room(app: &mut app::App) -> Result<()> {
 }
 
 pub fn quit_app(app: &mut app::App) -> Result<()> {
+    if !app.authenticated {
+        return Ok(());
+    }
     let com = leave_room(app);
     app.tx.unbounded_send(com?)?;
     Ok(())
@@ -64,22 +192,56 @@ pub fn leave_room(app: &mut app::App) -> Result<TalkProtocol> {
     })
 }
 
+/// Leaves the current room for `room_id` and starts over with its history.
+pub fn switch_room(app: &mut app::App, room_id: i32) -> Result<()> {
+    let (leave, join) = parse_command_room_valid(app, room_id)?;
+    app.tx.unbounded_send(leave)?;
+    app.communication
+        .lock()
+        .expect("Communication Vector")
+        .clear();
+    app.history_complete = false;
+    app.room_info = None;
+    app.evicted = false;
+    app.show_search = false;
+    app.search_hits.clear();
+    app.jump_to = None;
+    app.tx.unbounded_send(join)?;
+    Ok(())
+}
+
 pub fn parse(app: &mut app::App) -> Result<()> {
     if app.input.is_empty() {
-    } else if app.input.len() >= MESSAGE_LENGTH {
+    } else if app.input.chars().count() > MAX_MESSAGE_LENGTH {
         let com = parse_message_too_long();
         app.tx.unbounded_send(com?)?;
     } else if app.input.starts_with("/") {
         app.input = app.input.trim_start_matches("/").trim().to_string();
         let _ = parse_command(app);
+    } else if app.view == app::View::Direct {
+        match app.direct_partner.clone() {
+            Some((uuid, _)) => app.tx.unbounded_send(TalkProtocol::SendDirect {
+                recipient: Recipient::Uuid(uuid),
+                text: app.input.to_string(),
+            })?,
+            None => app.push_local_error(
+                "Use /msg <user> <text> to start a conversation".to_string(),
+            ),
+        }
     } else {
         let com = TalkProtocol::PostMessage {
             message: TalkMessage {
+                id: 0,
                 uuid: app.uuid,
                 username: app.username.to_string(),
                 text: app.input.to_string(),
                 room_id: app.room,
-                unixtime: get_unix_timestamp()?,
+                unixtime: 0,
+                seq: 0,
+                edited_at: None,
+                deleted_at: None,
+                reply_to: app.reply_to.take(),
+                reactions: Vec::new(),
             },
         };
         app.tx.unbounded_send(com)?;
@@ -90,7 +252,7 @@ pub fn parse(app: &mut app::App) -> Result<()> {
 fn parse_command(app: &mut app::App) -> Result<()> {
     if app.input.starts_with("name") {
         app.input = app.input.trim_start_matches("name ").trim().to_string();
-        if app.input.len() <= USERNAME_LENGTH {
+        if app.input.chars().count() <= MAX_USERNAME_LENGTH {
             let com = parse_command_name(app);
             app.tx.unbounded_send(com?)?;
         } else {
@@ -103,33 +265,257 @@ fn parse_command(app: &mut app::App) -> Result<()> {
     } else if app.input.starts_with("room") {
         app.input = app.input.trim_start_matches("room").trim().to_string();
         match app.input.parse::<i32>() {
-            Ok(number) => {
-                let (leave, join) = parse_command_room_valid(app, number)?;
-                app.tx.unbounded_send(join)?;
-                app.communication
-                    .lock()
-                    .expect("Communication Vector")
-                    .clear();
-                app.tx.unbounded_send(leave)?;
+            Ok(number) => switch_room(app, number)?,
+            Err(_) if app.input.is_empty() => {
+                app.push_local_error("Usage: /room <number or name>".to_string());
             }
-            Err(error) => {
-                let com = parse_command_room_invalid(error);
-                app.communication
-                    .lock()
-                    .expect("Communication Vector")
-                    .push(com?);
+            Err(_) if !app.capabilities.contains(Capabilities::ROOMS) => {
+                app.push_local_error("The server does not support named rooms".to_string());
+            }
+            Err(_) => {
+                let name = app.input.to_lowercase();
+                app.pending_room = Some(name.clone());
+                app.tx.unbounded_send(TalkProtocol::ResolveRoom { name })?;
+            }
+        }
+    } else if app.input.starts_with("create ") || app.input.starts_with("private ") {
+        let (visibility, rest) = match app.input.strip_prefix("private ") {
+            Some(rest) => (Visibility::Private, rest),
+            None => (Visibility::Public, app.input.trim_start_matches("create ")),
+        };
+        let mut parts = rest.trim().splitn(2, ' ');
+        let name = parts.next().unwrap_or_default().to_lowercase();
+        let topic = parts.next().unwrap_or_default().trim().to_string();
+        if !app.capabilities.contains(Capabilities::ROOMS) {
+            app.push_local_error("The server does not support named rooms".to_string());
+        } else if name.chars().count() > MAX_ROOM_NAME_LENGTH {
+            app.push_local_error(format!(
+                "Room names are limited to {} characters",
+                MAX_ROOM_NAME_LENGTH
+            ));
+        } else {
+            app.pending_room = Some(name.clone());
+            app.tx.unbounded_send(TalkProtocol::CreateRoom {
+                name,
+                topic,
+                visibility,
+            })?;
+        }
+    } else if app.input == "invite" || app.input.starts_with("join ") {
+        if !app.capabilities.contains(Capabilities::INVITES) {
+            app.push_local_error("The server does not support invites".to_string());
+            return Ok(());
+        }
+        let com = match app.input.strip_prefix("join ") {
+            Some(code) => TalkProtocol::RedeemInvite {
+                code: code.trim().to_string(),
+            },
+            None => TalkProtocol::CreateInvite { room_id: app.room },
+        };
+        app.tx.unbounded_send(com)?;
+    } else if app.input.starts_with("topic ") {
+        let topic = app.input.trim_start_matches("topic ").trim().to_string();
+        if topic.chars().count() > MAX_TOPIC_LENGTH {
+            app.push_local_error(format!(
+                "Topics are limited to {} characters",
+                MAX_TOPIC_LENGTH
+            ));
+        } else {
+            let com = TalkProtocol::SetTopic {
+                room_id: app.room,
+                topic,
+            };
+            app.tx.unbounded_send(com)?;
+        }
+    } else if app.input.starts_with("slow ") {
+        match app.input.trim_start_matches("slow ").trim().parse::<u32>() {
+            Ok(seconds) if seconds <= MAX_SLOW_MODE_SECS => {
+                let com = TalkProtocol::SetSlowMode {
+                    room_id: app.room,
+                    seconds,
+                };
+                app.tx.unbounded_send(com)?;
+            }
+            _ => app.push_local_error(format!(
+                "Usage: /slow <seconds up to {}>, 0 turns slow mode off",
+                MAX_SLOW_MODE_SECS
+            )),
+        }
+    } else if app.input.starts_with("retention ") {
+        let mut parts = app.input.trim_start_matches("retention ").split_whitespace();
+        let max_age_secs = match parts.next() {
+            Some("off") => Some(None),
+            Some(days) => days
+                .parse::<u64>()
+                .ok()
+                .filter(|days| *days > 0)
+                .and_then(|days| days.checked_mul(24 * 60 * 60))
+                .map(Some),
+            None => None,
+        };
+        let max_messages = match parts.next().map(str::parse::<u64>) {
+            None => Some(None),
+            Some(Ok(count)) if count > 0 => Some(Some(count)),
+            Some(_) => None,
+        };
+        match (max_age_secs, max_messages) {
+            (Some(max_age_secs), Some(max_messages)) => {
+                let com = TalkProtocol::SetRetention {
+                    room_id: app.room,
+                    retention: Retention {
+                        max_age_secs,
+                        max_messages,
+                    },
+                };
+                app.tx.unbounded_send(com)?;
+            }
+            _ => app.push_local_error(
+                "Usage: /retention <days or off> [messages to keep]".to_string(),
+            ),
+        }
+    } else if app.input.starts_with("search ") {
+        let query = app.input.trim_start_matches("search ").trim().to_string();
+        if !app.capabilities.contains(Capabilities::SEARCH) {
+            app.push_local_error("The server does not support searching".to_string());
+        } else if query.is_empty() {
+            app.push_local_error("Usage: /search <terms>".to_string());
+        } else {
+            app.tx.unbounded_send(TalkProtocol::Search {
+                room_id: app.room,
+                query,
+                limit: MAX_SEARCH_LIMIT,
+            })?;
+        }
+    } else if app.input.starts_with("register ") || app.input.starts_with("login ") {
+        let com = parse_command_credentials(app);
+        match com {
+            Ok(com) => app.tx.unbounded_send(com)?,
+            Err(error) => app
+                .communication
+                .lock()
+                .expect("Communication Vector")
+                .push(TalkProtocol::LocalError {
+                    message: error.to_string(),
+                }),
+        }
+    } else if app.input.starts_with("edit ") || app.input == "delete" {
+        if !app.capabilities.contains(Capabilities::EDITS) {
+            app.push_local_error("The server does not support editing messages".to_string());
+            return Ok(());
+        }
+        let selected = app.selected.take();
+        let Some(message_id) = selected.or_else(|| get_last_own_message_id(app)) else {
+            app.push_local_error("You have no message to change".to_string());
+            return Ok(());
+        };
+        let com = match app.input.strip_prefix("edit ") {
+            Some(text) => TalkProtocol::EditMessage {
+                message_id,
+                text: text.trim().to_string(),
+            },
+            None => TalkProtocol::DeleteMessage { message_id },
+        };
+        app.tx.unbounded_send(com)?;
+    } else if app.input.starts_with("react ") {
+        let emoji = app.input.trim_start_matches("react ").trim().to_string();
+        let selected = app.selected.take();
+        match selected.or_else(|| get_last_chat_message_id(app)) {
+            Some(message_id) => toggle_reaction(app, message_id, &emoji)?,
+            None => app.push_local_error("There is no message to react to".to_string()),
+        }
+    } else if app.input.starts_with("msg ") {
+        let mut parts = app.input.trim_start_matches("msg ").trim().splitn(2, ' ');
+        match (parts.next(), parts.next().map(str::trim)) {
+            (Some(user), Some(text)) if !text.is_empty() => {
+                if !app.capabilities.contains(Capabilities::DIRECT) {
+                    app.push_local_error(
+                        "The server does not support direct messages".to_string(),
+                    );
+                    return Ok(());
+                }
+                let com = TalkProtocol::SendDirect {
+                    recipient: Recipient::Username(user.to_string()),
+                    text: text.to_string(),
+                };
+                app.tx.unbounded_send(com)?;
+                if app.view != app::View::Direct {
+                    app.toggle_view();
+                }
             }
+            _ => app.push_local_error("Usage: /msg <user> <text>".to_string()),
+        }
+    } else if let Some((command, rest)) = app.input.split_once(' ')
+        && MODERATION_COMMANDS.contains(&command)
+    {
+        let mut parts = rest.split_whitespace();
+        let target = parts.next().map(|user| Recipient::Username(user.to_string()));
+        // Durations too long to fit count as a typo, not as forever
+        let duration_secs = match parts.next().map(str::parse::<u64>) {
+            None => Some(None),
+            Some(Ok(minutes)) => minutes.checked_mul(60).map(Some),
+            Some(Err(_)) => None,
+        };
+        if !app.capabilities.contains(Capabilities::MODERATION) {
+            app.push_local_error("The server does not support moderation".to_string());
+            return Ok(());
         }
+        let (Some(target), Some(duration_secs)) = (target, duration_secs) else {
+            app.push_local_error(format!("Usage: /{} <user> [minutes]", command));
+            return Ok(());
+        };
+        let room_id = app.room;
+        let com = match command {
+            "kick" => TalkProtocol::Kick { room_id, target },
+            "ban" => TalkProtocol::Ban {
+                room_id,
+                target,
+                duration_secs,
+            },
+            "mute" => TalkProtocol::Mute {
+                room_id,
+                target,
+                duration_secs,
+            },
+            "unban" => TalkProtocol::Unban { room_id, target },
+            "promote" => TalkProtocol::SetRole {
+                room_id,
+                target,
+                role: RoomRole::Moderator,
+            },
+            _ => TalkProtocol::SetRole {
+                room_id,
+                target,
+                role: RoomRole::Member,
+            },
+        };
+        app.tx.unbounded_send(com)?;
+    } else if app.input == "logout" {
+        logout(app)?;
     } else if app.input == "clear" {
         app.communication
             .lock()
             .expect("Communication Vector")
             .clear();
+        app.history_complete = false;
     } else if app.input.starts_with("fetch") {
         app.input = app.input.trim_start_matches("fetch").trim().to_string();
+        if !app.capabilities.contains(Capabilities::HISTORY) {
+            app.communication
+                .lock()
+                .expect("Communication Vector")
+                .push(TalkProtocol::LocalError {
+                    message: "The server does not support fetching history".to_string(),
+                });
+            return Ok(());
+        }
+        let newer = app.input.starts_with("newer");
+        app.input = app.input.trim_start_matches("newer").trim().to_string();
         match app.input.parse::<i64>() {
+            Ok(_) if !newer && app.history_complete => {
+                app.push_local_error("There are no older messages".to_string());
+            }
             Ok(number) => {
-                let com = parse_command_fetch_valid(app, number);
+                let com = parse_command_fetch_valid(app, number, newer);
                 app.tx.unbounded_send(com?)?;
             }
             Err(error) => {
@@ -160,12 +546,6 @@ fn parse_command_room_valid(
     Ok((leave_message, join_message))
 }
 
-fn parse_command_room_invalid(error: ParseIntError) -> Result<TalkProtocol> {
-    Ok(TalkProtocol::LocalError {
-        message: error.to_string(),
-    })
-}
-
 fn parse_command_name(app: &mut app::App) -> Result<TalkProtocol> {
     let old_username = app.username.to_string();
     app.username = app.input.to_string();
@@ -177,17 +557,67 @@ fn parse_command_name(app: &mut app::App) -> Result<TalkProtocol> {
     })
 }
 
+fn parse_command_credentials(app: &mut app::App) -> Result<TalkProtocol> {
+    let mut words = app.input.split_whitespace();
+    let (Some(command), Some(username), Some(password), None) =
+        (words.next(), words.next(), words.next(), words.next())
+    else {
+        bail!(
+            "Usage: /{} <username> <password>",
+            app.input.split_whitespace().next().unwrap_or("login")
+        );
+    };
+    if username.chars().count() > MAX_USERNAME_LENGTH {
+        bail!("Username too long");
+    }
+    let (username, password) = (username.to_string(), password.to_string());
+    Ok(match command {
+        "register" => TalkProtocol::Register { username, password },
+        _ => TalkProtocol::Login { username, password },
+    })
+}
+
+fn logout(app: &mut app::App) -> Result<()> {
+    if app.authenticated {
+        let com = leave_room(app);
+        app.tx.unbounded_send(com?)?;
+        app.tx.unbounded_send(TalkProtocol::Logout)?;
+    }
+    session::clear_token()?;
+    app.authenticated = false;
+    app.uuid = Uuid::nil();
+    app.communication
+        .lock()
+        .expect("Communication Vector")
+        .clear();
+    app.history_complete = false;
+    app.directs.clear();
+    app.direct_partner = None;
+    app.unread_directs = 0;
+    Ok(())
+}
+
 fn parse_invalid_command(app: &mut app::App) -> Result<TalkProtocol> {
     Ok(TalkProtocol::LocalError {
         message: format!("The command '{}' does not exist", app.input),
     })
 }
 
-fn parse_command_fetch_valid(app: &mut app::App, set_limit: i64) -> Result<TalkProtocol> {
+fn parse_command_fetch_valid(
+    app: &mut app::App,
+    set_limit: i64,
+    newer: bool,
+) -> Result<TalkProtocol> {
+    let (before_id, after_id) = if newer {
+        (None, get_last_message_id(app))
+    } else {
+        (get_first_message_id(app), None)
+    };
     Ok(TalkProtocol::Fetch {
         room_id: app.room,
         limit: set_limit,
-        fetch_before: get_first_message_timestamp(app)?,
+        before_id,
+        after_id,
     })
 }
 
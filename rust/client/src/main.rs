@@ -1,13 +1,13 @@
 mod app;
 mod command;
+mod session;
 mod ui;
 
 use crate::app::App;
 use futures_channel::mpsc::unbounded;
-use shared::TalkProtocol;
+use shared::{TalkProtocol, WireVersion};
 pub use shared::native::{connect, receiver_task, sender_task};
-use std::sync::{Arc, Mutex};
-use tokio;
+use std::sync::{Arc, Mutex, mpsc};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -18,24 +18,52 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let (tx, rx) = unbounded::<TalkProtocol>();
     let (write, read) = connect(url).await?;
     let communication: Arc<Mutex<Vec<TalkProtocol>>> = Arc::new(Mutex::new(Vec::new()));
+    let (event_tx, event_rx) = mpsc::channel::<TalkProtocol>();
 
-    tokio::spawn(sender_task(rx, write));
+    let version = WireVersion::new();
+    tokio::spawn(sender_task(rx, write, version.clone()));
 
     let com = Arc::clone(&communication);
-    tokio::spawn(receiver_task(read, move |msg| {
+    tokio::spawn(receiver_task(read, version, move |msg| {
         match msg {
-            TalkProtocol::History { text } => {
-                com.lock().unwrap().splice(0..0, text);
+            // Connection state and history paging are owned by the app
+            TalkProtocol::History { .. }
+            | TalkProtocol::MessageEdited { .. }
+            | TalkProtocol::MessageDeleted { .. }
+            | TalkProtocol::ReactionsChanged { .. }
+            | TalkProtocol::Members { .. }
+            | TalkProtocol::Typing { .. }
+            | TalkProtocol::DirectMessage { .. }
+            | TalkProtocol::DirectHistory { .. }
+            | TalkProtocol::RoomInfo { .. }
+            | TalkProtocol::RoomList { .. }
+            | TalkProtocol::SearchResults { .. }
+            | TalkProtocol::InviteRedeemed { .. }
+            | TalkProtocol::Welcome { .. }
+            | TalkProtocol::Authenticated { .. }
+            | TalkProtocol::SessionToken { .. }
+            | TalkProtocol::SessionExpired
+            | TalkProtocol::ServerShuttingDown { .. } => {
+                let _ = event_tx.send(msg);
+            }
+            // Shown in the chat, but the app may also want to react to it
+            TalkProtocol::Error { .. }
+            | TalkProtocol::UserJoined { .. }
+            | TalkProtocol::UserLeft { .. }
+            | TalkProtocol::UsernameChanged { .. }
+            | TalkProtocol::Moderation { .. } => {
+                app::insert_by_seq(&mut com.lock().unwrap(), msg.clone());
+                let _ = event_tx.send(msg);
             }
             _ => {
-                com.lock().unwrap().push(msg);
+                app::insert_by_seq(&mut com.lock().unwrap(), msg);
             }
         }
     }));
 
     color_eyre::install()?;
     let terminal = ratatui::init();
-    let app_result = App::new(tx, communication).run(terminal);
+    let app_result = App::new(tx, communication, event_rx).run(terminal);
     ratatui::restore();
     Ok(app_result?)
 }
@@ -0,0 +1,54 @@
+use anyhow::{Context, Result};
+use std::{
+    fs,
+    io::{ErrorKind, Write},
+    path::PathBuf,
+};
+
+const APP_DIR: &str = "tuitalk";
+const TOKEN_FILE: &str = "session";
+
+fn token_path() -> Result<PathBuf> {
+    Ok(dirs::config_dir()
+        .context("No config directory")?
+        .join(APP_DIR)
+        .join(TOKEN_FILE))
+}
+
+pub fn load_token() -> Option<String> {
+    let token = fs::read_to_string(token_path().ok()?).ok()?;
+    let token = token.trim();
+    (!token.is_empty()).then(|| token.to_string())
+}
+
+pub fn save_token(token: &str) -> Result<()> {
+    let path = token_path()?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context("Creating config directory")?;
+    }
+
+    // The token is as good as a password, so it is never readable by others,
+    // not even for a moment: written to a fresh file only we can read, then
+    // moved over the old one
+    let temp_path = path.with_extension("tmp");
+    let _ = fs::remove_file(&temp_path);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&temp_path).context("Creating session token")?;
+    file.write_all(token.as_bytes())
+        .context("Writing session token")?;
+    fs::rename(&temp_path, &path).context("Replacing session token")?;
+    Ok(())
+}
+
+pub fn clear_token() -> Result<()> {
+    match fs::remove_file(token_path()?) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e).context("Removing session token"),
+        _ => Ok(()),
+    }
+}
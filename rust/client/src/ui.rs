@@ -1,12 +1,12 @@
-use crate::app::{App, InputMode};
+use crate::app::{App, InputMode, View};
 use anyhow::{Context, Result};
 use chrono::{Local, TimeZone, Utc};
 use ratatui::{
     Frame,
-    layout::{Constraint, Layout, Position},
+    layout::{Constraint, Flex, Layout, Position, Rect},
     style::{Color, Modifier, Style, Stylize},
     text::{Line, Span, Text},
-    widgets::{Block, Paragraph, Wrap},
+    widgets::{Block, Clear, Paragraph, Wrap},
 };
 use shared::*;
 use uuid::Uuid;
@@ -22,7 +22,7 @@ fn color_from_uuid(uuid: Uuid) -> Color {
 
 fn format_timestamp(unixtime: u64) -> Result<Span<'static>> {
     let timestamp = Utc
-        .timestamp_opt(unixtime as i64, 0)
+        .timestamp_millis_opt(unixtime as i64)
         .single()
         .context("Invalid Timestamp")?;
     Ok(Span::raw(format!(
@@ -31,19 +31,19 @@ fn format_timestamp(unixtime: u64) -> Result<Span<'static>> {
     )))
 }
 
-fn return_server_error(message: &String, code: &String) -> Result<Line<'static>> {
-    let error = Span::styled(format!("Server Error"), Style::default().fg(Color::Red));
-    let code = Span::raw(code.clone());
+fn return_server_error(message: &str, code: &ErrorCode) -> Result<Line<'static>> {
+    let error = Span::styled("Server Error", Style::default().fg(Color::Red));
+    let code = Span::raw(format!("{} - ", code));
     let space = Span::raw(": ".to_string());
 
-    let message = Span::raw(message.clone());
+    let message = Span::raw(message.to_string());
 
     let content = Line::from(vec![error, space, code, message]);
     Ok(content)
 }
 
-fn return_local_error(message: &String) -> Result<Line> {
-    let error = Span::styled(format!("Local Error"), Style::default().fg(Color::Red));
+fn return_local_error(message: &str) -> Result<Line<'_>> {
+    let error = Span::styled("Local Error", Style::default().fg(Color::Red));
     let space = Span::raw(": ".to_string());
 
     let message = Span::raw(message);
@@ -52,9 +52,9 @@ fn return_local_error(message: &String) -> Result<Line> {
     Ok(content)
 }
 
-fn return_user_left(unixtime: u64, username: &String, uuid: Uuid) -> Result<Line> {
+fn return_user_left(unixtime: u64, username: &str, uuid: Uuid) -> Result<Line<'_>> {
     let timestamp = format_timestamp(unixtime)?;
-    let info = Span::styled(format!("Info: "), Style::default().fg(Color::Yellow));
+    let info = Span::styled("Info: ", Style::default().fg(Color::Yellow));
     let username = Span::styled(username, Style::default().fg(color_from_uuid(uuid)));
 
     let message = Span::raw(" left the room");
@@ -63,10 +63,10 @@ fn return_user_left(unixtime: u64, username: &String, uuid: Uuid) -> Result<Line
     Ok(content)
 }
 
-fn return_user_joined(unixtime: u64, username: &String, uuid: Uuid) -> Result<Line> {
+fn return_user_joined(unixtime: u64, username: &str, uuid: Uuid) -> Result<Line<'_>> {
     let timestamp = format_timestamp(unixtime)?;
 
-    let info = Span::styled(format!("Info: "), Style::default().fg(Color::Yellow));
+    let info = Span::styled("Info: ", Style::default().fg(Color::Yellow));
     let username = Span::styled(username, Style::default().fg(color_from_uuid(uuid)));
 
     let message = Span::raw(" joined the room");
@@ -75,25 +75,197 @@ fn return_user_joined(unixtime: u64, username: &String, uuid: Uuid) -> Result<Li
     Ok(content)
 }
 
-fn return_username_changed<'a>(
+fn return_moderation<'a>(
+    action: &ModerationAction,
+    target: &'a Member,
+    by: &'a Member,
+    until: Option<u64>,
     unixtime: u64,
-    username: &String,
-    old_username: &String,
+) -> Result<Line<'a>> {
+    let timestamp = format_timestamp(unixtime)?;
+    let info = Span::styled("Moderation: ", Style::default().fg(Color::Red));
+    let target_name = Span::styled(
+        target.username.as_str(),
+        Style::default().fg(color_from_uuid(target.uuid)),
+    );
+    let what = match action {
+        ModerationAction::Kicked => " was kicked by ",
+        ModerationAction::Banned => " was banned by ",
+        ModerationAction::Muted => " was muted by ",
+        ModerationAction::Unbanned => " was unbanned by ",
+        ModerationAction::RoleChanged(RoomRole::Moderator) => " was made a moderator by ",
+        ModerationAction::RoleChanged(_) => " was made a member by ",
+    };
+    let by_name = Span::styled(
+        by.username.as_str(),
+        Style::default().fg(color_from_uuid(by.uuid)),
+    );
+    let mut content = Line::from(vec![timestamp, info, target_name, Span::raw(what), by_name]);
+    if let Some(until) = until {
+        let until = Utc
+            .timestamp_millis_opt(until as i64)
+            .single()
+            .context("Invalid Timestamp")?;
+        content.push_span(Span::raw(format!(
+            " until {}",
+            until.with_timezone(&Local).format("%d.%m. %H:%M")
+        )));
+    }
+    Ok(content)
+}
+
+fn return_invite<'a>(code: &'a str, room: &Room, expires_at: u64) -> Result<Line<'a>> {
+    let expires = Utc
+        .timestamp_millis_opt(expires_at as i64)
+        .single()
+        .context("Invalid Timestamp")?;
+    let info = Span::styled("Info: ", Style::default().fg(Color::Yellow));
+    let message = Span::raw(format!("Invite to #{}: ", room.name));
+    let code = Span::styled(code, Style::default().bold());
+    let expires = Span::raw(format!(
+        " (others type /join {}, valid until {})",
+        code.content,
+        expires.with_timezone(&Local).format("%d.%m. %H:%M")
+    ));
+    Ok(Line::from(vec![info, message, code, expires]))
+}
+
+fn return_username_changed(
+    unixtime: u64,
+    username: &str,
+    old_username: &str,
     uuid: Uuid,
 ) -> Result<Line<'static>> {
     let timestamp = format_timestamp(unixtime)?;
 
     let info = Span::styled("Info: ".to_string(), Style::default().fg(Color::Yellow));
-    let old_username = Span::styled(old_username.clone(), Style::default().fg(color_from_uuid(uuid)));
+    let old_username = Span::styled(old_username.to_string(), Style::default().fg(color_from_uuid(uuid)));
 
     let message = Span::raw(" changed his name to ");
-    let username = Span::styled(username.clone(), Style::default().fg(color_from_uuid(uuid)));
+    let username = Span::styled(username.to_string(), Style::default().fg(color_from_uuid(uuid)));
 
     let content = Line::from(vec![timestamp, info, old_username, message, username]);
     Ok(content)
 }
 
-fn return_posted_message(message: &TalkMessage) -> Result<Line> {
+/// Longest excerpt of a parent message quoted above a reply.
+const QUOTE_LENGTH: usize = 60;
+
+fn find_message(messages: &[TalkProtocol], id: u64) -> Option<&TalkMessage> {
+    messages.iter().find_map(|proto| match proto {
+        TalkProtocol::PostMessage { message } if message.id == id => Some(message),
+        _ => None,
+    })
+}
+
+fn return_quote(parent: Option<&TalkMessage>) -> Line<'_> {
+    let style = Style::default().fg(Color::DarkGray);
+    let Some(parent) = parent else {
+        return Line::from(Span::styled("  ╭ reply to an earlier message", style));
+    };
+    let username = Span::styled(
+        format!("  ╭ {}: ", parent.username),
+        Style::default().fg(color_from_uuid(parent.uuid)),
+    );
+    let text = if parent.deleted_at.is_some() {
+        "message deleted".to_string()
+    } else if parent.text.chars().count() > QUOTE_LENGTH {
+        format!("{}…", parent.text.chars().take(QUOTE_LENGTH).collect::<String>())
+    } else {
+        parent.text.clone()
+    };
+    Line::from(vec![username, Span::styled(text, style.add_modifier(Modifier::ITALIC))])
+}
+
+/// Renders a chat message, preceded by a quote of its parent if it is a reply.
+fn return_posted_message<'a>(
+    message: &'a TalkMessage,
+    messages: &'a [TalkProtocol],
+    selected: bool,
+    own_uuid: Uuid,
+) -> Result<Vec<Line<'a>>> {
+    let mut lines = Vec::new();
+    if let Some(reply_to) = message.reply_to {
+        lines.push(return_quote(find_message(messages, reply_to)));
+    }
+    let mut line = return_message_line(message)?;
+    if selected {
+        line = line.patch_style(Style::default().add_modifier(Modifier::REVERSED));
+    }
+    lines.push(line);
+    if !message.reactions.is_empty() {
+        lines.push(return_reactions(&message.reactions, own_uuid));
+    }
+    Ok(lines)
+}
+
+/// Summary line under a message, our own reactions are highlighted.
+fn return_reactions(reactions: &[Reaction], own_uuid: Uuid) -> Line<'static> {
+    let mut spans = vec![Span::raw("   ")];
+    for reaction in reactions {
+        let style = if reaction.users.contains(&own_uuid) {
+            Style::default().fg(Color::Yellow)
+        } else {
+            Style::default().fg(Color::DarkGray)
+        };
+        spans.push(Span::styled(
+            format!(" {} {} ", reaction.emoji, reaction.users.len()),
+            style,
+        ));
+    }
+    Line::from(spans)
+}
+
+fn return_line(proto: &TalkProtocol) -> Result<Line<'_>> {
+    match proto {
+        TalkProtocol::Error { code, message, .. } => return_server_error(message, code),
+        TalkProtocol::LocalError { message } => return_local_error(message),
+        TalkProtocol::PostMessage { message } => return_message_line(message),
+        TalkProtocol::UserJoined {
+            uuid,
+            username,
+            unixtime,
+            ..
+        } => return_user_joined(*unixtime, username, *uuid),
+        TalkProtocol::UserLeft {
+            uuid,
+            username,
+            unixtime,
+            ..
+        } => return_user_left(*unixtime, username, *uuid),
+        TalkProtocol::UsernameChanged {
+            uuid,
+            username,
+            old_username,
+            unixtime,
+        } => return_username_changed(*unixtime, username, old_username, *uuid),
+        TalkProtocol::Invite {
+            code,
+            room,
+            expires_at,
+        } => return_invite(code, room, *expires_at),
+        TalkProtocol::Moderation {
+            action,
+            target,
+            by,
+            until,
+            unixtime,
+            ..
+        } => return_moderation(action, target, by, *until, *unixtime),
+        _ => Ok(Line::from(Span::raw(format!("{:?}", proto)))),
+    }
+}
+
+fn input_title(app: &App) -> String {
+    let communication = app.communication.lock().expect("Vector with all messages");
+    match app.reply_to.map(|id| find_message(&communication, id)) {
+        Some(Some(parent)) => format!("Replying to {}", parent.username),
+        Some(None) => "Replying".to_string(),
+        None => "Input".to_string(),
+    }
+}
+
+fn return_message_line(message: &TalkMessage) -> Result<Line<'_>> {
     let timestamp = format_timestamp(message.unixtime)?;
 
     let username = Span::styled(
@@ -101,12 +273,263 @@ fn return_posted_message(message: &TalkMessage) -> Result<Line> {
         Style::default().fg(color_from_uuid(message.uuid)),
     );
 
-    let message = Span::raw(message.text.clone());
+    if message.deleted_at.is_some() {
+        let tombstone = Span::styled(
+            "message deleted",
+            Style::default().fg(Color::DarkGray).add_modifier(Modifier::ITALIC),
+        );
+        return Ok(Line::from(vec![timestamp, username, tombstone]));
+    }
 
-    let content = Line::from(vec![timestamp, username, message]);
+    let mut spans = vec![timestamp, username, Span::raw(message.text.clone())];
+    if message.edited_at.is_some() {
+        spans.push(Span::styled(" (edited)", Style::default().fg(Color::DarkGray)));
+    }
+
+    let content = Line::from(spans);
     Ok(content)
 }
 
+/// Hides the password while typing `/login` or `/register`.
+fn mask_password(input: &str) -> String {
+    if !(input.starts_with("/login ") || input.starts_with("/register ")) {
+        return input.to_string();
+    }
+    let mut words = 0;
+    let mut in_word = false;
+    input
+        .chars()
+        .map(|c| {
+            if c.is_whitespace() {
+                in_word = false;
+                return c;
+            }
+            if !in_word {
+                in_word = true;
+                words += 1;
+            }
+            if words >= 3 { '*' } else { c }
+        })
+        .collect()
+}
+
+fn typing_notice(app: &App) -> Option<String> {
+    let names: Vec<&str> = app.typing.iter().map(|(_, name, _)| name.as_str()).collect();
+    match names.as_slice() {
+        [] => None,
+        [name] => Some(format!("  {} is typing…", name)),
+        [first, second] => Some(format!("  {} and {} are typing…", first, second)),
+        _ => Some("  Several people are typing…".to_string()),
+    }
+}
+
+fn room_lines<'a>(app: &App, messages: &'a [TalkProtocol]) -> Vec<Line<'a>> {
+    room_lines_until(app, messages, messages.len())
+}
+
+/// Lines of the first `count` messages, replies still quote any message.
+fn room_lines_until<'a>(app: &App, messages: &'a [TalkProtocol], count: usize) -> Vec<Line<'a>> {
+    messages[..count]
+        .iter()
+        .map(|proto| match proto {
+            TalkProtocol::PostMessage { message } => return_posted_message(
+                message,
+                messages,
+                app.selected == Some(message.id),
+                app.uuid,
+            ),
+            _ => return_line(proto).map(|line| vec![line]),
+        })
+        .collect::<Result<Vec<Vec<Line>>, anyhow::Error>>()
+        .expect("lines of text")
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+fn return_direct_message(message: &DirectMessage, own_uuid: Uuid) -> Result<Line<'_>> {
+    let timestamp = format_timestamp(message.unixtime)?;
+    let (arrow, partner, partner_uuid) = if message.sender == own_uuid {
+        ("→ ", &message.recipient_name, message.recipient)
+    } else {
+        ("← ", &message.sender_name, message.sender)
+    };
+    let partner = Span::styled(
+        format!("{}: ", partner),
+        Style::default().fg(color_from_uuid(partner_uuid)),
+    );
+    Ok(Line::from(vec![
+        timestamp,
+        Span::styled(arrow, Style::default().fg(Color::DarkGray)),
+        partner,
+        Span::raw(message.text.clone()),
+    ]))
+}
+
+fn messages_title(app: &App) -> String {
+    let mut room = match &app.room_info {
+        Some(room) if room.topic.is_empty() => format!("#{}", room.name),
+        Some(room) => format!("#{} - {}", room.name, room.topic),
+        None => format!("Room {}", app.room),
+    };
+    if let Some(info) = &app.room_info
+        && info.slow_mode_secs > 0
+    {
+        room.push_str(&format!(" [slow mode: {}s]", info.slow_mode_secs));
+    }
+    if let Some(info) = &app.room_info {
+        let Retention {
+            max_age_secs,
+            max_messages,
+        } = info.retention;
+        let limits: Vec<String> = [
+            max_age_secs.map(|secs| format!("{} days", secs.div_ceil(24 * 60 * 60))),
+            max_messages.map(|count| format!("{} messages", count)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if !limits.is_empty() {
+            room.push_str(&format!(" [keeps {}]", limits.join(", ")));
+        }
+    }
+    match app.view {
+        View::Room if app.unread_directs > 0 => format!(
+            " Chatting in {} ({} unread direct messages, press d) ",
+            room, app.unread_directs
+        ),
+        View::Room => format!(" Chatting in {} ", room),
+        View::Direct => match &app.direct_partner {
+            Some((_, partner)) => format!(" Direct messages, replying to {} ", partner),
+            None => " Direct messages ".to_string(),
+        },
+    }
+}
+
+/// Room for the longest username plus the border.
+const MEMBERS_WIDTH: u16 = MAX_USERNAME_LENGTH as u16 + 4;
+
+fn draw_members(app: &App, frame: &mut Frame, area: Rect) {
+    let lines: Vec<Line> = app
+        .members
+        .iter()
+        .map(|member| {
+            Line::from(Span::styled(
+                member.username.clone(),
+                Style::default().fg(color_from_uuid(member.uuid)),
+            ))
+        })
+        .collect();
+    frame.render_widget(
+        Paragraph::new(lines)
+            .block(Block::bordered().title(format!(" Members ({}) ", app.members.len()))),
+        area,
+    );
+}
+
+fn format_activity(last_activity: Option<u64>) -> String {
+    last_activity
+        .and_then(|time| Utc.timestamp_millis_opt(time as i64).single())
+        .map(|time| time.with_timezone(&Local).format("%d.%m. %H:%M").to_string())
+        .unwrap_or_else(|| "never".to_string())
+}
+
+fn draw_rooms(app: &App, frame: &mut Frame) {
+    let vertical = Layout::vertical([Constraint::Percentage(60)]).flex(Flex::Center);
+    let horizontal = Layout::horizontal([Constraint::Percentage(60)]).flex(Flex::Center);
+    let [area] = vertical.areas(frame.area());
+    let [area] = horizontal.areas(area);
+
+    let lines: Vec<Line> = if app.rooms.is_empty() {
+        vec![Line::from("No public rooms yet, create one with /create <name>")]
+    } else {
+        app.rooms
+            .iter()
+            .enumerate()
+            .map(|(index, summary)| {
+                let mut line = Line::from(vec![
+                    Span::styled(format!("#{} ", summary.room.name), Style::default().bold()),
+                    Span::raw(format!(
+                        "{} online, last active {} ",
+                        summary.members,
+                        format_activity(summary.last_activity)
+                    )),
+                    Span::styled(
+                        summary.room.topic.clone(),
+                        Style::default().fg(Color::DarkGray),
+                    ),
+                ]);
+                if index == app.rooms_selected {
+                    line = line.patch_style(Style::default().add_modifier(Modifier::REVERSED));
+                }
+                line
+            })
+            .collect()
+    };
+    frame.render_widget(Clear, area);
+    frame.render_widget(
+        Paragraph::new(lines).block(
+            Block::bordered().title(" Rooms (↑/↓ to select, Enter to join, Esc to close) "),
+        ),
+        area,
+    );
+}
+
+/// Splits a search snippet into plain and highlighted spans, matches are
+/// every other part.
+fn highlight_snippet(snippet: &str) -> Vec<Span<'_>> {
+    snippet
+        .split([SEARCH_MATCH_START, SEARCH_MATCH_END])
+        .enumerate()
+        .map(|(index, part)| {
+            if index % 2 == 1 {
+                Span::styled(part, Style::default().fg(Color::Yellow).bold())
+            } else {
+                Span::raw(part)
+            }
+        })
+        .collect()
+}
+
+fn draw_search(app: &App, frame: &mut Frame) {
+    let vertical = Layout::vertical([Constraint::Percentage(60)]).flex(Flex::Center);
+    let horizontal = Layout::horizontal([Constraint::Percentage(80)]).flex(Flex::Center);
+    let [area] = vertical.areas(frame.area());
+    let [area] = horizontal.areas(area);
+
+    let lines: Vec<Line> = if app.search_hits.is_empty() {
+        vec![Line::from("Nothing found")]
+    } else {
+        app.search_hits
+            .iter()
+            .enumerate()
+            .map(|(index, hit)| {
+                let mut spans = vec![
+                    Span::raw(format!("<{}> ", format_activity(Some(hit.message.unixtime)))),
+                    Span::styled(
+                        format!("{}: ", hit.message.username),
+                        Style::default().fg(color_from_uuid(hit.message.uuid)),
+                    ),
+                ];
+                spans.extend(highlight_snippet(&hit.snippet));
+                let mut line = Line::from(spans);
+                if index == app.search_selected {
+                    line = line.patch_style(Style::default().add_modifier(Modifier::REVERSED));
+                }
+                line
+            })
+            .collect()
+    };
+    frame.render_widget(Clear, area);
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(format!(
+            " Results for \"{}\" (↑/↓ to select, Enter to jump, Esc to close) ",
+            app.search_query
+        ))),
+        area,
+    );
+}
+
 pub fn draw(app: &mut App, frame: &mut Frame) {
     let vertical = Layout::vertical([
         Constraint::Length(1),
@@ -114,15 +537,49 @@ pub fn draw(app: &mut App, frame: &mut Frame) {
         Constraint::Min(1),
     ]);
     let [help_area, input_area, messages_area] = vertical.areas(frame.area());
+    let messages_area = if app.show_members {
+        let horizontal = Layout::horizontal([
+            Constraint::Min(1),
+            Constraint::Length(MEMBERS_WIDTH),
+        ]);
+        let [messages_area, members_area] = horizontal.areas(messages_area);
+        draw_members(app, frame, members_area);
+        messages_area
+    } else {
+        messages_area
+    };
 
     let (msg, style) = match app.input_mode {
+        InputMode::Normal if !app.authenticated => (
+            vec![
+                "Press ".into(),
+                "i".bold(),
+                " and type ".into(),
+                "/login <name> <password>".bold(),
+                " or ".into(),
+                "/register <name> <password>".bold(),
+            ],
+            Style::default(),
+        ),
         InputMode::Normal => (
             vec![
                 "Press ".into(),
                 "q".bold(),
                 " to exit, ".into(),
                 "i".bold(),
-                " to start editing.".bold(),
+                " to start editing, ".bold(),
+                "↑/↓".bold(),
+                " to select, ".into(),
+                "r".bold(),
+                " to reply, ".into(),
+                "+".bold(),
+                " to react, ".into(),
+                "m".bold(),
+                " for members, ".into(),
+                "d".bold(),
+                " for direct messages, ".into(),
+                "o".bold(),
+                " for rooms.".into(),
             ],
             Style::default().add_modifier(Modifier::RAPID_BLINK),
         ),
@@ -137,15 +594,21 @@ pub fn draw(app: &mut App, frame: &mut Frame) {
             Style::default(),
         ),
     };
-    let text = Text::from(Line::from(msg)).patch_style(style);
-    frame.render_widget(Paragraph::new(text), help_area);
+    let mut line = Line::from(msg).patch_style(style);
+    if let Some(typing) = typing_notice(app) {
+        line.push_span(Span::styled(
+            typing,
+            Style::default().fg(Color::DarkGray).add_modifier(Modifier::ITALIC),
+        ));
+    }
+    frame.render_widget(Paragraph::new(Text::from(line)), help_area);
 
-    let input = Paragraph::new(app.input.as_str())
+    let input = Paragraph::new(mask_password(&app.input))
         .style(match app.input_mode {
             InputMode::Normal => Style::default(),
             InputMode::Editing => Style::default().fg(Color::Yellow),
         })
-        .block(Block::bordered().title("Input"));
+        .block(Block::bordered().title(input_title(app)));
     frame.render_widget(input, input_area);
 
     if let InputMode::Editing = app.input_mode {
@@ -157,36 +620,18 @@ pub fn draw(app: &mut App, frame: &mut Frame) {
 
     let full_messages = app.communication.lock().expect("Vector with all messages");
 
-    let lines: Vec<Line> = full_messages
-        .iter()
-        .map(|proto| match proto {
-            TalkProtocol::Error { code, message } => return_server_error(message, code),
-            TalkProtocol::LocalError { message } => return_local_error(message),
-            TalkProtocol::PostMessage { message } => return_posted_message(message),
-            TalkProtocol::UserJoined {
-                uuid,
-                username,
-                room_id: _,
-                unixtime,
-            } => return_user_joined(*unixtime, username, uuid.clone()),
-            TalkProtocol::UserLeft {
-                uuid,
-                username,
-                room_id: _,
-                unixtime,
-            } => return_user_left(*unixtime, username, uuid.clone()),
-            TalkProtocol::UsernameChanged {
-                uuid,
-                username,
-                old_username,
-                unixtime,
-            } => return_username_changed(*unixtime, username, old_username, uuid.clone()),
-            _ => Ok(Line::from(Span::raw(format!("{:?}", proto)))),
-        })
-        .collect::<Result<Vec<Line>, anyhow::Error>>().expect("lines of text");
+    let lines: Vec<Line> = match app.view {
+        View::Room => room_lines(app, &full_messages),
+        View::Direct => app
+            .directs
+            .iter()
+            .map(|message| return_direct_message(message, app.uuid))
+            .collect::<Result<Vec<Line>, anyhow::Error>>()
+            .expect("lines of text"),
+    };
     let paragraph = Paragraph::new(lines).wrap(Wrap { trim: true });
 
-    let total_lines = paragraph.line_count(messages_area.width.into());
+    let total_lines = paragraph.line_count(messages_area.width);
     let visible_height = messages_area.height.saturating_sub(2) as usize;
     app.max_scroll = total_lines.saturating_sub(visible_height);
 
@@ -194,14 +639,52 @@ pub fn draw(app: &mut App, frame: &mut Frame) {
         app.scroll = total_lines.saturating_sub(visible_height);
     }
 
+    // Jumping to a search hit puts it in the middle of the view
+    if app.scroll_to_selected {
+        app.scroll_to_selected = false;
+        let index = app
+            .selected
+            .and_then(|id| full_messages.iter().position(|proto| proto.id() == Some(id)));
+        if let Some(index) = index {
+            let above = Paragraph::new(room_lines_until(app, &full_messages, index))
+                .wrap(Wrap { trim: true })
+                .line_count(messages_area.width);
+            app.scroll = above.saturating_sub(visible_height / 2);
+        }
+    }
+
     app.scroll = app
         .scroll
         .clamp(0, total_lines.saturating_sub(visible_height));
 
     frame.render_widget(
         paragraph
-            .block(Block::bordered().title(format!(" Chatting in Room {} ", app.room)))
+            .block(Block::bordered().title(messages_title(app)))
             .scroll((app.scroll as u16, 0)),
         messages_area,
     );
+    if app.show_rooms {
+        draw_rooms(app, frame);
+    }
+    if app.show_search {
+        draw_search(app, frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlights_every_match_of_a_snippet() {
+        let snippet = format!(
+            "say {}hello{} and {}hello{} again",
+            SEARCH_MATCH_START, SEARCH_MATCH_END, SEARCH_MATCH_START, SEARCH_MATCH_END
+        );
+        let spans = highlight_snippet(&snippet);
+        let parts: Vec<&str> = spans.iter().map(|span| span.content.as_ref()).collect();
+        assert_eq!(parts, ["say ", "hello", " and ", "hello", " again"]);
+        assert_eq!(spans[1].style.fg, Some(Color::Yellow));
+        assert_eq!(spans[2].style, Style::default());
+    }
 }
@@ -0,0 +1,531 @@
+//! Shapes of older protocol revisions, so a peer keeps working when the other
+//! side of the connection updates first.
+//!
+//! bincode writes fields back to back without names, and fields were only
+//! ever added at the end of a shape. An older shape is therefore the current
+//! one without the fields added since, which get their defaults when decoding.
+//! Variants added later never reach older peers, they are gated on
+//! capabilities those peers don't know.
+
+use super::*;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+
+// Revisions that added fields to existing shapes.
+const EDIT_MARKERS: u16 = 6;
+const REPLIES: u16 = 7;
+const REACTIONS: u16 = 8;
+const RETRY_HINTS: u16 = 9;
+const SLOW_MODE: u16 = 9;
+const RETENTION: u16 = 10;
+
+// Variant indices bincode writes for the variants whose shape changed.
+const HISTORY: u32 = 8;
+const ERROR: u32 = 9;
+const POST_MESSAGE: u32 = 10;
+const ROOM_INFO: u32 = 37;
+const ROOM_LIST: u32 = 39;
+const INVITE: u32 = 42;
+const INVITE_REDEEMED: u32 = 43;
+
+fn write<T: Serialize + ?Sized>(out: &mut Vec<u8>, value: &T) -> bincode::Result<()> {
+    bincode::serialize_into(out, value)
+}
+
+fn read<T: DeserializeOwned>(
+    input: &mut &[u8],
+    options: impl Options + Copy,
+) -> bincode::Result<T> {
+    options.deserialize_from(input)
+}
+
+/// Appends `msg` as a peer speaking `version` expects it.
+pub(crate) fn encode(msg: &TalkProtocol, version: u16, out: &mut Vec<u8>) -> bincode::Result<()> {
+    if version >= PROTOCOL_VERSION {
+        return write(out, msg);
+    }
+    match msg {
+        TalkProtocol::History {
+            text,
+            has_more,
+            before_id,
+            after_id,
+        } => {
+            write(out, &HISTORY)?;
+            write(out, &(text.len() as u64))?;
+            for msg in text {
+                encode(msg, version, out)?;
+            }
+            write(out, &(has_more, before_id, after_id))
+        }
+        TalkProtocol::Error {
+            code,
+            message,
+            retry_after_ms,
+        } => {
+            write(out, &ERROR)?;
+            write(out, &(error_code(*code, version), message))?;
+            if version >= RETRY_HINTS {
+                write(out, retry_after_ms)?;
+            }
+            Ok(())
+        }
+        TalkProtocol::PostMessage { message } => {
+            write(out, &POST_MESSAGE)?;
+            encode_message(message, version, out)
+        }
+        TalkProtocol::RoomInfo { room } => {
+            write(out, &ROOM_INFO)?;
+            encode_room(room, version, out)
+        }
+        TalkProtocol::RoomList { rooms } => {
+            write(out, &ROOM_LIST)?;
+            write(out, &(rooms.len() as u64))?;
+            for summary in rooms {
+                encode_room(&summary.room, version, out)?;
+                write(out, &(summary.members, summary.last_activity))?;
+            }
+            Ok(())
+        }
+        TalkProtocol::Invite {
+            code,
+            room,
+            expires_at,
+        } => {
+            write(out, &INVITE)?;
+            write(out, code)?;
+            encode_room(room, version, out)?;
+            write(out, expires_at)
+        }
+        TalkProtocol::InviteRedeemed { room } => {
+            write(out, &INVITE_REDEEMED)?;
+            encode_room(room, version, out)
+        }
+        _ => write(out, msg),
+    }
+}
+
+fn encode_message(message: &TalkMessage, version: u16, out: &mut Vec<u8>) -> bincode::Result<()> {
+    let TalkMessage {
+        id,
+        uuid,
+        username,
+        text,
+        room_id,
+        unixtime,
+        seq,
+        edited_at,
+        deleted_at,
+        reply_to,
+        reactions,
+    } = message;
+    write(out, &(id, uuid, username, text, room_id, unixtime, seq))?;
+    if version >= EDIT_MARKERS {
+        write(out, &(edited_at, deleted_at))?;
+    }
+    if version >= REPLIES {
+        write(out, reply_to)?;
+    }
+    if version >= REACTIONS {
+        write(out, reactions)?;
+    }
+    Ok(())
+}
+
+fn encode_room(room: &Room, version: u16, out: &mut Vec<u8>) -> bincode::Result<()> {
+    let Room {
+        id,
+        name,
+        topic,
+        created_by,
+        created_at,
+        visibility,
+        slow_mode_secs,
+        retention,
+    } = room;
+    write(out, &(id, name, topic, created_by, created_at, visibility))?;
+    if version >= SLOW_MODE {
+        write(out, slow_mode_secs)?;
+    }
+    if version >= RETENTION {
+        write(out, retention)?;
+    }
+    Ok(())
+}
+
+/// Reads a message a peer speaking `version` sent, leaving `input` after it.
+pub(crate) fn decode(
+    input: &mut &[u8],
+    version: u16,
+    options: impl Options + Copy,
+) -> bincode::Result<TalkProtocol> {
+    let index = match input.get(..4) {
+        Some(index) if version < PROTOCOL_VERSION => {
+            u32::from_le_bytes(index.try_into().expect("four bytes"))
+        }
+        _ => return read(input, options),
+    };
+    if !matches!(
+        index,
+        HISTORY | ERROR | POST_MESSAGE | ROOM_INFO | ROOM_LIST | INVITE | INVITE_REDEEMED
+    ) {
+        return read(input, options);
+    }
+    *input = &input[4..];
+
+    Ok(match index {
+        HISTORY => {
+            let len: u64 = read(input, options)?;
+            // Every entry takes a few bytes at least, running out of them
+            // ends a bogus length
+            let mut text = Vec::new();
+            for _ in 0..len {
+                text.push(decode(input, version, options)?);
+            }
+            let (has_more, before_id, after_id) = read(input, options)?;
+            TalkProtocol::History {
+                text,
+                has_more,
+                before_id,
+                after_id,
+            }
+        }
+        ERROR => {
+            let (code, message) = read(input, options)?;
+            let retry_after_ms = if version >= RETRY_HINTS {
+                read(input, options)?
+            } else {
+                None
+            };
+            TalkProtocol::Error {
+                code,
+                message,
+                retry_after_ms,
+            }
+        }
+        POST_MESSAGE => TalkProtocol::PostMessage {
+            message: decode_message(input, version, options)?,
+        },
+        ROOM_INFO => TalkProtocol::RoomInfo {
+            room: decode_room(input, version, options)?,
+        },
+        ROOM_LIST => {
+            let len: u64 = read(input, options)?;
+            let mut rooms = Vec::new();
+            for _ in 0..len {
+                let room = decode_room(input, version, options)?;
+                let (members, last_activity) = read(input, options)?;
+                rooms.push(RoomSummary {
+                    room,
+                    members,
+                    last_activity,
+                });
+            }
+            TalkProtocol::RoomList { rooms }
+        }
+        INVITE => TalkProtocol::Invite {
+            code: read(input, options)?,
+            room: decode_room(input, version, options)?,
+            expires_at: read(input, options)?,
+        },
+        _ => TalkProtocol::InviteRedeemed {
+            room: decode_room(input, version, options)?,
+        },
+    })
+}
+
+fn decode_message(
+    input: &mut &[u8],
+    version: u16,
+    options: impl Options + Copy,
+) -> bincode::Result<TalkMessage> {
+    let (id, uuid, username, text, room_id, unixtime, seq) = read(input, options)?;
+    let (edited_at, deleted_at) = if version >= EDIT_MARKERS {
+        read(input, options)?
+    } else {
+        (None, None)
+    };
+    let reply_to = if version >= REPLIES {
+        read(input, options)?
+    } else {
+        None
+    };
+    let reactions = if version >= REACTIONS {
+        read(input, options)?
+    } else {
+        Vec::new()
+    };
+    Ok(TalkMessage {
+        id,
+        uuid,
+        username,
+        text,
+        room_id,
+        unixtime,
+        seq,
+        edited_at,
+        deleted_at,
+        reply_to,
+        reactions,
+    })
+}
+
+fn decode_room(
+    input: &mut &[u8],
+    version: u16,
+    options: impl Options + Copy,
+) -> bincode::Result<Room> {
+    let (id, name, topic, created_by, created_at, visibility) = read(input, options)?;
+    let slow_mode_secs = if version >= SLOW_MODE {
+        read(input, options)?
+    } else {
+        0
+    };
+    let retention = if version >= RETENTION {
+        read(input, options)?
+    } else {
+        Retention::default()
+    };
+    Ok(Room {
+        id,
+        name,
+        topic,
+        created_by,
+        created_at,
+        visibility,
+        slow_mode_secs,
+        retention,
+    })
+}
+
+/// `code`, or the closest one a peer speaking `version` knows.
+fn error_code(code: ErrorCode, version: u16) -> ErrorCode {
+    let since = match code {
+        ErrorCode::MessageNotFound | ErrorCode::Forbidden => EDIT_MARKERS,
+        ErrorCode::UserNotFound
+        | ErrorCode::RoomExists
+        | ErrorCode::InvalidInvite
+        | ErrorCode::Muted => REACTIONS,
+        _ => 0,
+    };
+    match code {
+        _ if version >= since => code,
+        ErrorCode::Muted => error_code(ErrorCode::Forbidden, version),
+        _ => ErrorCode::InvalidInput,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `TalkMessage` as revision 7 declared it.
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct MessageV7 {
+        id: u64,
+        uuid: Uuid,
+        username: String,
+        text: String,
+        room_id: i32,
+        unixtime: u64,
+        seq: u64,
+        edited_at: Option<u64>,
+        deleted_at: Option<u64>,
+        reply_to: Option<u64>,
+    }
+
+    fn message() -> TalkMessage {
+        TalkMessage {
+            id: 7,
+            uuid: Uuid::new_v4(),
+            username: "alice".to_string(),
+            text: "hello".to_string(),
+            room_id: 1,
+            unixtime: 1_000,
+            seq: 3,
+            edited_at: Some(2_000),
+            deleted_at: None,
+            reply_to: Some(5),
+            reactions: vec![Reaction {
+                emoji: "+1".to_string(),
+                users: vec![Uuid::new_v4()],
+            }],
+        }
+    }
+
+    fn room() -> Room {
+        Room {
+            id: 1,
+            name: "lobby".to_string(),
+            topic: "Say hi".to_string(),
+            created_by: None,
+            created_at: 1_000,
+            visibility: Visibility::Public,
+            slow_mode_secs: 30,
+            retention: Retention {
+                max_age_secs: Some(60),
+                max_messages: None,
+            },
+        }
+    }
+
+    fn error() -> TalkProtocol {
+        TalkProtocol::Error {
+            code: ErrorCode::Muted,
+            message: "Quiet".to_string(),
+            retry_after_ms: Some(500),
+        }
+    }
+
+    fn round_trip(msg: &TalkProtocol, version: u16) -> TalkProtocol {
+        let bytes = msg.serialize_for(version).unwrap();
+        TalkProtocol::deserialize_for(&bytes, version).unwrap()
+    }
+
+    #[test]
+    fn variant_indices_match_the_enum() {
+        let index = |msg: &TalkProtocol| {
+            u32::from_le_bytes(msg.serialize().unwrap()[..4].try_into().unwrap())
+        };
+        let history = TalkProtocol::History {
+            text: Vec::new(),
+            has_more: false,
+            before_id: None,
+            after_id: None,
+        };
+        assert_eq!(index(&history), HISTORY);
+        assert_eq!(index(&error()), ERROR);
+        assert_eq!(
+            index(&TalkProtocol::PostMessage { message: message() }),
+            POST_MESSAGE
+        );
+        assert_eq!(index(&TalkProtocol::RoomInfo { room: room() }), ROOM_INFO);
+        assert_eq!(
+            index(&TalkProtocol::RoomList { rooms: Vec::new() }),
+            ROOM_LIST
+        );
+        let invite = TalkProtocol::Invite {
+            code: "abc".to_string(),
+            room: room(),
+            expires_at: 0,
+        };
+        assert_eq!(index(&invite), INVITE);
+        assert_eq!(
+            index(&TalkProtocol::InviteRedeemed { room: room() }),
+            INVITE_REDEEMED
+        );
+    }
+
+    #[test]
+    fn older_revisions_get_the_shape_they_declared() {
+        let message = message();
+        let bytes = TalkProtocol::PostMessage {
+            message: message.clone(),
+        }
+        .serialize_for(REPLIES)
+        .unwrap();
+        let expected = MessageV7 {
+            id: message.id,
+            uuid: message.uuid,
+            username: message.username,
+            text: message.text,
+            room_id: message.room_id,
+            unixtime: message.unixtime,
+            seq: message.seq,
+            edited_at: message.edited_at,
+            deleted_at: message.deleted_at,
+            reply_to: message.reply_to,
+        };
+        assert_eq!(bytes[..4], POST_MESSAGE.to_le_bytes());
+        assert_eq!(bytes[4..], bincode::serialize(&expected).unwrap());
+    }
+
+    #[test]
+    fn fields_added_since_come_back_as_defaults() {
+        let msg = TalkProtocol::History {
+            text: vec![TalkProtocol::PostMessage { message: message() }, error()],
+            has_more: true,
+            before_id: Some(9),
+            after_id: None,
+        };
+        let TalkProtocol::History { text, has_more, .. } = round_trip(&msg, MIN_PROTOCOL_VERSION)
+        else {
+            panic!("expected History");
+        };
+        assert!(has_more);
+        let TalkProtocol::PostMessage { message: decoded } = &text[0] else {
+            panic!("expected PostMessage");
+        };
+        assert_eq!(decoded.text, "hello");
+        assert_eq!((decoded.edited_at, decoded.reply_to), (None, None));
+        assert!(decoded.reactions.is_empty());
+        assert_eq!(
+            text[1],
+            TalkProtocol::Error {
+                code: ErrorCode::InvalidInput,
+                message: "Quiet".to_string(),
+                retry_after_ms: None,
+            }
+        );
+
+        let TalkProtocol::RoomInfo { room: decoded } =
+            round_trip(&TalkProtocol::RoomInfo { room: room() }, SLOW_MODE)
+        else {
+            panic!("expected RoomInfo");
+        };
+        assert_eq!(decoded.slow_mode_secs, 30);
+        assert_eq!(decoded.retention, Retention::default());
+    }
+
+    #[test]
+    fn every_supported_revision_round_trips_what_it_knows() {
+        let invite = TalkProtocol::Invite {
+            code: "abc".to_string(),
+            room: room(),
+            expires_at: 5,
+        };
+        let summary = RoomSummary {
+            room: room(),
+            members: 2,
+            last_activity: Some(3),
+        };
+        for version in MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION {
+            for msg in [
+                TalkProtocol::RoomList {
+                    rooms: vec![summary.clone()],
+                },
+                invite.clone(),
+                TalkProtocol::Typing {
+                    room_id: 1,
+                    uuid: Uuid::nil(),
+                    username: "bob".to_string(),
+                },
+            ] {
+                let decoded = round_trip(&msg, version);
+                assert_eq!(
+                    decoded.serialize_for(version).unwrap(),
+                    msg.serialize_for(version).unwrap()
+                );
+            }
+        }
+        assert_eq!(round_trip(&error(), PROTOCOL_VERSION), error());
+    }
+
+    #[test]
+    fn unknown_error_codes_fall_back() {
+        assert_eq!(error_code(ErrorCode::Muted, REACTIONS), ErrorCode::Muted);
+        assert_eq!(error_code(ErrorCode::Muted, REPLIES), ErrorCode::Forbidden);
+        assert_eq!(error_code(ErrorCode::Muted, 5), ErrorCode::InvalidInput);
+        assert_eq!(
+            error_code(ErrorCode::RoomNotFound, 5),
+            ErrorCode::RoomNotFound
+        );
+    }
+
+    #[test]
+    fn bogus_lengths_run_out_of_input() {
+        let mut bytes = HISTORY.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(TalkProtocol::deserialize_for(&bytes, MIN_PROTOCOL_VERSION).is_err());
+    }
+}
@@ -1,34 +1,529 @@
+use bincode::Options;
 use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    ops::BitOr,
+    sync::{
+        Arc,
+        atomic::{AtomicU16, Ordering},
+    },
+};
 use uuid::Uuid;
 
+mod legacy;
+
+/// Revision of the wire format spoken by this build. Bump it whenever an
+/// existing variant or struct changes shape, and teach `legacy` the old one.
+pub const PROTOCOL_VERSION: u16 = 10;
+
+/// Oldest revision the server still talks to, clients that never send a
+/// `Hello` count as revision 0. `legacy` speaks every revision since, the
+/// ones before paged history by time and sequence number instead of ids.
+pub const MIN_PROTOCOL_VERSION: u16 = 5;
+
+/// Whether a peer speaking `version` can still be talked to. Newer peers are
+/// fine, they get downgraded to `PROTOCOL_VERSION` during the handshake.
+pub fn is_supported_version(version: u16) -> bool {
+    (MIN_PROTOCOL_VERSION..).contains(&version)
+}
+
+/// Longest chat message, in characters.
+pub const MAX_MESSAGE_LENGTH: usize = 250;
+
+/// Longest username, in characters.
+pub const MAX_USERNAME_LENGTH: usize = 15;
+
+/// Most messages a single `Fetch` returns.
+pub const MAX_FETCH_LIMIT: i64 = 100;
+
+/// Longest room name, in characters.
+pub const MAX_ROOM_NAME_LENGTH: usize = 32;
+
+/// Longest room topic, in characters.
+pub const MAX_TOPIC_LENGTH: usize = 200;
+
+/// Longest wait a room's slow mode can impose between two messages.
+pub const MAX_SLOW_MODE_SECS: u32 = 60 * 60;
+
+/// Most hits a single `Search` returns.
+pub const MAX_SEARCH_LIMIT: i64 = 50;
+
+/// Surround the matched words in `SearchHit::snippet`.
+pub const SEARCH_MATCH_START: char = '\u{2}';
+pub const SEARCH_MATCH_END: char = '\u{3}';
+
+/// Longest reaction, in characters. Enough for emoji made of several code
+/// points, too short to abuse reactions as messages.
+pub const MAX_REACTION_LENGTH: usize = 16;
+
+/// Why the server refused a request, so clients can react without matching on
+/// the message text. Like `TalkProtocol`, only ever append new codes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Internal,
+    UnsupportedVersion,
+    ProtocolViolation,
+    MalformedFrame,
+    Unauthorized,
+    InvalidCredentials,
+    UsernameTaken,
+    InvalidInput,
+    NotInRoom,
+    RateLimited,
+    MessageTooLong,
+    RoomNotFound,
+    MessageNotFound,
+    Forbidden,
+    UserNotFound,
+    RoomExists,
+    InvalidInvite,
+    Muted,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            ErrorCode::Internal => "Internal",
+            ErrorCode::UnsupportedVersion => "Unsupported version",
+            ErrorCode::ProtocolViolation => "Protocol violation",
+            ErrorCode::MalformedFrame => "Malformed frame",
+            ErrorCode::Unauthorized => "Unauthorized",
+            ErrorCode::InvalidCredentials => "Invalid credentials",
+            ErrorCode::UsernameTaken => "Username taken",
+            ErrorCode::InvalidInput => "Invalid input",
+            ErrorCode::NotInRoom => "Not in room",
+            ErrorCode::RateLimited => "Rate limited",
+            ErrorCode::MessageTooLong => "Message too long",
+            ErrorCode::RoomNotFound => "Room not found",
+            ErrorCode::MessageNotFound => "Message not found",
+            ErrorCode::Forbidden => "Forbidden",
+            ErrorCode::UserNotFound => "User not found",
+            ErrorCode::RoomExists => "Room exists",
+            ErrorCode::InvalidInvite => "Invalid invite",
+            ErrorCode::Muted => "Muted",
+        };
+        f.write_str(text)
+    }
+}
+
+/// Optional protocol features, negotiated during the handshake.
+///
+/// Sent as a plain bitmask so that peers simply ignore bits they don't know.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    pub const HISTORY: Self = Self(1 << 0);
+    pub const SESSION_RESUME: Self = Self(1 << 1);
+    pub const EDITS: Self = Self(1 << 2);
+    pub const REACTIONS: Self = Self(1 << 3);
+    pub const PRESENCE: Self = Self(1 << 4);
+    pub const TYPING: Self = Self(1 << 5);
+    pub const DIRECT: Self = Self(1 << 6);
+    pub const ROOMS: Self = Self(1 << 7);
+    pub const DIRECTORY: Self = Self(1 << 8);
+    pub const INVITES: Self = Self(1 << 9);
+    pub const MODERATION: Self = Self(1 << 10);
+    pub const SEARCH: Self = Self(1 << 11);
+    pub const SHUTDOWN_NOTICE: Self = Self(1 << 12);
+
+    /// Features every client had before the handshake existed.
+    pub const LEGACY: Self = Self::HISTORY;
+
+    /// Everything this build knows how to speak.
+    pub fn supported() -> Self {
+        Self::HISTORY
+            | Self::SESSION_RESUME
+            | Self::EDITS
+            | Self::REACTIONS
+            | Self::PRESENCE
+            | Self::TYPING
+            | Self::DIRECT
+            | Self::ROOMS
+            | Self::DIRECTORY
+            | Self::INVITES
+            | Self::MODERATION
+            | Self::SEARCH
+            | Self::SHUTDOWN_NOTICE
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// The revision a connection negotiated, shared by the tasks reading and
+/// writing it. `PROTOCOL_VERSION` until the `Welcome` says otherwise.
+#[derive(Debug, Clone)]
+pub struct WireVersion(Arc<AtomicU16>);
+
+impl WireVersion {
+    pub fn new() -> Self {
+        Self(Arc::new(AtomicU16::new(PROTOCOL_VERSION)))
+    }
+
+    pub fn get(&self) -> u16 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Follows the `Welcome` of the server, ignoring everything else.
+    pub fn observe(&self, msg: &TalkProtocol) {
+        if let TalkProtocol::Welcome { version, .. } = msg {
+            self.0.store(*version, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for WireVersion {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `unixtime` is in milliseconds and, like `seq`, assigned by the server when
+/// the message is stored. Whatever a client puts there is ignored.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TalkMessage {
+    /// Stable id of the stored message, used as cursor when fetching history.
+    pub id: u64,
     pub uuid: Uuid,
     pub username: String,
     pub text: String,
     pub room_id: i32,
-    pub unixtime: u64
+    pub unixtime: u64,
+    /// Position within the room, strictly increasing in storage order.
+    pub seq: u64,
+    /// When the text was last changed, in milliseconds.
+    pub edited_at: Option<u64>,
+    /// Set once the message was deleted, its text is gone by then.
+    pub deleted_at: Option<u64>,
+    /// Id of the message this one answers, in the same room.
+    pub reply_to: Option<u64>,
+    /// In the order they were first used.
+    pub reactions: Vec<Reaction>,
+}
+
+/// A private message between two accounts. Names are the display names at
+/// the time it was sent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DirectMessage {
+    pub id: u64,
+    pub sender: Uuid,
+    pub sender_name: String,
+    pub recipient: Uuid,
+    pub recipient_name: String,
+    pub text: String,
+    /// Milliseconds, assigned by the server.
+    pub unixtime: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    /// Listed and joinable by everybody.
+    Public,
+    /// Only joinable by those who know it.
+    Private,
+}
+
+impl Visibility {
+    pub fn to_i16(self) -> i16 {
+        match self {
+            Visibility::Public => 0,
+            Visibility::Private => 1,
+        }
+    }
+
+    pub fn from_i16(value: i16) -> Option<Self> {
+        match value {
+            0 => Some(Visibility::Public),
+            1 => Some(Visibility::Private),
+            _ => None,
+        }
+    }
+}
+
+/// A named room. Rooms without a name only exist as their id.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Room {
+    pub id: i32,
+    pub name: String,
+    pub topic: String,
+    pub created_by: Option<Uuid>,
+    /// Milliseconds.
+    pub created_at: u64,
+    pub visibility: Visibility,
+    /// Seconds members have to wait between messages, 0 when off.
+    pub slow_mode_secs: u32,
+    pub retention: Retention,
+}
+
+/// How long a room keeps its history. The server may have limits of its own,
+/// the stricter one wins.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Retention {
+    /// Messages older than this are deleted.
+    pub max_age_secs: Option<u64>,
+    /// Only this many of the newest entries are kept, joins and leaves
+    /// included.
+    pub max_messages: Option<u64>,
+}
+
+/// A public room as listed in the room directory.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RoomSummary {
+    pub room: Room,
+    /// How many people are in the room right now.
+    pub members: u32,
+    /// Milliseconds, time of the newest message or join/leave in the room.
+    pub last_activity: Option<u64>,
+}
+
+/// Who a direct message goes to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Recipient {
+    /// The name the account logs in with or is shown as, no two accounts
+    /// share one.
+    Username(String),
+    Uuid(Uuid),
+}
+
+/// Somebody currently connected to a room.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Member {
+    pub uuid: Uuid,
+    pub username: String,
+}
+
+/// What somebody may do in a named room, each role includes the ones before.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RoomRole {
+    Member,
+    Moderator,
+    Owner,
+}
+
+impl RoomRole {
+    pub fn to_i16(self) -> i16 {
+        match self {
+            RoomRole::Member => 0,
+            RoomRole::Moderator => 1,
+            RoomRole::Owner => 2,
+        }
+    }
+
+    pub fn from_i16(value: i16) -> Option<Self> {
+        match value {
+            0 => Some(RoomRole::Member),
+            1 => Some(RoomRole::Moderator),
+            2 => Some(RoomRole::Owner),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModerationAction {
+    Kicked,
+    Banned,
+    Muted,
+    /// Lifts bans and mutes alike.
+    Unbanned,
+    RoleChanged(RoomRole),
+}
+
+impl ModerationAction {
+    /// Whether the target has to leave the room.
+    pub fn evicts(self) -> bool {
+        matches!(self, ModerationAction::Kicked | ModerationAction::Banned)
+    }
+}
+
+/// A chat message matching a `Search`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub message: TalkMessage,
+    /// Excerpt of the text around the matches, which are marked with
+    /// `SEARCH_MATCH_START` and `SEARCH_MATCH_END`.
+    pub snippet: String,
+    /// Higher is better, only meaningful within one result.
+    pub rank: f32,
 }
 
+/// Everybody who reacted to a message with `emoji`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Reaction {
+    pub emoji: String,
+    pub users: Vec<Uuid>,
+}
+
+/// bincode encodes variants by position, so new variants must only ever be
+/// appended at the end of this enum.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum TalkProtocol {
     // Client -> Server Commands
     JoinRoom { room_id: i32, uuid: Uuid, username: String, unixtime: u64},
     LeaveRoom { room_id: i32, uuid: Uuid, username: String, unixtime: u64},
     ChangeName {uuid: Uuid, username: String, old_username: String, unixtime: u64},
-    Fetch { room_id: i32, limit: i64, fetch_before: u64},
+    /// Pages through a room by message id. Without cursors the latest
+    /// messages are returned, `before_id` pages backwards and `after_id`
+    /// forwards. Both cursors are exclusive.
+    Fetch { room_id: i32, limit: i64, before_id: Option<u64>, after_id: Option<u64> },
     LocalError { message: String },
 
     // Server -> Client Events
-    UserJoined { uuid: Uuid, username: String, room_id: i32, unixtime: u64 },
-    UserLeft { uuid: Uuid, username: String, room_id: i32, unixtime: u64  },
+    UserJoined { id: u64, uuid: Uuid, username: String, room_id: i32, unixtime: u64, seq: u64 },
+    UserLeft { id: u64, uuid: Uuid, username: String, room_id: i32, unixtime: u64, seq: u64 },
     UsernameChanged {uuid: Uuid, username: String, old_username: String, unixtime: u64},
-    History { text: Vec<TalkProtocol> },
-    Error { code: String, message: String },
+    /// Answer to a `Fetch`, oldest message first. The cursors of the request
+    /// are echoed so the client knows where the page belongs, `has_more` says
+    /// whether there is anything beyond it in the fetched direction.
+    History { text: Vec<TalkProtocol>, has_more: bool, before_id: Option<u64>, after_id: Option<u64> },
+    /// `retry_after_ms` says when a `RateLimited` request may be sent again.
+    Error { code: ErrorCode, message: String, retry_after_ms: Option<u64> },
 
 
     // Server <-> Client
     PostMessage { message: TalkMessage },
+
+    // Handshake: Client -> Server
+    Hello { version: u16, capabilities: Capabilities },
+
+    // Handshake: Server -> Client
+    Welcome { version: u16, capabilities: Capabilities },
+
+    // Accounts: Client -> Server
+    Register { username: String, password: String },
+    Login { username: String, password: String },
+
+    // Accounts: Server -> Client
+    Authenticated { uuid: Uuid, username: String },
+
+    // Sessions: Client -> Server
+    Resume { token: String },
+    Logout,
+
+    // Sessions: Server -> Client
+    SessionToken { token: String, expires_at: u64 },
+    SessionExpired,
+
+    // Edits: Client -> Server
+    EditMessage { message_id: u64, text: String },
+    DeleteMessage { message_id: u64 },
+
+    // Edits: Server -> Client
+    MessageEdited { message_id: u64, room_id: i32, text: String, edited_at: u64 },
+    MessageDeleted { message_id: u64, room_id: i32, deleted_at: u64 },
+
+    // Reactions: Client -> Server
+    AddReaction { message_id: u64, emoji: String },
+    RemoveReaction { message_id: u64, emoji: String },
+
+    // Reactions: Server -> Client
+    /// All reactions of a message after one was added or removed.
+    ReactionsChanged { message_id: u64, room_id: i32, reactions: Vec<Reaction> },
+
+    // Presence: Client -> Server
+    ListMembers { room_id: i32 },
+
+    // Presence: Server -> Client
+    /// Everybody currently in the room, sorted by name.
+    Members { room_id: i32, members: Vec<Member> },
+
+    // Server <-> Client
+    /// Sent repeatedly while somebody is typing, never stored. The server
+    /// fills in who is typing.
+    Typing { room_id: i32, uuid: Uuid, username: String },
+
+    // Direct messages: Client -> Server
+    SendDirect { recipient: Recipient, text: String },
+    /// Pages backwards through all conversations of the account.
+    FetchDirect { before_id: Option<u64>, limit: i64 },
+
+    // Direct messages: Server -> Client
+    /// Delivered to both the sender and the recipient.
+    DirectMessage { message: DirectMessage },
+    DirectHistory { messages: Vec<DirectMessage>, has_more: bool },
+
+    // Rooms: Client -> Server
+    CreateRoom { name: String, topic: String, visibility: Visibility },
+    SetTopic { room_id: i32, topic: String },
+    ResolveRoom { name: String },
+
+    // Rooms: Server -> Client
+    /// Answer to the requests above, also sent when joining a named room and
+    /// to everybody in it when its topic changes.
+    RoomInfo { room: Room },
+
+    // Room directory: Client -> Server
+    ListRooms,
+
+    // Room directory: Server -> Client
+    /// Public rooms, the busiest first.
+    RoomList { rooms: Vec<RoomSummary> },
+
+    // Invites: Client -> Server
+    CreateInvite { room_id: i32 },
+    RedeemInvite { code: String },
+
+    // Invites: Server -> Client
+    /// Milliseconds, the code can't be redeemed after `expires_at`.
+    Invite { code: String, room: Room, expires_at: u64 },
+    /// The account may join `room` from now on.
+    InviteRedeemed { room: Room },
+
+    // Moderation: Client -> Server
+    /// `duration_secs` of `None` bans or mutes for good.
+    Kick { room_id: i32, target: Recipient },
+    Ban { room_id: i32, target: Recipient, duration_secs: Option<u64> },
+    Mute { room_id: i32, target: Recipient, duration_secs: Option<u64> },
+    Unban { room_id: i32, target: Recipient },
+    SetRole { room_id: i32, target: Recipient, role: RoomRole },
+    /// Answered with `RoomInfo` to everybody in the room, 0 turns it off.
+    SetSlowMode { room_id: i32, seconds: u32 },
+
+    // Moderation: Server -> Client
+    /// Sent to everybody in the room. `until` is in milliseconds.
+    Moderation {
+        room_id: i32,
+        action: ModerationAction,
+        target: Member,
+        by: Member,
+        until: Option<u64>,
+        unixtime: u64,
+    },
+
+    // Search: Client -> Server
+    /// Finds chat messages of a room containing all words of `query`. Quoted
+    /// phrases, `or` and `-word` work like in web search engines.
+    Search { room_id: i32, query: String, limit: i64 },
+
+    // Search: Server -> Client
+    /// Best match first, the query is echoed.
+    SearchResults { room_id: i32, query: String, hits: Vec<SearchHit> },
+
+    // Retention: Client -> Server
+    /// Only for owners of the room, answered with `RoomInfo` to everybody in
+    /// it.
+    SetRetention { room_id: i32, retention: Retention },
+
+    // Shutdown: Server -> Client
+    /// The node is going away and closes the connection right after. Wait
+    /// `reconnect_after` milliseconds before connecting again, by then the
+    /// load balancer sends new connections to another node.
+    ServerShuttingDown { reconnect_after: u64 },
 }
 
 impl TalkProtocol {
@@ -39,6 +534,53 @@ impl TalkProtocol {
     pub fn deserialize(bytes: &[u8]) -> Result<Self, bincode::Error> {
         bincode::deserialize(bytes)
     }
+
+    /// Encodes the message for a peer that negotiated `version`.
+    pub fn serialize_for(&self, version: u16) -> Result<Vec<u8>, bincode::Error> {
+        let mut bytes = Vec::new();
+        legacy::encode(self, version, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Decodes a message from a peer that negotiated `version`. Length
+    /// prefixes can't claim more than `bytes` holds, so a bogus one doesn't
+    /// make us allocate gigabytes.
+    pub fn deserialize_for(bytes: &[u8], version: u16) -> Result<Self, bincode::Error> {
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(bytes.len() as u64);
+        legacy::decode(&mut &*bytes, version, options)
+    }
+
+    /// Capability a peer must have negotiated to be sent this message.
+    pub fn required_capability(&self) -> Capabilities {
+        match self {
+            TalkProtocol::History { .. } => Capabilities::HISTORY,
+            TalkProtocol::SessionToken { .. } | TalkProtocol::SessionExpired => {
+                Capabilities::SESSION_RESUME
+            }
+            TalkProtocol::MessageEdited { .. } | TalkProtocol::MessageDeleted { .. } => {
+                Capabilities::EDITS
+            }
+            TalkProtocol::ReactionsChanged { .. } => Capabilities::REACTIONS,
+            TalkProtocol::Members { .. } => Capabilities::PRESENCE,
+            TalkProtocol::Typing { .. } => Capabilities::TYPING,
+            TalkProtocol::DirectMessage { .. } | TalkProtocol::DirectHistory { .. } => {
+                Capabilities::DIRECT
+            }
+            TalkProtocol::RoomInfo { .. } => Capabilities::ROOMS,
+            TalkProtocol::RoomList { .. } => Capabilities::DIRECTORY,
+            TalkProtocol::Invite { .. } | TalkProtocol::InviteRedeemed { .. } => {
+                Capabilities::INVITES
+            }
+            TalkProtocol::Moderation { .. } => Capabilities::MODERATION,
+            TalkProtocol::SearchResults { .. } => Capabilities::SEARCH,
+            TalkProtocol::ServerShuttingDown { .. } => Capabilities::SHUTDOWN_NOTICE,
+            _ => Capabilities::NONE,
+        }
+    }
+
     pub fn to_i16(&self) -> Option<i16> {
         match self {
             TalkProtocol::UserJoined {..} => Some(0),
@@ -50,16 +592,38 @@ impl TalkProtocol {
         }
     }
 
-    pub fn from_i16(value: i16, room_id: i32, uuid: Uuid, username: String, unixtime: u64, message: String) -> Option<Self> {
+    /// Rebuilds the event a stored message was persisted for.
+    pub fn from_i16(value: i16, message: TalkMessage) -> Option<Self> {
+        if value == 4 {
+            return Some(TalkProtocol::PostMessage { message });
+        }
+        let TalkMessage { id, uuid, username, text, room_id, unixtime, seq, .. } = message;
         Some(match value {
-            0 => TalkProtocol::UserJoined { uuid, username, room_id, unixtime },
-            1 => TalkProtocol::UserLeft { uuid, username, room_id, unixtime },
-            2 => TalkProtocol::UsernameChanged { uuid, username, old_username: message, unixtime },
-            3 => TalkProtocol::Error { code: message.clone(), message },
-            4 => TalkProtocol::PostMessage { message: TalkMessage { uuid, username, text: message, room_id, unixtime } },
+            0 => TalkProtocol::UserJoined { id, uuid, username, room_id, unixtime, seq },
+            1 => TalkProtocol::UserLeft { id, uuid, username, room_id, unixtime, seq },
+            2 => TalkProtocol::UsernameChanged { uuid, username, old_username: text, unixtime },
+            3 => TalkProtocol::Error { code: ErrorCode::Internal, message: text, retry_after_ms: None },
             _ => return None,
         })
     }
+
+    /// Room sequence number of persisted events.
+    pub fn seq(&self) -> Option<u64> {
+        match self {
+            TalkProtocol::PostMessage { message } => Some(message.seq),
+            TalkProtocol::UserJoined { seq, .. } | TalkProtocol::UserLeft { seq, .. } => Some(*seq),
+            _ => None,
+        }
+    }
+
+    /// Id of persisted events.
+    pub fn id(&self) -> Option<u64> {
+        match self {
+            TalkProtocol::PostMessage { message } => Some(message.id),
+            TalkProtocol::UserJoined { id, .. } | TalkProtocol::UserLeft { id, .. } => Some(*id),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -88,9 +652,10 @@ pub mod native {
     pub async fn sender_task(
         mut rx: UnboundedReceiver<TalkProtocol>,
         mut write: SplitSink<WebStream, Message>,
+        version: WireVersion,
     ) {
         while let Some(msg) = rx.next().await {
-            match bincode::serialize(&msg) {
+            match msg.serialize_for(version.get()) {
                 Ok(bin) => {
                     if let Err(e) = write.send(Message::Binary(bin)).await {
                         eprintln!("WebSocket send error: {:?}", e);
@@ -108,12 +673,14 @@ pub mod native {
 
     pub async fn receiver_task(
         mut read: SplitStream<WebStream>,
+        version: WireVersion,
         mut on_message: impl FnMut(TalkProtocol) + Send + 'static,
     ) -> Result<(), WsError> {
         while let Some(msg) = read.next().await {
             match msg {
                 Ok(Message::Binary(bin)) => {
-                    if let Ok(parsed) = TalkProtocol::deserialize(&bin) {
+                    if let Ok(parsed) = TalkProtocol::deserialize_for(&bin, version.get()) {
+                        version.observe(&parsed);
                         on_message(parsed);
                     }
                 }
@@ -132,7 +699,7 @@ pub mod native {
 //----------------------------------------------------------------------------------------------------WASM----------------------------------------------------------------------------------------------------
 
 pub mod wasm {
-    use super::TalkProtocol;
+    use super::{TalkProtocol, WireVersion};
     use futures_channel::mpsc::UnboundedReceiver;
     use futures_util::SinkExt;
     use futures_util::StreamExt;
@@ -152,9 +719,10 @@ pub mod wasm {
     pub async fn sender_task(
         mut rx: UnboundedReceiver<TalkProtocol>,
         mut write: SplitSink<WebSocket, Message>,
+        version: WireVersion,
     ) {
         while let Some(msg) = rx.next().await {
-            match bincode::serialize(&msg) {
+            match msg.serialize_for(version.get()) {
                 Ok(bin) => {
                     if let Err(_e) = write.send(Message::Bytes(bin)).await {
                         break;
@@ -169,13 +737,15 @@ pub mod wasm {
 
     pub async fn receiver_task(
         mut read: SplitStream<WebSocket>,
+        version: WireVersion,
         messages: UseStateHandle<Vec<TalkProtocol>>,
     ) {
         let messages = messages.clone();
         while let Some(msg) = read.next().await {
             match msg {
                 Ok(Message::Bytes(bin)) => {
-                    if let Ok(parsed) = TalkProtocol::deserialize(&bin) {
+                    if let Ok(parsed) = TalkProtocol::deserialize_for(&bin, version.get()) {
+                        version.observe(&parsed);
                         let mut current = (*messages).clone();
                         current.push(parsed.clone());
                         messages.set(current);
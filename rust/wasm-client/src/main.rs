@@ -1,7 +1,7 @@
 use futures_channel::mpsc::{UnboundedSender, unbounded};
 use futures_util::StreamExt;
 use gloo_net::websocket::futures::WebSocket;
-use shared::TalkProtocol;
+use shared::{Capabilities, ErrorCode, PROTOCOL_VERSION, TalkProtocol, WireVersion};
 use shared::wasm::*;
 use wasm_bindgen_futures::spawn_local;
 use yew::prelude::*;
@@ -9,8 +9,8 @@ use yew::prelude::*;
 #[function_component(App)]
 pub fn app() -> Html {
     // Component state
-    let username = use_state(|| String::new());
-    let message = use_state(|| String::new());
+    let username = use_state(String::new);
+    let message = use_state(String::new);
     let messages = use_state(Vec::<TalkProtocol>::new);
     let tx = use_state(|| None::<UnboundedSender<TalkProtocol>>);
 
@@ -31,17 +31,19 @@ pub fn app() -> Html {
 
                 let (write, read) = ws.split();
                 let (tx_ws, rx) = unbounded::<TalkProtocol>();
+                let version = WireVersion::new();
 
                 // Spawn sender task
                 spawn_local({
+                    let version = version.clone();
                     async move {
-                        sender_task(rx, write).await;
+                        sender_task(rx, write, version).await;
                     }
                 });
 
                 let messages = messages.clone();
                 spawn_local(async move {
-                    receiver_task(read, messages).await;
+                    receiver_task(read, version, messages).await;
                 });
                 // Spawn receiver task
                 // spawn_local({
@@ -68,6 +70,17 @@ pub fn app() -> Html {
                 //     }
                 // });
 
+                // Without a `Hello` the server takes us for a revision 0
+                // client and turns us away. Nothing optional is rendered
+                // yet, so no capabilities are asked for.
+                let hello = TalkProtocol::Hello {
+                    version: PROTOCOL_VERSION,
+                    capabilities: Capabilities::NONE,
+                };
+                if let Err(e) = tx_ws.unbounded_send(hello) {
+                    log::error!("Failed to send hello: {:?}", e);
+                }
+
                 // Store the sender in component state
                 tx.set(Some(tx_ws));
 
@@ -103,10 +116,9 @@ pub fn app() -> Html {
 
     let on_send = {
         let tx = tx.clone();
-        let username = username.clone();
         let message = message.clone();
         Callback::from(move |_| {
-            if let Some(tx) = &*tx {
+            if let Some(_tx) = &*tx {
                 // let msg = TalkProtocol {
                 //     username: (*username).clone(),
                 //     message: (*message).clone(),
@@ -147,10 +159,17 @@ pub fn app() -> Html {
                 <h2>{ "Messages" }</h2>
                 <ul>
                     {(*messages).iter().map(|msg| {
-                    html! {
-                    // <li key={msg.unixtime.to_string()}>
-                    //     <strong>{ &msg.username }</strong> { &msg.message }
-                    // </li>
+                    match msg {
+                        TalkProtocol::Error { code, message, .. } => html! {
+                        <li class={error_class(code)}>
+                            <strong>{ code.to_string() }</strong> { message }
+                        </li>
+                        },
+                        _ => html! {
+                        // <li key={msg.unixtime.to_string()}>
+                        //     <strong>{ &msg.username }</strong> { &msg.message }
+                        // </li>
+                        },
                     }
                     }).collect::<Html>()}
                 </ul>
@@ -160,6 +179,16 @@ pub fn app() -> Html {
     }
 }
 
+/// Lets the stylesheet tell errors the user can fix apart from server faults.
+fn error_class(code: &ErrorCode) -> &'static str {
+    match code {
+        ErrorCode::Internal => "error error-internal",
+        ErrorCode::Unauthorized | ErrorCode::InvalidCredentials => "error error-auth",
+        ErrorCode::RateLimited => "error error-rate-limited",
+        _ => "error",
+    }
+}
+
 pub fn main() {
     wasm_logger::init(wasm_logger::Config::default());
     yew::Renderer::<App>::new().render();
@@ -0,0 +1,19 @@
+-- The schema as it was baked into the Postgres image. Tables are only
+-- created if missing, so those databases are taken over as they are and
+-- brought up to date by the migrations after this one.
+
+CREATE TABLE IF NOT EXISTS users (
+    id SERIAL PRIMARY KEY,
+    room_id INTEGER,
+    uuid UUID
+);
+
+CREATE TABLE IF NOT EXISTS messages (
+    id SERIAL PRIMARY KEY,
+    time BIGINT,
+    message TEXT,
+    username TEXT,
+    room_id INTEGER,
+    uuid UUID,
+    protocol_type SMALLINT
+);
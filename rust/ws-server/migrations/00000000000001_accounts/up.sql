@@ -0,0 +1,21 @@
+-- Later images of the database baked parts of the following migrations in
+-- already, hence the IF NOT EXISTS here and after.
+
+CREATE TABLE IF NOT EXISTS accounts (
+    uuid UUID PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    display_name TEXT NOT NULL,
+    password_hash TEXT NOT NULL,
+    created_at BIGINT NOT NULL
+);
+
+-- Moderators may edit and delete everybody's messages.
+ALTER TABLE accounts ADD COLUMN IF NOT EXISTS is_moderator BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE IF NOT EXISTS sessions (
+    id UUID PRIMARY KEY,
+    account_uuid UUID NOT NULL REFERENCES accounts (uuid) ON DELETE CASCADE,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    revoked BOOLEAN NOT NULL DEFAULT FALSE
+);
@@ -0,0 +1,10 @@
+DROP TABLE IF EXISTS reactions;
+DROP TABLE IF EXISTS message_edits;
+DROP TABLE IF EXISTS room_sequences;
+DROP INDEX IF EXISTS messages_room_seq;
+
+ALTER TABLE messages
+    DROP COLUMN IF EXISTS reply_to,
+    DROP COLUMN IF EXISTS deleted_at,
+    DROP COLUMN IF EXISTS edited_at,
+    DROP COLUMN IF EXISTS seq;
@@ -0,0 +1,64 @@
+-- Rows the old server left incomplete can't be shown and would keep the
+-- columns below from becoming NOT NULL.
+DELETE FROM messages
+    WHERE time IS NULL OR message IS NULL OR username IS NULL
+        OR room_id IS NULL OR uuid IS NULL OR protocol_type IS NULL;
+
+ALTER TABLE messages ALTER COLUMN id TYPE BIGINT;
+ALTER SEQUENCE messages_id_seq AS BIGINT;
+
+ALTER TABLE messages
+    ADD COLUMN IF NOT EXISTS seq BIGINT,
+    ADD COLUMN IF NOT EXISTS edited_at BIGINT,
+    -- Deleted messages are kept as tombstones with an empty text.
+    ADD COLUMN IF NOT EXISTS deleted_at BIGINT,
+    ADD COLUMN IF NOT EXISTS reply_to BIGINT REFERENCES messages (id) ON DELETE SET NULL;
+
+-- Clients used to stamp messages in seconds, the server now does so in
+-- milliseconds.
+UPDATE messages SET time = time * 1000 WHERE seq IS NULL AND time < 100000000000;
+
+-- Existing messages are numbered in the order they were stored.
+UPDATE messages SET seq = numbered.seq
+    FROM (
+        SELECT id, row_number() OVER (PARTITION BY room_id ORDER BY id) AS seq
+        FROM messages
+    ) numbered
+    WHERE messages.id = numbered.id AND messages.seq IS NULL;
+ALTER TABLE messages ALTER COLUMN seq SET NOT NULL;
+
+-- Unless a later migration already replaced it with a unique constraint.
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT FROM pg_constraint WHERE conname = 'messages_room_seq_key') THEN
+        CREATE INDEX IF NOT EXISTS messages_room_seq ON messages (room_id, seq);
+    END IF;
+END
+$$;
+
+-- Last sequence number handed out per room, see `queries::insert_message`.
+CREATE TABLE IF NOT EXISTS room_sequences (
+    room_id INTEGER PRIMARY KEY,
+    last_seq BIGINT NOT NULL
+);
+
+INSERT INTO room_sequences (room_id, last_seq)
+    SELECT room_id, max(seq) FROM messages GROUP BY room_id
+    ON CONFLICT (room_id) DO NOTHING;
+
+-- Previous versions of edited messages, newest last.
+CREATE TABLE IF NOT EXISTS message_edits (
+    id BIGSERIAL PRIMARY KEY,
+    message_id BIGINT NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    previous_text TEXT NOT NULL,
+    edited_at BIGINT NOT NULL,
+    edited_by UUID NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS reactions (
+    message_id BIGINT NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    account_uuid UUID NOT NULL REFERENCES accounts (uuid) ON DELETE CASCADE,
+    emoji TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    PRIMARY KEY (message_id, account_uuid, emoji)
+);
@@ -0,0 +1,13 @@
+-- Only readable by the two participants, see `queries::get_direct_history`.
+CREATE TABLE IF NOT EXISTS direct_messages (
+    id BIGSERIAL PRIMARY KEY,
+    sender_uuid UUID NOT NULL REFERENCES accounts (uuid) ON DELETE CASCADE,
+    sender_name TEXT NOT NULL,
+    recipient_uuid UUID NOT NULL REFERENCES accounts (uuid) ON DELETE CASCADE,
+    recipient_name TEXT NOT NULL,
+    message TEXT NOT NULL,
+    time BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS direct_messages_sender ON direct_messages (sender_uuid, id);
+CREATE INDEX IF NOT EXISTS direct_messages_recipient ON direct_messages (recipient_uuid, id);
@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS room_sanctions;
+DROP TABLE IF EXISTS invites;
+DROP TABLE IF EXISTS room_members;
+DROP TABLE IF EXISTS rooms;
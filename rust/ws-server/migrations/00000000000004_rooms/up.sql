@@ -0,0 +1,52 @@
+-- Named rooms. Ids start high so they don't clash with the numbered rooms
+-- that were in use before rooms had names.
+CREATE TABLE IF NOT EXISTS rooms (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    topic TEXT NOT NULL DEFAULT '',
+    created_by UUID REFERENCES accounts (uuid) ON DELETE SET NULL,
+    created_at BIGINT NOT NULL,
+    -- 0 = public, 1 = private
+    visibility SMALLINT NOT NULL DEFAULT 0
+);
+
+-- Seconds between two messages of the same member, 0 = off.
+ALTER TABLE rooms ADD COLUMN IF NOT EXISTS slow_mode_secs INT NOT NULL DEFAULT 0;
+
+-- Only while the sequence is untouched, databases that had rooms before
+-- there were migrations may have handed out ids already.
+SELECT setval('rooms_id_seq', 1000, false) FROM rooms_id_seq WHERE NOT is_called;
+
+-- Who may join a private room besides its creator, and who runs a room.
+CREATE TABLE IF NOT EXISTS room_members (
+    room_id INT NOT NULL REFERENCES rooms (id) ON DELETE CASCADE,
+    account_uuid UUID NOT NULL REFERENCES accounts (uuid) ON DELETE CASCADE,
+    joined_at BIGINT NOT NULL,
+    PRIMARY KEY (room_id, account_uuid)
+);
+
+-- 0 = member, 1 = moderator, 2 = owner
+ALTER TABLE room_members ADD COLUMN IF NOT EXISTS role SMALLINT NOT NULL DEFAULT 0;
+
+-- Codes can be redeemed any number of times until they expire.
+CREATE TABLE IF NOT EXISTS invites (
+    code TEXT PRIMARY KEY,
+    room_id INT NOT NULL REFERENCES rooms (id) ON DELETE CASCADE,
+    created_by UUID NOT NULL REFERENCES accounts (uuid) ON DELETE CASCADE,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+
+-- Bans and mutes. Numbered rooms have no row in `rooms`, so there is no
+-- foreign key on the room.
+CREATE TABLE IF NOT EXISTS room_sanctions (
+    room_id INT NOT NULL,
+    account_uuid UUID NOT NULL REFERENCES accounts (uuid) ON DELETE CASCADE,
+    -- 0 = ban, 1 = mute
+    kind SMALLINT NOT NULL,
+    issued_by UUID REFERENCES accounts (uuid) ON DELETE SET NULL,
+    created_at BIGINT NOT NULL,
+    -- NULL lasts until lifted
+    expires_at BIGINT,
+    PRIMARY KEY (room_id, account_uuid, kind)
+);
@@ -0,0 +1,19 @@
+DROP INDEX messages_room_time;
+
+ALTER TABLE messages DROP CONSTRAINT messages_room_seq_key;
+CREATE INDEX messages_room_seq ON messages (room_id, seq);
+
+ALTER TABLE messages
+    ALTER COLUMN time DROP NOT NULL,
+    ALTER COLUMN message DROP NOT NULL,
+    ALTER COLUMN username DROP NOT NULL,
+    ALTER COLUMN room_id DROP NOT NULL,
+    ALTER COLUMN uuid DROP NOT NULL,
+    ALTER COLUMN protocol_type DROP NOT NULL;
+
+DROP INDEX users_uuid;
+ALTER TABLE users
+    DROP CONSTRAINT users_connection_key,
+    DROP COLUMN connection,
+    ALTER COLUMN room_id DROP NOT NULL,
+    ALTER COLUMN uuid DROP NOT NULL;
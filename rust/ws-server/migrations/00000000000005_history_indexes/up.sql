@@ -0,0 +1,31 @@
+-- A row per connection, the room it is in right now, so when one of two
+-- connections of an account leaves, the other one stays in its room. Older
+-- servers added a row per join, only the newest one of an account is kept.
+DELETE FROM users WHERE room_id IS NULL OR uuid IS NULL;
+DELETE FROM users stale USING users newer
+    WHERE stale.uuid = newer.uuid AND stale.id < newer.id;
+ALTER TABLE users
+    ALTER COLUMN room_id SET NOT NULL,
+    ALTER COLUMN uuid SET NOT NULL,
+    ADD COLUMN connection UUID NOT NULL DEFAULT gen_random_uuid();
+ALTER TABLE users
+    ALTER COLUMN connection DROP DEFAULT,
+    ADD CONSTRAINT users_connection_key UNIQUE (connection);
+CREATE INDEX users_uuid ON users (uuid);
+
+-- A single NULL would make every page of a room's history fail to load.
+ALTER TABLE messages
+    ALTER COLUMN time SET NOT NULL,
+    ALTER COLUMN message SET NOT NULL,
+    ALTER COLUMN username SET NOT NULL,
+    ALTER COLUMN room_id SET NOT NULL,
+    ALTER COLUMN uuid SET NOT NULL,
+    ALTER COLUMN protocol_type SET NOT NULL;
+
+-- History is paged by sequence number, which `queries::insert_message`
+-- hands out once per room.
+DROP INDEX IF EXISTS messages_room_seq;
+ALTER TABLE messages ADD CONSTRAINT messages_room_seq_key UNIQUE (room_id, seq);
+
+-- Last activity in the room directory and pruning by age.
+CREATE INDEX messages_room_time ON messages (room_id, time);
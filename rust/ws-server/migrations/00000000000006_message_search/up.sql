@@ -0,0 +1,6 @@
+-- Full-text search over chat messages, see `queries::search_messages`. The
+-- 'simple' configuration doesn't stem, as rooms mix languages. Queries have
+-- to use the very same expression and condition to hit the index.
+CREATE INDEX messages_search ON messages
+    USING GIN (to_tsvector('simple', message))
+    WHERE protocol_type = 4;
@@ -0,0 +1,3 @@
+ALTER TABLE rooms
+    DROP COLUMN retention_max_age_secs,
+    DROP COLUMN retention_max_messages;
@@ -0,0 +1,5 @@
+-- How long a room keeps its history, NULL keeps it as long as the server
+-- does. See `retention.rs` for the pruning.
+ALTER TABLE rooms
+    ADD COLUMN retention_max_age_secs BIGINT CHECK (retention_max_age_secs > 0),
+    ADD COLUMN retention_max_messages BIGINT CHECK (retention_max_messages > 0);
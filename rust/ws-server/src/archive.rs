@@ -0,0 +1,285 @@
+use crate::database::connection::{database_url, establish_connection};
+use crate::database::models::{Message, NewMessage};
+use crate::database::queries::{get_history, insert_message, set_message_markers};
+use crate::wsserver::stored_message;
+use anyhow::{Context, Result, bail};
+use chrono::{TimeZone, Utc};
+use diesel::{Connection, PgConnection};
+use serde::{Deserialize, Serialize};
+use shared::TalkProtocol;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use uuid::Uuid;
+
+const USAGE: &str = "Usage: ws-server export <room id> [--format jsonl|text] [--output <file>]
+       ws-server import <room id> [--input <file>]
+Archives hold the messages and events of a room, reactions and the earlier
+versions of edited messages are left out.";
+
+/// Rows read from Postgres at a time while exporting.
+const EXPORT_BATCH_SIZE: i64 = 1000;
+
+/// A row of `messages` as stored, one per line of a JSON Lines export. Ids
+/// are those of the exporting database and only used to restore replies.
+/// Reactions and `message_edits` aren't archived, an imported message only
+/// keeps its markers.
+#[derive(Serialize, Deserialize, Debug)]
+struct ArchivedMessage {
+    id: i64,
+    seq: i64,
+    /// Milliseconds.
+    time: i64,
+    uuid: Uuid,
+    username: String,
+    /// See `TalkProtocol::to_i16`.
+    protocol_type: i16,
+    message: String,
+    edited_at: Option<i64>,
+    deleted_at: Option<i64>,
+    reply_to: Option<i64>,
+}
+
+impl From<&Message> for ArchivedMessage {
+    fn from(message: &Message) -> Self {
+        Self {
+            id: message.id,
+            seq: message.seq,
+            time: message.time,
+            uuid: message.uuid,
+            username: message.username.clone(),
+            protocol_type: message.protocol_type,
+            message: message.message.clone(),
+            edited_at: message.edited_at,
+            deleted_at: message.deleted_at,
+            reply_to: message.reply_to,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    /// Everything needed to import the room again.
+    JsonLines,
+    /// For reading only, can't be imported.
+    Text,
+}
+
+/// Runs `ws-server export` or `ws-server import` with the arguments after
+/// the subcommand. Files default to stdout and stdin. Only messages are
+/// carried over, see `USAGE`.
+pub fn run(command: &str, args: &[String]) -> Result<()> {
+    let mut args = args.iter();
+    let room_id: i32 = args
+        .next()
+        .and_then(|room_id| room_id.parse().ok())
+        .context(USAGE)?;
+    let mut format = Format::JsonLines;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match (command, arg.as_str()) {
+            ("export", "--format") => {
+                format = match args.next().map(String::as_str) {
+                    Some("jsonl") => Format::JsonLines,
+                    Some("text") => Format::Text,
+                    _ => bail!(USAGE),
+                }
+            }
+            ("export", "--output") | ("import", "--input") => {
+                path = Some(args.next().context(USAGE)?.clone());
+            }
+            _ => bail!(USAGE),
+        }
+    }
+
+    let mut conn =
+        establish_connection(&database_url()).context("Could not connect to Postgres")?;
+    if command == "export" {
+        let output: Box<dyn Write> = match path {
+            Some(path) => Box::new(File::create(&path).with_context(|| path)?),
+            None => Box::new(io::stdout().lock()),
+        };
+        let exported = export(&mut conn, room_id, format, &mut BufWriter::new(output))?;
+        eprintln!("Exported {} messages of room {}", exported, room_id);
+    } else {
+        let input: Box<dyn BufRead> = match path {
+            Some(path) => Box::new(BufReader::new(File::open(&path).with_context(|| path)?)),
+            None => Box::new(io::stdin().lock()),
+        };
+        let imported = import(&mut conn, room_id, input)?;
+        eprintln!("Imported {} messages into room {}", imported, room_id);
+    }
+    Ok(())
+}
+
+/// Writes the whole history of a room, oldest first, as of a single snapshot.
+fn export(
+    conn: &mut PgConnection,
+    room_id: i32,
+    format: Format,
+    output: &mut impl Write,
+) -> Result<usize> {
+    conn.build_transaction()
+        .read_only()
+        .repeatable_read()
+        .run(|conn| {
+            write_pages(
+                |after_seq| get_history(conn, &room_id, &EXPORT_BATCH_SIZE, None, Some(after_seq)),
+                format,
+                output,
+            )
+        })
+}
+
+/// Walks the history forwards from the first sequence number, `next_page`
+/// returns the oldest messages after the one it is given.
+fn write_pages(
+    mut next_page: impl FnMut(i64) -> diesel::QueryResult<Vec<Message>>,
+    format: Format,
+    output: &mut impl Write,
+) -> Result<usize> {
+    let mut exported = 0;
+    let mut after_seq = 0;
+    loop {
+        let batch = next_page(after_seq)?;
+        for message in &batch {
+            match format {
+                Format::JsonLines => {
+                    serde_json::to_writer(&mut *output, &ArchivedMessage::from(message))?;
+                    writeln!(output)?;
+                }
+                Format::Text => writeln!(output, "{}", transcript_line(message))?,
+            }
+        }
+        exported += batch.len();
+        match batch.last() {
+            Some(last) if batch.len() as i64 == EXPORT_BATCH_SIZE => after_seq = last.seq,
+            _ => break,
+        }
+    }
+    output.flush()?;
+    Ok(exported)
+}
+
+fn transcript_line(message: &Message) -> String {
+    let time = Utc
+        .timestamp_millis_opt(message.time)
+        .single()
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| message.time.to_string());
+    let event = TalkProtocol::from_i16(message.protocol_type, stored_message(message.clone()));
+    let text = match event {
+        Some(TalkProtocol::PostMessage { message }) if message.deleted_at.is_some() => {
+            format!("<{}> [message deleted]", message.username)
+        }
+        Some(TalkProtocol::PostMessage { message }) => {
+            let edited = if message.edited_at.is_some() {
+                " (edited)"
+            } else {
+                ""
+            };
+            format!("<{}> {}{}", message.username, message.text, edited)
+        }
+        Some(TalkProtocol::UserJoined { username, .. }) => format!("* {} joined", username),
+        Some(TalkProtocol::UserLeft { username, .. }) => format!("* {} left", username),
+        Some(TalkProtocol::UsernameChanged {
+            username,
+            old_username,
+            ..
+        }) => format!("* {} is now known as {}", old_username, username),
+        _ => format!("! {}", message.message),
+    };
+    format!("[{}] {}", time, text)
+}
+
+/// Appends an export to the history of `room_id`, keeping authors, times
+/// and event types. Either every line is imported or none is.
+fn import(conn: &mut PgConnection, room_id: i32, input: impl BufRead) -> Result<usize> {
+    conn.transaction(|conn| {
+        // Ids of the export mapped to the ones here, for replies
+        let mut ids: HashMap<i64, i64> = HashMap::new();
+        let mut imported = 0;
+        for (index, line) in input.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let archived: ArchivedMessage = serde_json::from_str(&line)
+                .with_context(|| format!("Line {} is not an exported message", index + 1))?;
+            // Joins, leaves, name changes, errors and posts
+            if !(0..=4).contains(&archived.protocol_type) {
+                bail!(
+                    "Line {} has unknown protocol type {}",
+                    index + 1,
+                    archived.protocol_type
+                );
+            }
+            let stored = insert_message(
+                conn,
+                NewMessage {
+                    time: archived.time,
+                    username: archived.username,
+                    message: archived.message,
+                    room_id,
+                    uuid: archived.uuid,
+                    protocol_type: archived.protocol_type,
+                    reply_to: archived.reply_to.and_then(|id| ids.get(&id).copied()),
+                },
+            )?;
+            if archived.edited_at.is_some() || archived.deleted_at.is_some() {
+                set_message_markers(conn, stored.id, archived.edited_at, archived.deleted_at)?;
+            }
+            ids.insert(archived.id, stored.id);
+            imported += 1;
+        }
+        Ok(imported)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(seq: i64) -> Message {
+        Message {
+            id: seq + 100,
+            time: seq * 1000,
+            message: format!("message {}", seq),
+            username: "alice".to_string(),
+            room_id: 1,
+            uuid: Uuid::nil(),
+            protocol_type: 4,
+            seq,
+            edited_at: None,
+            deleted_at: None,
+            reply_to: None,
+        }
+    }
+
+    #[test]
+    fn exports_every_batch_oldest_first() {
+        let stored: Vec<Message> = (1..=2 * EXPORT_BATCH_SIZE + 1).map(message).collect();
+        let mut output = Vec::new();
+        let exported = write_pages(
+            |after_seq| {
+                Ok(stored
+                    .iter()
+                    .filter(|message| message.seq > after_seq)
+                    .take(EXPORT_BATCH_SIZE as usize)
+                    .cloned()
+                    .collect())
+            },
+            Format::JsonLines,
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(exported, stored.len());
+        let seqs: Vec<i64> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<ArchivedMessage>(line).unwrap().seq)
+            .collect();
+        assert_eq!(seqs, (1..=2 * EXPORT_BATCH_SIZE + 1).collect::<Vec<_>>());
+    }
+}
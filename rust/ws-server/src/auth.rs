@@ -0,0 +1,183 @@
+use crate::database::models::SessionRecord;
+use anyhow::{Result, anyhow};
+use argon2::{
+    Argon2,
+    password_hash::{
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+        rand_core::{OsRng, RngCore},
+    },
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{env, sync::OnceLock};
+use uuid::Uuid;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+const DEFAULT_SESSION_TTL_DAYS: u64 = 30;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Hashes `password` with Argon2id and a fresh random salt, returning the
+/// PHC string that is stored in `accounts.password_hash`.
+///
+/// Hashing is deliberately slow, so it runs on the blocking thread pool.
+pub async fn hash_password(password: String) -> Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| anyhow!("hashing password: {}", e))
+    })
+    .await?
+}
+
+pub async fn verify_password(password: String, password_hash: String) -> Result<bool> {
+    tokio::task::spawn_blocking(move || {
+        let parsed = PasswordHash::new(&password_hash)
+            .map_err(|e| anyhow!("parsing password hash: {}", e))?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok())
+    })
+    .await?
+}
+
+/// Key the session tokens are signed with. All nodes behind the load balancer
+/// need the same `SESSION_SECRET`, otherwise tokens only work on the node that
+/// issued them.
+fn session_secret() -> &'static [u8] {
+    static SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+    SECRET.get_or_init(|| match env::var("SESSION_SECRET") {
+        Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+        _ => {
+            eprintln!("No env SESSION_SECRET was provided, sessions won't survive a restart");
+            let mut secret = vec![0u8; 32];
+            OsRng.fill_bytes(&mut secret);
+            secret
+        }
+    })
+}
+
+pub fn session_ttl_secs() -> i64 {
+    let days = env::var("SESSION_TTL_DAYS")
+        .ok()
+        .and_then(|days| days.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_SESSION_TTL_DAYS);
+    (days * 24 * 60 * 60) as i64
+}
+
+fn signature(session_id: Uuid, account_uuid: Uuid) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(session_secret()).expect("HMAC accepts keys of any length");
+    mac.update(session_id.as_bytes());
+    mac.update(account_uuid.as_bytes());
+    mac
+}
+
+/// Builds the token handed to clients: `<session id>.<account uuid>.<hmac>`.
+pub fn sign_token(session_id: Uuid, account_uuid: Uuid) -> String {
+    let mac = signature(session_id, account_uuid).finalize().into_bytes();
+    format!(
+        "{}.{}.{}",
+        session_id.simple(),
+        account_uuid.simple(),
+        hex::encode(mac)
+    )
+}
+
+/// Checks the signature of a token and returns the session id and account
+/// uuid it was issued for. Whether the session is still valid is up to the
+/// database.
+pub fn verify_token(token: &str) -> Option<(Uuid, Uuid)> {
+    let mut parts = token.trim().split('.');
+    let session_id = Uuid::parse_str(parts.next()?).ok()?;
+    let account_uuid = Uuid::parse_str(parts.next()?).ok()?;
+    let mac = hex::decode(parts.next()?).ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    signature(session_id, account_uuid)
+        .verify_slice(&mac)
+        .ok()
+        .map(|_| (session_id, account_uuid))
+}
+
+/// Whether a token verified for `account_uuid` may resume `record` at `now`.
+pub fn resumable(record: &SessionRecord, account_uuid: Uuid, now: i64) -> bool {
+    record.account_uuid == account_uuid && !record.revoked && record.expires_at > now
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(account_uuid: Uuid, expires_at: i64, revoked: bool) -> SessionRecord {
+        SessionRecord {
+            id: Uuid::new_v4(),
+            account_uuid,
+            created_at: 0,
+            expires_at,
+            revoked,
+        }
+    }
+
+    #[test]
+    fn signed_tokens_verify() {
+        let (session_id, account_uuid) = (Uuid::new_v4(), Uuid::new_v4());
+        let token = sign_token(session_id, account_uuid);
+        assert_eq!(verify_token(&token), Some((session_id, account_uuid)));
+        assert_eq!(
+            verify_token(&format!("{}\n", token)),
+            Some((session_id, account_uuid))
+        );
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let token = sign_token(Uuid::new_v4(), Uuid::new_v4());
+        let parts: Vec<&str> = token.split('.').collect();
+
+        // Someone else's account with our signature
+        let other_account = Uuid::new_v4().simple().to_string();
+        assert_eq!(
+            verify_token(&[parts[0], &other_account, parts[2]].join(".")),
+            None
+        );
+
+        let mut mac = parts[2].to_string();
+        let last = if mac.ends_with('0') { "1" } else { "0" };
+        mac.replace_range(mac.len() - 1.., last);
+        assert_eq!(verify_token(&[parts[0], parts[1], &mac].join(".")), None);
+
+        assert_eq!(verify_token(&format!("{}.00", token)), None);
+        assert_eq!(verify_token(&parts[..2].join(".")), None);
+        assert_eq!(verify_token(""), None);
+    }
+
+    #[test]
+    fn expired_and_revoked_sessions_do_not_resume() {
+        let account_uuid = Uuid::new_v4();
+        let now = 1_000;
+        assert!(resumable(
+            &record(account_uuid, now + 1, false),
+            account_uuid,
+            now
+        ));
+        assert!(!resumable(
+            &record(account_uuid, now, false),
+            account_uuid,
+            now
+        ));
+        assert!(!resumable(
+            &record(account_uuid, now + 1, true),
+            account_uuid,
+            now
+        ));
+        assert!(!resumable(
+            &record(account_uuid, now + 1, false),
+            Uuid::new_v4(),
+            now
+        ));
+    }
+}
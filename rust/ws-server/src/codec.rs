@@ -0,0 +1,126 @@
+use crate::error::RequestError;
+use shared::{ErrorCode, TalkProtocol};
+use tokio_tungstenite::tungstenite::{Message, protocol::WebSocketConfig};
+
+/// Largest message a client may send. Chat messages are tiny, anything close
+/// to this is either a bug or abuse.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Malformed frames tolerated per connection before it gets dropped.
+pub const MAX_REJECTED_FRAMES: u32 = 10;
+
+/// What a single incoming WebSocket message turned out to be.
+pub enum Frame {
+    Protocol(TalkProtocol),
+    /// Ping/Pong, which tungstenite already answers on its own.
+    Control,
+    Close,
+}
+
+pub fn websocket_config() -> WebSocketConfig {
+    WebSocketConfig::default()
+        .max_message_size(Some(MAX_FRAME_SIZE))
+        .max_frame_size(Some(MAX_FRAME_SIZE))
+}
+
+/// Decodes an incoming message in the shapes of `version`, returning the
+/// `Error` to reply with if the client sent something we can't make sense of.
+pub fn decode_frame(msg: Message, version: u16) -> Result<Frame, RequestError> {
+    match msg {
+        Message::Binary(bytes) => TalkProtocol::deserialize_for(&bytes, version)
+            .map(Frame::Protocol)
+            .map_err(|e| malformed(format!("Could not decode message: {}", e))),
+        Message::Text(_) => Err(malformed(
+            "Text frames are not supported, messages must be sent as binary".to_string(),
+        )),
+        Message::Ping(_) | Message::Pong(_) => Ok(Frame::Control),
+        Message::Close(_) => Ok(Frame::Close),
+        Message::Frame(_) => Err(malformed("Unexpected raw frame".to_string())),
+    }
+}
+
+pub fn malformed(message: String) -> RequestError {
+    RequestError::new(ErrorCode::MalformedFrame, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+    use uuid::Uuid;
+
+    fn join_room(username: String) -> TalkProtocol {
+        TalkProtocol::JoinRoom {
+            room_id: 1,
+            uuid: Uuid::nil(),
+            username,
+            unixtime: 0,
+        }
+    }
+
+    fn rejection(msg: Message) -> RequestError {
+        match decode_frame(msg, PROTOCOL_VERSION) {
+            Err(error) => error,
+            Ok(_) => panic!("frame was accepted"),
+        }
+    }
+
+    #[test]
+    fn decodes_what_bincode_serialize_writes() {
+        let bytes = bincode::serialize(&join_room("alice".to_string())).unwrap();
+        match decode_frame(Message::Binary(bytes.into()), PROTOCOL_VERSION) {
+            Ok(Frame::Protocol(TalkProtocol::JoinRoom { username, .. })) => {
+                assert_eq!(username, "alice")
+            }
+            _ => panic!("expected a JoinRoom"),
+        }
+    }
+
+    #[test]
+    fn decodes_the_shapes_of_the_negotiated_version() {
+        let msg = join_room("alice".to_string());
+        let bytes = msg.serialize_for(MIN_PROTOCOL_VERSION).unwrap();
+        assert!(matches!(
+            decode_frame(Message::Binary(bytes.into()), MIN_PROTOCOL_VERSION),
+            Ok(Frame::Protocol(decoded)) if decoded == msg
+        ));
+    }
+
+    #[test]
+    fn websocket_limits_match_the_frame_limit() {
+        // Oversized messages never reach `decode_frame`, tungstenite drops them
+        let config = websocket_config();
+        assert_eq!(config.max_message_size, Some(MAX_FRAME_SIZE));
+        assert_eq!(config.max_frame_size, Some(MAX_FRAME_SIZE));
+    }
+
+    #[test]
+    fn rejects_bogus_length_prefixes() {
+        let username = "b".repeat(37);
+        let mut bytes = bincode::serialize(&join_room(username.clone())).unwrap();
+        let mut prefix = 37u64.to_le_bytes().to_vec();
+        prefix.extend_from_slice(username.as_bytes());
+        let at = bytes
+            .windows(prefix.len())
+            .position(|window| window == prefix)
+            .unwrap();
+        bytes[at..at + 8].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+
+        let error = rejection(Message::Binary(bytes.into()));
+        assert_eq!(error.code, ErrorCode::MalformedFrame);
+    }
+
+    #[test]
+    fn rejects_text_and_passes_control_frames() {
+        let error = rejection(Message::Text("hello".into()));
+        assert_eq!(error.code, ErrorCode::MalformedFrame);
+        assert!(matches!(
+            decode_frame(Message::Ping(Vec::new().into()), PROTOCOL_VERSION),
+            Ok(Frame::Control)
+        ));
+        assert!(matches!(
+            decode_frame(Message::Close(None), PROTOCOL_VERSION),
+            Ok(Frame::Close)
+        ));
+    }
+}
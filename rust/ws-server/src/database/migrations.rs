@@ -0,0 +1,39 @@
+use crate::database::connection::{database_url, establish_connection};
+use anyhow::{Context, Result, anyhow};
+use diesel::sql_types::BigInt;
+use diesel::{RunQueryDsl, sql_query};
+use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
+
+/// `ws-server/migrations`, compiled into the binary. `schema.rs` is still
+/// written by hand and has to follow along.
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+
+/// Key of the advisory lock held while migrating, so servers starting at the
+/// same time don't apply the same migration twice, "tuitalk" in ASCII.
+const MIGRATION_LOCK: i64 = 0x0074_7569_7461_6c6b;
+
+/// Brings the database up to date, returning once every migration is applied.
+pub fn run_pending_migrations() -> Result<()> {
+    let mut conn =
+        establish_connection(&database_url()).context("Could not connect to Postgres")?;
+
+    sql_query("SELECT pg_advisory_lock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK)
+        .execute(&mut conn)?;
+    let applied = conn
+        .run_pending_migrations(MIGRATIONS)
+        .map(|versions| versions.iter().map(ToString::to_string).collect::<Vec<_>>())
+        .map_err(|e| anyhow!("Migration failed: {}", e));
+    sql_query("SELECT pg_advisory_unlock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK)
+        .execute(&mut conn)?;
+
+    let applied = applied?;
+    for version in &applied {
+        println!("Applied migration {}", version);
+    }
+    if applied.is_empty() {
+        println!("Database is up to date");
+    }
+    Ok(())
+}
@@ -2,3 +2,5 @@ pub mod connection;
 pub mod models;
 pub mod schema;
 pub mod queries;
+pub mod pool;
+pub mod migrations;
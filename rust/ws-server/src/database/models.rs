@@ -1,9 +1,19 @@
 use diesel::prelude::Insertable;
 use diesel::Queryable;
+use diesel::QueryableByName;
 use diesel::Selectable;
 use ::uuid::Uuid;   
 use crate::database::schema::users;
 use crate::database::schema::messages;
+use crate::database::schema::accounts;
+use crate::database::schema::sessions;
+use crate::database::schema::message_edits;
+use crate::database::schema::reactions;
+use crate::database::schema::direct_messages;
+use crate::database::schema::rooms;
+use crate::database::schema::room_members;
+use crate::database::schema::invites;
+use crate::database::schema::room_sanctions;
 
 #[allow(unused)]
 #[derive(Queryable, Selectable, Debug)]
@@ -13,6 +23,7 @@ pub struct User {
     pub id: i32,
     pub room_id: i32,
     pub uuid: Uuid,
+    pub connection: Uuid,
 }
 
 #[derive(Insertable, Debug)]
@@ -21,20 +32,26 @@ pub struct User {
 pub struct NewUser {
     pub room_id: i32,
     pub uuid: Uuid,
+    /// `Session::connection` of the connection in the room.
+    pub connection: Uuid,
 }
 
 #[allow(unused)]
-#[derive(Queryable, Selectable, Debug)]
+#[derive(Queryable, QueryableByName, Selectable, Debug, Clone)]
 #[diesel(table_name = messages)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Message {
-    pub id: i32,
+    pub id: i64,
     pub time: i64,
     pub username: String,
     pub message: String,
     pub room_id: i32,
     pub uuid: Uuid,
     pub protocol_type: i16,
+    pub seq: i64,
+    pub edited_at: Option<i64>,
+    pub deleted_at: Option<i64>,
+    pub reply_to: Option<i64>,
 }
 
 #[derive(Insertable, Debug)]
@@ -47,4 +64,166 @@ pub struct NewMessage {
     pub room_id: i32,
     pub uuid: Uuid,
     pub protocol_type: i16,
+    pub reply_to: Option<i64>,
+}
+
+#[allow(unused)]
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = accounts)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Account {
+    pub uuid: Uuid,
+    pub username: String,
+    pub display_name: String,
+    pub password_hash: String,
+    pub created_at: i64,
+    pub is_moderator: bool,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = accounts)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewAccount {
+    pub uuid: Uuid,
+    pub username: String,
+    pub display_name: String,
+    pub password_hash: String,
+    pub created_at: i64,
+}
+
+#[allow(unused)]
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = sessions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct SessionRecord {
+    pub id: Uuid,
+    pub account_uuid: Uuid,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub revoked: bool,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = sessions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewSessionRecord {
+    pub id: Uuid,
+    pub account_uuid: Uuid,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = message_edits)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewMessageEdit {
+    pub message_id: i64,
+    pub previous_text: String,
+    pub edited_at: i64,
+    pub edited_by: Uuid,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug)]
+#[diesel(table_name = reactions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ReactionRecord {
+    pub message_id: i64,
+    pub account_uuid: Uuid,
+    pub emoji: String,
+    pub created_at: i64,
+}
+
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = direct_messages)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DirectMessageRecord {
+    pub id: i64,
+    pub sender_uuid: Uuid,
+    pub sender_name: String,
+    pub recipient_uuid: Uuid,
+    pub recipient_name: String,
+    pub message: String,
+    pub time: i64,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = direct_messages)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewDirectMessage {
+    pub sender_uuid: Uuid,
+    pub sender_name: String,
+    pub recipient_uuid: Uuid,
+    pub recipient_name: String,
+    pub message: String,
+    pub time: i64,
+}
+
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = rooms)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RoomRecord {
+    pub id: i32,
+    pub name: String,
+    pub topic: String,
+    pub created_by: Option<Uuid>,
+    pub created_at: i64,
+    pub visibility: i16,
+    pub slow_mode_secs: i32,
+    pub retention_max_age_secs: Option<i64>,
+    pub retention_max_messages: Option<i64>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = rooms)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewRoom {
+    pub name: String,
+    pub topic: String,
+    pub created_by: Option<Uuid>,
+    pub created_at: i64,
+    pub visibility: i16,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = room_members)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewRoomMember {
+    pub room_id: i32,
+    pub account_uuid: Uuid,
+    pub joined_at: i64,
+    pub role: i16,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug)]
+#[diesel(table_name = invites)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct InviteRecord {
+    pub code: String,
+    pub room_id: i32,
+    pub created_by: Uuid,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug)]
+#[diesel(table_name = room_sanctions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct SanctionRecord {
+    pub room_id: i32,
+    pub account_uuid: Uuid,
+    pub kind: i16,
+    pub issued_by: Option<Uuid>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+}
+
+/// A message found by `queries::search_messages`.
+#[derive(QueryableByName, Debug)]
+pub struct SearchRecord {
+    #[diesel(embed)]
+    pub message: Message,
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub snippet: String,
+    #[diesel(sql_type = diesel::sql_types::Float4)]
+    pub rank: f32,
 }
@@ -0,0 +1,72 @@
+use crate::database::connection::database_url;
+use anyhow::{Context, Result};
+use diesel::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use std::env;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+const DEFAULT_POOL_SIZE: usize = 10;
+
+/// A fixed number of Postgres connections shared by all websocket
+/// connections. Connections are checked before being handed out and replaced
+/// when they broke, so the pool recovers on its own once Postgres is back.
+#[derive(Clone)]
+pub struct PgPool {
+    pool: Pool<ConnectionManager<PgConnection>>,
+    /// One permit per connection, so waiting for a free one happens here
+    /// instead of blocking a runtime thread inside r2d2.
+    permits: Arc<Semaphore>,
+}
+
+impl PgPool {
+    /// Sized by `POSTGRES_POOL_SIZE`. Opens one connection right away, so a
+    /// misconfigured database is noticed on startup.
+    pub fn from_env() -> Result<Self> {
+        let size = pool_size(env::var("POSTGRES_POOL_SIZE").ok());
+        let pool = Pool::builder()
+            .max_size(size as u32)
+            .min_idle(Some(1))
+            .test_on_check_out(true)
+            .build(ConnectionManager::new(database_url()))
+            .context("Could not connect to Postgres")?;
+        println!("Connected to Postgres with up to {} connections", size);
+        Ok(Self {
+            pool,
+            permits: Arc::new(Semaphore::new(size)),
+        })
+    }
+
+    /// Runs `f` on a connection of its own. Diesel is synchronous, so the
+    /// work is moved off the async executor with `block_in_place`, which
+    /// needs the multi-threaded runtime.
+    pub async fn run<T>(&self, f: impl FnOnce(&mut PgConnection) -> Result<T>) -> Result<T> {
+        let _permit = self.permits.acquire().await?;
+        tokio::task::block_in_place(|| {
+            let mut conn = self.pool.get().context("Could not connect to Postgres")?;
+            f(&mut conn)
+        })
+    }
+}
+
+/// The configured pool size, unless it isn't a positive number.
+fn pool_size(configured: Option<String>) -> usize {
+    configured
+        .and_then(|size| size.trim().parse::<usize>().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(DEFAULT_POOL_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_size_falls_back_to_the_default() {
+        assert_eq!(pool_size(Some("4".to_string())), 4);
+        assert_eq!(pool_size(Some(" 4\n".to_string())), 4);
+        for configured in [None, Some("0".to_string()), Some("many".to_string())] {
+            assert_eq!(pool_size(configured), DEFAULT_POOL_SIZE);
+        }
+    }
+}
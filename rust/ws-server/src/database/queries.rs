@@ -1,14 +1,34 @@
-use crate::database::models::{Message, NewMessage, NewUser, User};
+use crate::database::models::{
+    Account, DirectMessageRecord, InviteRecord, Message, NewAccount, NewDirectMessage, NewMessage,
+    NewMessageEdit, NewRoom, NewRoomMember, NewSessionRecord, NewUser, ReactionRecord, RoomRecord,
+    SanctionRecord, SearchRecord, SessionRecord, User,
+};
+use crate::database::schema::accounts;
+use crate::database::schema::direct_messages;
+use crate::database::schema::invites;
+use crate::database::schema::message_edits;
 use crate::database::schema::messages::{self, dsl::room_id as msg_room_id, dsl::*};
-use crate::database::schema::users::dsl::uuid;
+use crate::database::schema::reactions;
+use crate::database::schema::room_members;
+use crate::database::schema::room_sanctions;
+use crate::database::schema::room_sequences;
+use crate::database::schema::rooms;
+use crate::database::schema::sessions;
 use crate::database::schema::users::dsl::*;
+use crate::database::schema::users::dsl::{connection, room_id as user_room_id, uuid};
 use ::uuid::Uuid;
 use diesel::associations::HasTable;
 use diesel::prelude::*;
+use shared::{SEARCH_MATCH_END, SEARCH_MATCH_START};
 
+/// Records the room a connection is in, replacing the room it was in before.
 pub fn insert_user(conn: &mut PgConnection, user: NewUser) -> Result<usize, diesel::result::Error> {
+    let current_room_id = user.room_id;
     diesel::insert_into(users::table())
         .values(user)
+        .on_conflict(connection)
+        .do_update()
+        .set(user_room_id.eq(current_room_id))
         .execute(conn)
 }
 
@@ -22,47 +42,620 @@ pub fn get_messages(conn: &mut PgConnection) -> QueryResult<Vec<Message>> {
     messages.load::<Message>(conn)
 }
 
-pub fn insert_message(
-    conn: &mut PgConnection,
-    msg: NewMessage,
-) -> Result<usize, diesel::result::Error> {
-    diesel::insert_into(messages::table)
-        .values(msg)
-        .execute(conn)
+/// Stores `msg` under the next sequence number of its room and returns the
+/// stored row.
+///
+/// The counter row is locked by the upsert until the transaction commits, so
+/// concurrent inserts into the same room get distinct, increasing numbers.
+pub fn insert_message(conn: &mut PgConnection, msg: NewMessage) -> QueryResult<Message> {
+    conn.transaction(|conn| {
+        let next_seq = diesel::insert_into(room_sequences::table)
+            .values((
+                room_sequences::room_id.eq(msg.room_id),
+                room_sequences::last_seq.eq(1),
+            ))
+            .on_conflict(room_sequences::room_id)
+            .do_update()
+            .set(room_sequences::last_seq.eq(room_sequences::last_seq + 1))
+            .returning(room_sequences::last_seq)
+            .get_result::<i64>(conn)?;
+
+        diesel::insert_into(messages::table)
+            .values((&msg, seq.eq(next_seq)))
+            .returning(Message::as_returning())
+            .get_result(conn)
+    })
 }
 
-pub fn delete_user_by_uuid(
+pub fn delete_user_by_connection(
     conn: &mut PgConnection,
-    user_uuid: Uuid,
+    user_connection: Uuid,
 ) -> Result<usize, diesel::result::Error> {
-    diesel::delete(users.filter(uuid.eq(user_uuid))).execute(conn)
+    diesel::delete(users.filter(connection.eq(user_connection))).execute(conn)
 }
 
 pub fn get_history(
     conn: &mut PgConnection,
     requested_room_id: &i32,
     limit: &i64,
-    fetch_before: &u64,
+    before_seq: Option<i64>,
+    after_seq: Option<i64>,
 ) -> Result<Vec<Message>, diesel::result::Error> {
-    let mut result = messages
-        .filter(
-            msg_room_id
-                .eq(*requested_room_id)
-                .and(time.lt(*fetch_before as i64)),
-        )
-        .order_by(time.desc())
+    let mut query = messages
+        .filter(msg_room_id.eq(*requested_room_id))
+        .into_boxed();
+    if let Some(before_seq) = before_seq {
+        query = query.filter(seq.lt(before_seq));
+    }
+    if let Some(after_seq) = after_seq {
+        query = query.filter(seq.gt(after_seq));
+    }
+    // Paging forwards takes the oldest messages after the cursor, otherwise
+    // the newest ones before it.
+    query = if after_seq.is_some() {
+        query.order_by(seq.asc())
+    } else {
+        query.order_by(seq.desc())
+    };
+    let mut result = query
         .limit(*limit)
         .select(Message::as_select())
         .load::<Message>(conn)?;
 
-    result.sort_by_key(|e| e.time);
+    result.sort_by_key(|e| e.seq);
+    Ok(result)
+}
+
+/// Chat messages of a room that aren't deleted and match `search_query`,
+/// best match first. The condition is the one of the `messages_search`
+/// index, so the index is used.
+pub fn search_messages(
+    conn: &mut PgConnection,
+    requested_room_id: i32,
+    search_query: &str,
+    limit: i64,
+) -> QueryResult<Vec<SearchRecord>> {
+    let headline_options = format!(
+        "StartSel={}, StopSel={}",
+        SEARCH_MATCH_START, SEARCH_MATCH_END
+    );
+    diesel::sql_query(
+        "SELECT messages.*,
+                ts_headline('simple', message, search, $3) AS snippet,
+                ts_rank(to_tsvector('simple', message), search) AS rank
+         FROM messages, websearch_to_tsquery('simple', $2) AS search
+         WHERE room_id = $1
+           AND protocol_type = 4
+           AND deleted_at IS NULL
+           AND to_tsvector('simple', message) @@ search
+         ORDER BY rank DESC, seq DESC
+         LIMIT $4",
+    )
+    .bind::<diesel::sql_types::Integer, _>(requested_room_id)
+    .bind::<diesel::sql_types::Text, _>(search_query)
+    .bind::<diesel::sql_types::Text, _>(headline_options)
+    .bind::<diesel::sql_types::BigInt, _>(limit)
+    .load(conn)
+}
+
+/// Sequence number of message `message_id`, if it was posted in
+/// `requested_room_id`.
+pub fn get_message_seq(
+    conn: &mut PgConnection,
+    requested_room_id: i32,
+    message_id: i64,
+) -> QueryResult<Option<i64>> {
+    messages
+        .filter(messages::id.eq(message_id))
+        .filter(msg_room_id.eq(requested_room_id))
+        .select(seq)
+        .first::<i64>(conn)
+        .optional()
+}
+
+pub fn get_message(conn: &mut PgConnection, message_id: i64) -> QueryResult<Option<Message>> {
+    messages
+        .find(message_id)
+        .select(Message::as_select())
+        .first::<Message>(conn)
+        .optional()
+}
+
+/// Replaces the text of a message, keeping the previous one in
+/// `message_edits`.
+pub fn edit_message(
+    conn: &mut PgConnection,
+    edit: NewMessageEdit,
+    new_text: &str,
+) -> QueryResult<usize> {
+    conn.transaction(|conn| {
+        diesel::insert_into(message_edits::table)
+            .values(&edit)
+            .execute(conn)?;
+        diesel::update(messages.find(edit.message_id))
+            .set((
+                message.eq(new_text),
+                messages::edited_at.eq(Some(edit.edited_at)),
+            ))
+            .execute(conn)
+    })
+}
+
+/// Turns a message into a tombstone. The row stays so that sequence numbers
+/// and cursors keep working, only its text and edit history are dropped.
+pub fn delete_message(
+    conn: &mut PgConnection,
+    message_id: i64,
+    deleted_time: i64,
+) -> QueryResult<usize> {
+    conn.transaction(|conn| {
+        diesel::delete(message_edits::table.filter(message_edits::message_id.eq(message_id)))
+            .execute(conn)?;
+        diesel::delete(reactions::table.filter(reactions::message_id.eq(message_id)))
+            .execute(conn)?;
+        diesel::update(messages.find(message_id))
+            .set((message.eq(""), messages::deleted_at.eq(Some(deleted_time))))
+            .execute(conn)
+    })
+}
+
+/// Restores the edit and delete markers of an imported message.
+pub fn set_message_markers(
+    conn: &mut PgConnection,
+    message_id: i64,
+    edited_time: Option<i64>,
+    deleted_time: Option<i64>,
+) -> QueryResult<usize> {
+    diesel::update(messages.find(message_id))
+        .set((
+            messages::edited_at.eq(edited_time),
+            messages::deleted_at.eq(deleted_time),
+        ))
+        .execute(conn)
+}
+
+/// Adds a reaction, reacting twice with the same emoji is a no-op.
+pub fn insert_reaction(conn: &mut PgConnection, reaction: ReactionRecord) -> QueryResult<usize> {
+    diesel::insert_into(reactions::table)
+        .values(&reaction)
+        .on_conflict_do_nothing()
+        .execute(conn)
+}
+
+pub fn delete_reaction(
+    conn: &mut PgConnection,
+    message_id: i64,
+    account_uuid: Uuid,
+    emoji: &str,
+) -> QueryResult<usize> {
+    diesel::delete(reactions::table.find((message_id, account_uuid, emoji))).execute(conn)
+}
+
+/// Reactions to any of `message_ids`, oldest first.
+pub fn get_reactions(
+    conn: &mut PgConnection,
+    message_ids: &[i64],
+) -> QueryResult<Vec<ReactionRecord>> {
+    reactions::table
+        .filter(reactions::message_id.eq_any(message_ids))
+        .order_by(reactions::created_at.asc())
+        .select(ReactionRecord::as_select())
+        .load::<ReactionRecord>(conn)
+}
+
+pub fn insert_direct_message(
+    conn: &mut PgConnection,
+    msg: NewDirectMessage,
+) -> QueryResult<DirectMessageRecord> {
+    diesel::insert_into(direct_messages::table)
+        .values(msg)
+        .returning(DirectMessageRecord::as_returning())
+        .get_result(conn)
+}
+
+/// Direct messages `account_uuid` sent or received, newest `limit` ones
+/// before `before_id`, returned oldest first. Nobody else's conversations
+/// can be read through this.
+pub fn get_direct_history(
+    conn: &mut PgConnection,
+    account_uuid: Uuid,
+    before_id: Option<i64>,
+    limit: i64,
+) -> QueryResult<Vec<DirectMessageRecord>> {
+    let mut query = direct_messages::table
+        .filter(
+            direct_messages::sender_uuid
+                .eq(account_uuid)
+                .or(direct_messages::recipient_uuid.eq(account_uuid)),
+        )
+        .into_boxed();
+    if let Some(before_id) = before_id {
+        query = query.filter(direct_messages::id.lt(before_id));
+    }
+    let mut result = query
+        .order_by(direct_messages::id.desc())
+        .limit(limit)
+        .select(DirectMessageRecord::as_select())
+        .load::<DirectMessageRecord>(conn)?;
+
+    result.sort_by_key(|e| e.id);
     Ok(result)
 }
 
-pub fn get_room_id_by_uuid(conn: &mut PgConnection, user_uuid: Uuid) -> QueryResult<Vec<User>> {
+pub fn insert_room(conn: &mut PgConnection, room: NewRoom) -> QueryResult<RoomRecord> {
+    diesel::insert_into(rooms::table)
+        .values(room)
+        .returning(RoomRecord::as_returning())
+        .get_result(conn)
+}
+
+pub fn get_room(
+    conn: &mut PgConnection,
+    requested_room_id: i32,
+) -> QueryResult<Option<RoomRecord>> {
+    rooms::table
+        .find(requested_room_id)
+        .select(RoomRecord::as_select())
+        .first::<RoomRecord>(conn)
+        .optional()
+}
+
+pub fn get_room_by_name(
+    conn: &mut PgConnection,
+    room_name: &str,
+) -> QueryResult<Option<RoomRecord>> {
+    rooms::table
+        .filter(rooms::name.eq(room_name))
+        .select(RoomRecord::as_select())
+        .first::<RoomRecord>(conn)
+        .optional()
+}
+
+pub fn update_topic(
+    conn: &mut PgConnection,
+    requested_room_id: i32,
+    new_topic: &str,
+) -> QueryResult<RoomRecord> {
+    diesel::update(rooms::table.find(requested_room_id))
+        .set(rooms::topic.eq(new_topic))
+        .returning(RoomRecord::as_returning())
+        .get_result(conn)
+}
+
+pub fn update_slow_mode(
+    conn: &mut PgConnection,
+    requested_room_id: i32,
+    seconds: i32,
+) -> QueryResult<RoomRecord> {
+    diesel::update(rooms::table.find(requested_room_id))
+        .set(rooms::slow_mode_secs.eq(seconds))
+        .returning(RoomRecord::as_returning())
+        .get_result(conn)
+}
+
+pub fn update_retention(
+    conn: &mut PgConnection,
+    requested_room_id: i32,
+    max_age_secs: Option<i64>,
+    max_messages: Option<i64>,
+) -> QueryResult<RoomRecord> {
+    diesel::update(rooms::table.find(requested_room_id))
+        .set((
+            rooms::retention_max_age_secs.eq(max_age_secs),
+            rooms::retention_max_messages.eq(max_messages),
+        ))
+        .returning(RoomRecord::as_returning())
+        .get_result(conn)
+}
+
+/// Named rooms with a retention of their own.
+pub fn get_rooms_with_retention(conn: &mut PgConnection) -> QueryResult<Vec<RoomRecord>> {
+    rooms::table
+        .filter(
+            rooms::retention_max_age_secs
+                .is_not_null()
+                .or(rooms::retention_max_messages.is_not_null()),
+        )
+        .select(RoomRecord::as_select())
+        .load(conn)
+}
+
+/// Every room that ever had a message, named or not.
+pub fn get_message_room_ids(conn: &mut PgConnection) -> QueryResult<Vec<i32>> {
+    room_sequences::table
+        .select(room_sequences::room_id)
+        .load(conn)
+}
+
+/// Sequence number of the newest entry of a room beyond its `keep` newest
+/// ones, if there are that many.
+pub fn get_seq_beyond(
+    conn: &mut PgConnection,
+    requested_room_id: i32,
+    keep: i64,
+) -> QueryResult<Option<i64>> {
+    messages
+        .filter(msg_room_id.eq(requested_room_id))
+        .order_by(seq.desc())
+        .offset(keep)
+        .select(seq)
+        .first::<i64>(conn)
+        .optional()
+}
+
+/// Deletes up to `batch` of the oldest entries of a room stored before
+/// `older_than` or with a sequence number up to `up_to_seq`. Rows locked by
+/// somebody else are left for the next batch, so pruning never waits on
+/// edits or other nodes pruning the same room.
+pub fn prune_messages(
+    conn: &mut PgConnection,
+    requested_room_id: i32,
+    older_than: Option<i64>,
+    up_to_seq: Option<i64>,
+    batch: i64,
+) -> QueryResult<usize> {
+    diesel::sql_query(
+        "DELETE FROM messages WHERE id IN (
+             SELECT id FROM messages
+             WHERE room_id = $1 AND (time < $2 OR seq <= $3)
+             ORDER BY seq
+             LIMIT $4
+             FOR UPDATE SKIP LOCKED
+         )",
+    )
+    .bind::<diesel::sql_types::Integer, _>(requested_room_id)
+    .bind::<diesel::sql_types::Nullable<diesel::sql_types::BigInt>, _>(older_than)
+    .bind::<diesel::sql_types::Nullable<diesel::sql_types::BigInt>, _>(up_to_seq)
+    .bind::<diesel::sql_types::BigInt, _>(batch)
+    .execute(conn)
+}
+
+/// Joining a room twice keeps the first membership.
+pub fn insert_room_member(conn: &mut PgConnection, member: NewRoomMember) -> QueryResult<usize> {
+    diesel::insert_into(room_members::table)
+        .values(member)
+        .on_conflict_do_nothing()
+        .execute(conn)
+}
+
+pub fn is_room_member(
+    conn: &mut PgConnection,
+    requested_room_id: i32,
+    member_uuid: Uuid,
+) -> QueryResult<bool> {
+    diesel::select(diesel::dsl::exists(
+        room_members::table.find((requested_room_id, member_uuid)),
+    ))
+    .get_result(conn)
+}
+
+pub fn get_room_role(
+    conn: &mut PgConnection,
+    requested_room_id: i32,
+    member_uuid: Uuid,
+) -> QueryResult<Option<i16>> {
+    room_members::table
+        .find((requested_room_id, member_uuid))
+        .select(room_members::role)
+        .first::<i16>(conn)
+        .optional()
+}
+
+/// Makes `member` a member of the room with its role, whether it was one before or not.
+pub fn set_room_role(conn: &mut PgConnection, member: NewRoomMember) -> QueryResult<usize> {
+    diesel::insert_into(room_members::table)
+        .values(&member)
+        .on_conflict((room_members::room_id, room_members::account_uuid))
+        .do_update()
+        .set(room_members::role.eq(member.role))
+        .execute(conn)
+}
+
+/// Replaces an earlier sanction of the same kind, so bans can be extended or shortened.
+pub fn upsert_sanction(conn: &mut PgConnection, sanction: SanctionRecord) -> QueryResult<usize> {
+    diesel::insert_into(room_sanctions::table)
+        .values(&sanction)
+        .on_conflict((
+            room_sanctions::room_id,
+            room_sanctions::account_uuid,
+            room_sanctions::kind,
+        ))
+        .do_update()
+        .set((
+            room_sanctions::issued_by.eq(sanction.issued_by),
+            room_sanctions::created_at.eq(sanction.created_at),
+            room_sanctions::expires_at.eq(sanction.expires_at),
+        ))
+        .execute(conn)
+}
+
+/// The sanction of `sanction_kind` that is still in effect at `now`, if any.
+pub fn get_active_sanction(
+    conn: &mut PgConnection,
+    requested_room_id: i32,
+    member_uuid: Uuid,
+    sanction_kind: i16,
+    now: i64,
+) -> QueryResult<Option<SanctionRecord>> {
+    room_sanctions::table
+        .find((requested_room_id, member_uuid, sanction_kind))
+        .filter(
+            room_sanctions::expires_at
+                .is_null()
+                .or(room_sanctions::expires_at.gt(now)),
+        )
+        .select(SanctionRecord::as_select())
+        .first::<SanctionRecord>(conn)
+        .optional()
+}
+
+pub fn delete_sanctions(
+    conn: &mut PgConnection,
+    requested_room_id: i32,
+    member_uuid: Uuid,
+) -> QueryResult<usize> {
+    diesel::delete(
+        room_sanctions::table
+            .filter(room_sanctions::room_id.eq(requested_room_id))
+            .filter(room_sanctions::account_uuid.eq(member_uuid)),
+    )
+    .execute(conn)
+}
+
+pub fn insert_invite(conn: &mut PgConnection, invite: InviteRecord) -> QueryResult<usize> {
+    diesel::insert_into(invites::table)
+        .values(invite)
+        .execute(conn)
+}
+
+/// The invite behind `invite_code`, as long as it hasn't expired at `now`.
+pub fn get_invite(
+    conn: &mut PgConnection,
+    invite_code: &str,
+    now: i64,
+) -> QueryResult<Option<InviteRecord>> {
+    invites::table
+        .find(invite_code)
+        .filter(invites::expires_at.gt(now))
+        .select(InviteRecord::as_select())
+        .first::<InviteRecord>(conn)
+        .optional()
+}
+
+/// Rooms with visibility 0 are public.
+pub fn get_public_rooms(conn: &mut PgConnection) -> QueryResult<Vec<RoomRecord>> {
+    rooms::table
+        .filter(rooms::visibility.eq(0))
+        .select(RoomRecord::as_select())
+        .load::<RoomRecord>(conn)
+}
+
+/// Time of the newest message per room, rooms without messages are left out.
+pub fn get_last_activity(
+    conn: &mut PgConnection,
+    room_ids: &[i32],
+) -> QueryResult<Vec<(i32, Option<i64>)>> {
+    messages::table
+        .filter(messages::room_id.eq_any(room_ids))
+        .group_by(messages::room_id)
+        .select((messages::room_id, diesel::dsl::max(messages::time)))
+        .load(conn)
+}
+
+/// Rooms any connection of the account is in.
+pub fn get_room_ids_by_uuid(conn: &mut PgConnection, user_uuid: Uuid) -> QueryResult<Vec<i32>> {
     users
         .filter(uuid.eq(user_uuid))
-        .limit(1)
-        .select(User::as_select())
-        .load::<User>(conn)
+        .select(user_room_id)
+        .distinct()
+        .load::<i32>(conn)
+}
+
+pub fn insert_account(conn: &mut PgConnection, account: NewAccount) -> QueryResult<usize> {
+    diesel::insert_into(accounts::table)
+        .values(account)
+        .execute(conn)
+}
+
+pub fn get_account_by_username(
+    conn: &mut PgConnection,
+    account_username: &str,
+) -> QueryResult<Option<Account>> {
+    accounts::table
+        .filter(accounts::username.eq(account_username))
+        .select(Account::as_select())
+        .first::<Account>(conn)
+        .optional()
+}
+
+/// Display names of the given accounts, accounts that don't exist are left
+/// out.
+pub fn get_account_by_uuid(
+    conn: &mut PgConnection,
+    account_uuid: Uuid,
+) -> QueryResult<Option<Account>> {
+    accounts::table
+        .find(account_uuid)
+        .select(Account::as_select())
+        .first::<Account>(conn)
+        .optional()
+}
+
+pub fn get_display_names(
+    conn: &mut PgConnection,
+    account_uuids: &[Uuid],
+) -> QueryResult<Vec<(Uuid, String)>> {
+    accounts::table
+        .filter(accounts::uuid.eq_any(account_uuids))
+        .select((accounts::uuid, accounts::display_name))
+        .load::<(Uuid, String)>(conn)
+}
+
+/// The account logging in with `name`, or else the one shown as `name`.
+pub fn get_account_by_name(conn: &mut PgConnection, name: &str) -> QueryResult<Option<Account>> {
+    if let Some(account) = get_account_by_username(conn, name)? {
+        return Ok(Some(account));
+    }
+    accounts::table
+        .filter(accounts::display_name.eq(name))
+        .select(Account::as_select())
+        .first::<Account>(conn)
+        .optional()
+}
+
+/// Whether an account other than `except` logs in with or is shown as `name`.
+pub fn name_in_use(conn: &mut PgConnection, name: &str, except: Uuid) -> QueryResult<bool> {
+    diesel::select(diesel::dsl::exists(
+        accounts::table
+            .filter(
+                accounts::username
+                    .eq(name)
+                    .or(accounts::display_name.eq(name)),
+            )
+            .filter(accounts::uuid.ne(except)),
+    ))
+    .get_result(conn)
+}
+
+pub fn update_display_name(
+    conn: &mut PgConnection,
+    account_uuid: Uuid,
+    new_display_name: &str,
+) -> QueryResult<usize> {
+    diesel::update(accounts::table.find(account_uuid))
+        .set(accounts::display_name.eq(new_display_name))
+        .execute(conn)
+}
+
+pub fn insert_session(conn: &mut PgConnection, session: NewSessionRecord) -> QueryResult<usize> {
+    diesel::insert_into(sessions::table)
+        .values(session)
+        .execute(conn)
+}
+
+/// Looks up a session together with the account it belongs to, whether
+/// `auth::resumable` still accepts it is up to the caller.
+pub fn get_session(
+    conn: &mut PgConnection,
+    session_id: Uuid,
+) -> QueryResult<Option<(SessionRecord, Account)>> {
+    sessions::table
+        .inner_join(accounts::table)
+        .filter(sessions::id.eq(session_id))
+        .select((SessionRecord::as_select(), Account::as_select()))
+        .first::<(SessionRecord, Account)>(conn)
+        .optional()
+}
+
+pub fn revoke_session(conn: &mut PgConnection, session_id: Uuid) -> QueryResult<usize> {
+    diesel::update(sessions::table.find(session_id))
+        .set(sessions::revoked.eq(true))
+        .execute(conn)
+}
+
+pub fn delete_expired_sessions(conn: &mut PgConnection, now: i64) -> QueryResult<usize> {
+    diesel::delete(
+        sessions::table.filter(sessions::expires_at.le(now).or(sessions::revoked.eq(true))),
+    )
+    .execute(conn)
 }
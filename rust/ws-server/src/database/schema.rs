@@ -3,17 +3,129 @@ diesel::table! {
         id -> Int4,
         room_id -> Int4,
         uuid -> Uuid,
+        connection -> Uuid,
     }
 }
 
 diesel::table! {
     messages (id) {
-        id -> Int4,
+        id -> Int8,
         time -> BigInt,
         message -> Text,
         username -> Text,
         room_id -> Int4,
         uuid -> Uuid,
         protocol_type -> SmallInt,
+        seq -> BigInt,
+        edited_at -> Nullable<BigInt>,
+        deleted_at -> Nullable<BigInt>,
+        reply_to -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    message_edits (id) {
+        id -> Int8,
+        message_id -> Int8,
+        previous_text -> Text,
+        edited_at -> BigInt,
+        edited_by -> Uuid,
+    }
+}
+
+diesel::table! {
+    room_sequences (room_id) {
+        room_id -> Int4,
+        last_seq -> BigInt,
+    }
+}
+
+diesel::table! {
+    accounts (uuid) {
+        uuid -> Uuid,
+        username -> Text,
+        display_name -> Text,
+        password_hash -> Text,
+        created_at -> BigInt,
+        is_moderator -> Bool,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Uuid,
+        account_uuid -> Uuid,
+        created_at -> BigInt,
+        expires_at -> BigInt,
+        revoked -> Bool,
     }
 }
+
+diesel::table! {
+    reactions (message_id, account_uuid, emoji) {
+        message_id -> Int8,
+        account_uuid -> Uuid,
+        emoji -> Text,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    direct_messages (id) {
+        id -> Int8,
+        sender_uuid -> Uuid,
+        sender_name -> Text,
+        recipient_uuid -> Uuid,
+        recipient_name -> Text,
+        message -> Text,
+        time -> BigInt,
+    }
+}
+
+diesel::table! {
+    rooms (id) {
+        id -> Int4,
+        name -> Text,
+        topic -> Text,
+        created_by -> Nullable<Uuid>,
+        created_at -> BigInt,
+        visibility -> SmallInt,
+        slow_mode_secs -> Int4,
+        retention_max_age_secs -> Nullable<BigInt>,
+        retention_max_messages -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    room_members (room_id, account_uuid) {
+        room_id -> Int4,
+        account_uuid -> Uuid,
+        joined_at -> BigInt,
+        role -> SmallInt,
+    }
+}
+
+diesel::table! {
+    room_sanctions (room_id, account_uuid, kind) {
+        room_id -> Int4,
+        account_uuid -> Uuid,
+        kind -> SmallInt,
+        issued_by -> Nullable<Uuid>,
+        created_at -> BigInt,
+        expires_at -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    invites (code) {
+        code -> Text,
+        room_id -> Int4,
+        created_by -> Uuid,
+        created_at -> BigInt,
+        expires_at -> BigInt,
+    }
+}
+
+diesel::joinable!(sessions -> accounts (account_uuid));
+diesel::joinable!(message_edits -> messages (message_id));
+diesel::allow_tables_to_appear_in_same_query!(accounts, sessions);
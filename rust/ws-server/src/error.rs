@@ -0,0 +1,58 @@
+use shared::{ErrorCode, TalkProtocol};
+use std::fmt;
+use std::time::Duration;
+
+/// A request the server refused, reported back to the client as
+/// `TalkProtocol::Error`.
+#[derive(Debug)]
+pub struct RequestError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub retry_after: Option<Duration>,
+}
+
+impl RequestError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<RequestError> for TalkProtocol {
+    fn from(error: RequestError) -> Self {
+        TalkProtocol::Error {
+            code: error.code,
+            message: error.message,
+            retry_after_ms: error.retry_after.map(|after| after.as_millis() as u64),
+        }
+    }
+}
+
+/// Turns whatever a handler failed with into the reply for the client.
+/// Anything that isn't a `RequestError` is our own fault, so its details stay
+/// in the server log.
+pub fn error_reply(error: anyhow::Error) -> TalkProtocol {
+    match error.downcast::<RequestError>() {
+        Ok(error) => error.into(),
+        Err(error) => {
+            eprintln!("[SERVER] Internal error: {:?}", error);
+            RequestError::new(ErrorCode::Internal, "Something went wrong on the server").into()
+        }
+    }
+}
@@ -1,6 +1,14 @@
+mod archive;
+mod auth;
+mod codec;
 mod wsserver;
 mod database;
+mod error;
+mod ratelimit;
+mod retention;
 mod redis;
+mod session;
+mod shutdown;
 
 use openssl_sys as _;
 use pq_sys as _;
@@ -12,6 +20,19 @@ use anyhow::Result;
 async fn main() -> Result<()> {
     dotenv().ok(); 
 
+    // Admin tools, run against a database the server has already migrated
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(command @ ("export" | "import")) = args.first().map(String::as_str) {
+        let rest = args[1..].to_vec();
+        let command = command.to_string();
+        return tokio::task::spawn_blocking(move || archive::run(&command, &rest)).await?;
+    }
+
+    tokio::task::spawn_blocking(database::migrations::run_pending_migrations).await??;
+    if std::env::args().any(|arg| arg == "--migrate-only") {
+        return Ok(());
+    }
+
     let server_handle = tokio::spawn(async move {
         wsserver::start_ws_server().await.expect("Server failed");
     });
@@ -0,0 +1,360 @@
+use crate::error::RequestError;
+use crate::redis::AsyncRedis;
+use anyhow::Result;
+use redis::{AsyncCommands, Script};
+use shared::{ErrorCode, TalkProtocol};
+use std::collections::HashMap;
+use std::env;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Requests that share a budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LimitKind {
+    Post,
+    Typing,
+    Reaction,
+    Edit,
+    Room,
+    Fetch,
+    Auth,
+    Other,
+}
+
+impl LimitKind {
+    const ALL: [LimitKind; 8] = [
+        LimitKind::Post,
+        LimitKind::Typing,
+        LimitKind::Reaction,
+        LimitKind::Edit,
+        LimitKind::Room,
+        LimitKind::Fetch,
+        LimitKind::Auth,
+        LimitKind::Other,
+    ];
+
+    pub fn of(msg: &TalkProtocol) -> Self {
+        match msg {
+            TalkProtocol::PostMessage { .. } | TalkProtocol::SendDirect { .. } => LimitKind::Post,
+            TalkProtocol::Typing { .. } => LimitKind::Typing,
+            TalkProtocol::AddReaction { .. } | TalkProtocol::RemoveReaction { .. } => {
+                LimitKind::Reaction
+            }
+            TalkProtocol::EditMessage { .. } | TalkProtocol::DeleteMessage { .. } => {
+                LimitKind::Edit
+            }
+            TalkProtocol::JoinRoom { .. }
+            | TalkProtocol::LeaveRoom { .. }
+            | TalkProtocol::CreateRoom { .. }
+            | TalkProtocol::RedeemInvite { .. } => LimitKind::Room,
+            TalkProtocol::Fetch { .. }
+            | TalkProtocol::FetchDirect { .. }
+            | TalkProtocol::ListMembers { .. }
+            | TalkProtocol::ListRooms
+            | TalkProtocol::ResolveRoom { .. }
+            | TalkProtocol::Search { .. } => LimitKind::Fetch,
+            TalkProtocol::Register { .. }
+            | TalkProtocol::Login { .. }
+            | TalkProtocol::Resume { .. } => LimitKind::Auth,
+            _ => LimitKind::Other,
+        }
+    }
+
+    /// Suffix of the `RATE_LIMIT_*` variable overriding the default.
+    fn name(self) -> &'static str {
+        match self {
+            LimitKind::Post => "POST",
+            LimitKind::Typing => "TYPING",
+            LimitKind::Reaction => "REACTION",
+            LimitKind::Edit => "EDIT",
+            LimitKind::Room => "ROOM",
+            LimitKind::Fetch => "FETCH",
+            LimitKind::Auth => "AUTH",
+            LimitKind::Other => "OTHER",
+        }
+    }
+
+    fn default_limit(self) -> Limit {
+        let (burst, per_minute) = match self {
+            LimitKind::Post => (5, 30),
+            LimitKind::Typing => (3, 30),
+            LimitKind::Reaction => (10, 60),
+            LimitKind::Edit => (5, 20),
+            LimitKind::Room => (5, 20),
+            LimitKind::Fetch => (10, 60),
+            LimitKind::Auth => (5, 10),
+            LimitKind::Other => (20, 120),
+        };
+        Limit { burst, per_minute }
+    }
+
+    /// Configured as `RATE_LIMIT_POST=<burst>/<per minute>`, e.g. `5/30`.
+    fn limit(self) -> Limit {
+        static LIMITS: OnceLock<HashMap<LimitKind, Limit>> = OnceLock::new();
+        let limits = LIMITS.get_or_init(|| {
+            LimitKind::ALL
+                .into_iter()
+                .map(|kind| {
+                    let limit = env::var(format!("RATE_LIMIT_{}", kind.name()))
+                        .ok()
+                        .and_then(|value| Limit::parse(&value))
+                        .unwrap_or_else(|| kind.default_limit());
+                    (kind, limit)
+                })
+                .collect()
+        });
+        limits[&self]
+    }
+}
+
+/// A bucket holds up to `burst` requests and refills `per_minute` of them
+/// every minute.
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+impl Limit {
+    fn parse(value: &str) -> Option<Self> {
+        let (burst, per_minute) = value.split_once('/')?;
+        let limit = Limit {
+            burst: burst.trim().parse().ok()?,
+            per_minute: per_minute.trim().parse().ok()?,
+        };
+        (limit.burst > 0 && limit.per_minute > 0).then_some(limit)
+    }
+
+    fn tokens_per_milli(self) -> f64 {
+        self.per_minute as f64 / 60_000.0
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: Limit) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or tells how long until the next one is there.
+    fn take(&mut self, limit: Limit) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_millis() as f64;
+        self.tokens = (self.tokens + elapsed * limit.tokens_per_milli()).min(limit.burst as f64);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        let missing = (1.0 - self.tokens) / limit.tokens_per_milli();
+        Err(Duration::from_millis(missing.ceil() as u64))
+    }
+}
+
+/// Buckets of a single connection, checked before anything reaches Redis or
+/// Postgres.
+#[derive(Debug, Default)]
+pub struct ConnectionLimiter {
+    buckets: HashMap<LimitKind, TokenBucket>,
+}
+
+impl ConnectionLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn check(&mut self, kind: LimitKind) -> Result<(), RequestError> {
+        let limit = kind.limit();
+        self.buckets
+            .entry(kind)
+            .or_insert_with(|| TokenBucket::full(limit))
+            .take(limit)
+            .map_err(rate_limited)
+    }
+}
+
+/// The same token bucket as `TokenBucket::take`, kept in a hash per account
+/// (see `shared_bucket_key`) so every node draws from it. Redis' clock is
+/// used so nodes don't have to agree on the time. Returns the milliseconds to
+/// wait, 0 if a token was taken.
+const ACCOUNT_BUCKET_SCRIPT: &str = r"
+local burst = tonumber(ARGV[1])
+local per_ms = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(state[1]) or burst
+local updated = tonumber(state[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated) * per_ms)
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = math.ceil((1 - tokens) / per_ms)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(burst / per_ms))
+return wait
+";
+
+/// Whose shared bucket a request draws from besides the connection's own:
+/// the account once logged in, before that the account a login is tried
+/// for, so reconnecting doesn't buy more guesses at a password. Logins are
+/// keyed on the name exactly as `handle_login` looks it up.
+fn shared_bucket_key(account: Option<Uuid>, msg: &TalkProtocol, kind: LimitKind) -> Option<String> {
+    let owner = match (account, msg) {
+        (Some(account), _) => account.simple().to_string(),
+        (None, TalkProtocol::Login { username, .. }) => format!("login:{}", username.trim()),
+        (None, _) => return None,
+    };
+    Some(format!("ratelimit:{}:{}", owner, kind.name()))
+}
+
+/// Checks `msg` against the connection's own buckets and against the ones
+/// of the account, shared by all of its connections on every node.
+pub async fn check(
+    limiter: &mut ConnectionLimiter,
+    account: Option<Uuid>,
+    msg: &TalkProtocol,
+    async_redis: &AsyncRedis,
+) -> Result<()> {
+    let kind = LimitKind::of(msg);
+    limiter.check(kind)?;
+    let Some(key) = shared_bucket_key(account, msg, kind) else {
+        return Ok(());
+    };
+    let limit = kind.limit();
+    let wait: u64 = Script::new(ACCOUNT_BUCKET_SCRIPT)
+        .key(key)
+        .arg(limit.burst)
+        .arg(limit.tokens_per_milli())
+        .invoke_async(&mut async_redis.clone())
+        .await?;
+    if wait > 0 {
+        return Err(rate_limited(Duration::from_millis(wait)).into());
+    }
+    Ok(())
+}
+
+fn slow_mode_key(room_id: i32, uuid: Uuid) -> String {
+    format!("slowmode:{}:{}", room_id, uuid.simple())
+}
+
+/// Lets an account post in a room in slow mode once per `interval`.
+pub async fn check_slow_mode(
+    async_redis: &AsyncRedis,
+    room_id: i32,
+    uuid: Uuid,
+    interval: Duration,
+) -> Result<()> {
+    let key = slow_mode_key(room_id, uuid);
+    let mut conn = async_redis.clone();
+    let posted: bool = redis::cmd("SET")
+        .arg(&key)
+        .arg(1)
+        .arg("PX")
+        .arg(interval.as_millis() as u64)
+        .arg("NX")
+        .query_async::<Option<String>>(&mut conn)
+        .await?
+        .is_some();
+    if posted {
+        return Ok(());
+    }
+    let remaining: i64 = conn.pttl(&key).await?;
+    Err(rate_limited(Duration::from_millis(remaining.max(0) as u64)).into())
+}
+
+pub fn rate_limited(retry_after: Duration) -> RequestError {
+    RequestError::new(
+        ErrorCode::RateLimited,
+        format!(
+            "Slow down, try again in {:.1} seconds",
+            retry_after.as_secs_f64()
+        ),
+    )
+    .retry_after(retry_after)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        burst: 2,
+        per_minute: 60,
+    };
+
+    /// A bucket last touched `ago`, so refills can be tested without sleeping.
+    fn bucket(tokens: f64, ago: Duration) -> TokenBucket {
+        TokenBucket {
+            tokens,
+            updated: Instant::now() - ago,
+        }
+    }
+
+    #[test]
+    fn empties_after_the_burst() {
+        let mut bucket = TokenBucket::full(LIMIT);
+        assert_eq!(bucket.take(LIMIT), Ok(()));
+        assert_eq!(bucket.take(LIMIT), Ok(()));
+        let wait = bucket.take(LIMIT).unwrap_err();
+        // One token a second
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn refills_with_the_time_passed() {
+        let mut bucket = bucket(0.0, Duration::from_millis(1500));
+        assert_eq!(bucket.take(LIMIT), Ok(()));
+        let wait = bucket.take(LIMIT).unwrap_err();
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn refills_no_further_than_the_burst() {
+        let mut bucket = bucket(0.0, Duration::from_secs(3600));
+        assert_eq!(bucket.take(LIMIT), Ok(()));
+        assert_eq!(bucket.take(LIMIT), Ok(()));
+        assert!(bucket.take(LIMIT).is_err());
+    }
+
+    #[test]
+    fn parses_configured_limits() {
+        let limit = Limit::parse(" 5 / 30 ").unwrap();
+        assert_eq!((limit.burst, limit.per_minute), (5, 30));
+        assert!(Limit::parse("5").is_none());
+        assert!(Limit::parse("0/30").is_none());
+        assert!(Limit::parse("5/x").is_none());
+    }
+
+    #[test]
+    fn logins_share_a_bucket_per_username() {
+        let login = |username: &str| TalkProtocol::Login {
+            username: username.to_string(),
+            password: String::new(),
+        };
+        assert_eq!(
+            shared_bucket_key(None, &login(" Alice "), LimitKind::Auth),
+            shared_bucket_key(None, &login("Alice"), LimitKind::Auth)
+        );
+        // Names are case-sensitive, so "alice" is another account.
+        assert_ne!(
+            shared_bucket_key(None, &login("Alice"), LimitKind::Auth),
+            shared_bucket_key(None, &login("alice"), LimitKind::Auth)
+        );
+        assert_eq!(
+            shared_bucket_key(None, &TalkProtocol::ListRooms, LimitKind::Fetch),
+            None
+        );
+    }
+}
@@ -1,14 +1,46 @@
 use redis::cluster::{ClusterClient, ClusterClientBuilder, ClusterConnection};
 use redis::cluster_async::ClusterConnection as ClusterConnectionAsync;
-use redis::{PushInfo, Value};
+use redis::{PushInfo, RedisResult, Value};
 use shared::TalkProtocol;
 use std::{env, sync::Arc};
-use tokio::sync::{Mutex as TMutex, mpsc::{UnboundedSender as TUnboundedSender, UnboundedReceiver as TUnboundedReceiver}};
-use tokio_tungstenite::tungstenite::protocol::Message;
 use tokio::sync::oneshot::Sender;
+use tokio::sync::{
+    Mutex as TMutex,
+    mpsc::{UnboundedReceiver as TUnboundedReceiver, UnboundedSender as TUnboundedSender},
+};
+use uuid::Uuid;
+
+pub mod presence;
+pub mod subscriber;
+
+pub use subscriber::Subscriber;
 
 pub type SharedRedis = Arc<TMutex<ClusterConnection>>;
 
+/// Multiplexed, every task sends its commands through its own clone without
+/// waiting for the others.
+pub type AsyncRedis = ClusterConnectionAsync;
+
+/// A channel the subscriber of a connection should switch to. Each kind
+/// replaces the previous channel of the same kind.
+#[derive(Debug)]
+pub enum Subscription {
+    /// The room the connection is in, `None` after leaving it.
+    Room(Option<i32>),
+    /// Direct messages of the logged in account, `None` after logging out.
+    User(Option<Uuid>),
+}
+
+/// A subscription change, acknowledged once Redis delivers the new channel
+/// or with the error that kept it from doing so.
+pub type SubscriptionRequest = (Subscription, Sender<RedisResult<()>>);
+
+/// Per-account channel, so direct messages reach the recipient on whatever
+/// node they are connected to.
+pub fn user_channel(uuid: Uuid) -> String {
+    format!("user:{}", uuid.simple())
+}
+
 pub async fn create_redis_async_pubsub_connection()
 -> Result<(ClusterConnectionAsync, TUnboundedReceiver<PushInfo>), redis::RedisError> {
     let nodes = env::var("REDIS_NODES")
@@ -32,6 +64,14 @@ pub async fn create_redis_async_pubsub_connection()
     Ok((connection, rx))
 }
 
+pub async fn create_redis_async_connection() -> Result<AsyncRedis, redis::RedisError> {
+    let nodes = env::var("REDIS_NODES")
+        .unwrap_or_else(|_| "localhost:7001,localhost:7002,localhost:7003".to_string());
+    let node_urls: Vec<String> = nodes.split(',').map(|s| format!("redis://{}", s)).collect();
+
+    ClusterClient::new(node_urls)?.get_async_connection().await
+}
+
 pub async fn create_redis_connection() -> Result<ClusterConnection, redis::RedisError> {
     let nodes = env::var("REDIS_NODES")
         .unwrap_or_else(|_| "localhost:7001,localhost:7002,localhost:7003".to_string());
@@ -45,26 +85,26 @@ pub async fn create_redis_connection() -> Result<ClusterConnection, redis::Redis
 #[allow(dead_code)]
 fn extract_binary_payload_from_pmessage(data: Vec<Value>) -> Option<Vec<u8>> {
     // PMessage data format: [pattern, channel, binary_payload]
-    if data.len() >= 3 {
-        if let Value::BulkString(binary_data) = &data[2] {
-            return Some(binary_data.clone());
-        }
+    if data.len() >= 3
+        && let Value::BulkString(binary_data) = &data[2]
+    {
+        return Some(binary_data.clone());
     }
     None
 }
 
 fn extract_binary_payload_from_message(data: Vec<Value>) -> Option<Vec<u8>> {
     // PMessage data format: [pattern, channel, binary_payload]
-    if data.len() >= 2 {
-        if let Value::BulkString(binary_data) = &data[1] {
-            return Some(binary_data.clone());
-        }
+    if data.len() >= 2
+        && let Value::BulkString(binary_data) = &data[1]
+    {
+        return Some(binary_data.clone());
     }
     None
 }
 
 #[allow(dead_code)]
-pub async fn subscribe_to_redis_pattern(tx: TUnboundedSender<Message>) {
+pub async fn subscribe_to_redis_pattern(tx: TUnboundedSender<TalkProtocol>) {
     let r = create_redis_async_pubsub_connection().await;
     let (mut con, mut rx) = r.expect("Pubusb Connection");
 
@@ -78,7 +118,7 @@ pub async fn subscribe_to_redis_pattern(tx: TUnboundedSender<Message>) {
                 let payload: Vec<u8> = extract_binary_payload_from_pmessage(message.data).unwrap();
                 if let Ok(deserialized) = bincode::deserialize::<TalkProtocol>(&payload) {
                     println!("[REDIS] Received  {:?}", deserialized);
-                    let _ = tx.send(Message::Binary(deserialized.serialize().unwrap().into()));
+                    let _ = tx.send(deserialized);
                 } else {
                     eprintln!("Failed to deserialize message from Redis");
                 }
@@ -88,57 +128,52 @@ pub async fn subscribe_to_redis_pattern(tx: TUnboundedSender<Message>) {
     }
 }
 
+/// Follows the channels of a single connection through the node's shared
+/// subscriber, leaving them once the connection is gone.
 pub async fn subscribe_to_redis(
-    tx: TUnboundedSender<Message>,
-    mut room_id_receiver: TUnboundedReceiver<(i32, Sender<()>)>,
+    subscriber: Subscriber,
+    tx: TUnboundedSender<TalkProtocol>,
+    mut subscription_receiver: TUnboundedReceiver<SubscriptionRequest>,
 ) {
-    println!("[REDIS] Subbing to redis");
-
-    // create one persistent redis connection for all rooms
-    let r = create_redis_async_pubsub_connection().await;
-    let (mut con, mut rx) = r.expect("Pubsub Connection");
-
-    // spawn background task to receive all messages
-    let tx_clone = tx.clone();
-    tokio::spawn(async move {
-        while let Some(message) = rx.recv().await {
-            println!("[REDIS] type {:?}", message);
-
-            match message.kind {
-                redis::PushKind::SMessage => {
-                    if let Some(payload) = extract_binary_payload_from_message(message.data) {
-                        if let Ok(deserialized) = bincode::deserialize::<TalkProtocol>(&payload) {
-                            println!("[REDIS] Received {:?}", deserialized);
-                            let _ = tx_clone
-                                .send(Message::Binary(deserialized.serialize().unwrap().into()));
-                        } else {
-                            eprintln!("Failed to deserialize message from Redis");
-                        }
-                    }
-                }
-                _ => println!("other"),
-            }
-        }
-    });
+    let id = subscriber.next_id();
 
-    // track currently active room
+    // track currently active room and account
     let mut current_room: Option<String> = None;
+    let mut current_user: Option<String> = None;
+    let mut current_account: Option<Uuid> = None;
+
+    // listen on channel for room and account changes
+    while let Some((subscription, ack)) = subscription_receiver.recv().await {
+        let (current, channel) = match subscription {
+            Subscription::Room(room_id) => (&mut current_room, room_id.map(|id| id.to_string())),
+            Subscription::User(uuid) => {
+                current_account = uuid;
+                subscriber.set_account(id, uuid);
+                (&mut current_user, uuid.map(user_channel))
+            }
+        };
 
-    // listen on channel for room changes
-    while let Some((room_id, ack)) = room_id_receiver.recv().await {
-        let channel = format!("{}", room_id);
-
-        // unsubscribe from old room if there was one
-        if let Some(old) = &current_room {
-            println!("[REDIS] Unsubscribing from {}", old);
-            let _ = con.sunsubscribe(old).await;
+        // leave the old channel if there was one
+        if let Some(old) = current.take()
+            && let Err(e) = subscriber.leave(&old, id).await
+        {
+            eprintln!("[REDIS] Leaving {} failed: {:?}", old, e);
         }
 
-        // subscribe to new room
-        println!("[REDIS] Subscribing to {}", channel);
-        con.ssubscribe(&channel).await.expect("SSUBSCRIBE failed");
+        let mut joined = Ok(());
+        if let Some(channel) = channel {
+            joined = subscriber
+                .join(&channel, id, tx.clone(), current_account)
+                .await;
+            match &joined {
+                Ok(()) => *current = Some(channel),
+                Err(e) => eprintln!("[REDIS] Joining {} failed: {:?}", channel, e),
+            }
+        }
+        let _ = ack.send(joined);
+    }
 
-        current_room = Some(channel);
-        let _ = ack.send(());
+    for channel in [current_room, current_user].into_iter().flatten() {
+        let _ = subscriber.leave(&channel, id).await;
     }
 }
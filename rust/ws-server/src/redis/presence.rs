@@ -0,0 +1,89 @@
+use super::SharedRedis;
+use anyhow::Result;
+use redis::Commands;
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// How long a member stays listed without a heartbeat from its connection.
+pub const PRESENCE_TTL: Duration = Duration::from_secs(60);
+
+/// How often connections refresh their presence, well within the TTL.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Every node writes to the same sorted set per room, scored by the time the
+/// entry expires. Clients that crash or lose their node simply stop being
+/// refreshed and drop out once their score lies in the past.
+fn presence_key(room_id: i32) -> String {
+    format!("presence:{}", room_id)
+}
+
+/// Entries are per connection, so an account connected twice stays present
+/// until both connections are gone.
+fn presence_member(uuid: Uuid, connection: Uuid) -> String {
+    format!("{}:{}", uuid, connection.simple())
+}
+
+/// Accounts of the entries, each once.
+fn accounts(members: &[String]) -> Vec<Uuid> {
+    let mut seen = HashSet::new();
+    members
+        .iter()
+        .filter_map(|member| member.split(':').next())
+        .filter_map(|uuid| Uuid::parse_str(uuid).ok())
+        .filter(|uuid| seen.insert(*uuid))
+        .collect()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Marks `uuid` as present in `room_id` through `connection` for another
+/// `PRESENCE_TTL`.
+pub async fn mark_present(
+    shared_redis: &SharedRedis,
+    room_id: i32,
+    uuid: Uuid,
+    connection: Uuid,
+) -> Result<()> {
+    let key = presence_key(room_id);
+    let expires_at = now_millis() + PRESENCE_TTL.as_millis() as u64;
+    let mut conn = shared_redis.lock().await;
+    let _: () = conn.zadd(&key, presence_member(uuid, connection), expires_at)?;
+    // Rooms nobody is in anymore clean up after themselves
+    let _: () = conn.pexpire(&key, PRESENCE_TTL.as_millis() as i64)?;
+    Ok(())
+}
+
+pub async fn remove_presence(
+    shared_redis: &SharedRedis,
+    room_id: i32,
+    uuid: Uuid,
+    connection: Uuid,
+) -> Result<()> {
+    let mut conn = shared_redis.lock().await;
+    let _: () = conn.zrem(presence_key(room_id), presence_member(uuid, connection))?;
+    Ok(())
+}
+
+/// How many people are present in `room_id`, without listing them.
+pub async fn present_count(shared_redis: &SharedRedis, room_id: i32) -> Result<usize> {
+    let now = now_millis();
+    let mut conn = shared_redis.lock().await;
+    let members: Vec<String> = conn.zrangebyscore(presence_key(room_id), now, "+inf")?;
+    Ok(accounts(&members).len())
+}
+
+/// Everybody whose presence in `room_id` has not expired yet.
+pub async fn present_members(shared_redis: &SharedRedis, room_id: i32) -> Result<Vec<Uuid>> {
+    let key = presence_key(room_id);
+    let now = now_millis();
+    let mut conn = shared_redis.lock().await;
+    let _: () = conn.zrembyscore(&key, "-inf", now)?;
+    let members: Vec<String> = conn.zrangebyscore(&key, now, "+inf")?;
+    Ok(accounts(&members))
+}
@@ -0,0 +1,278 @@
+use super::{create_redis_async_pubsub_connection, extract_binary_payload_from_message};
+use redis::cluster_async::ClusterConnection as ClusterConnectionAsync;
+use redis::{PushInfo, RedisResult, Value};
+use shared::TalkProtocol;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Mutex as TMutex;
+use tokio::sync::mpsc::{
+    UnboundedReceiver as TUnboundedReceiver, UnboundedSender as TUnboundedSender,
+};
+use uuid::Uuid;
+
+/// A connection listening on a channel.
+#[derive(Debug)]
+struct Listener {
+    tx: TUnboundedSender<TalkProtocol>,
+    /// Account logged in on the connection, to drop it from rooms it gets
+    /// kicked or banned from.
+    account: Option<Uuid>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    channels: HashMap<String, HashMap<u64, Listener>>,
+    /// Channels the node is subscribed to in Redis.
+    subscribed: HashSet<String>,
+}
+
+impl Registry {
+    /// Starts listening, returning whether the node has yet to subscribe.
+    fn add(&mut self, channel: &str, id: u64, listener: Listener) -> bool {
+        self.channels
+            .entry(channel.to_string())
+            .or_default()
+            .insert(id, listener);
+        !self.subscribed.contains(channel)
+    }
+
+    fn remove(&mut self, channel: &str, id: u64) {
+        if let Some(listeners) = self.channels.get_mut(channel) {
+            listeners.remove(&id);
+            if listeners.is_empty() {
+                self.channels.remove(channel);
+            }
+        }
+    }
+
+    /// Forgets `channel` once nobody listens, returning whether the node is
+    /// still subscribed to it and has to unsubscribe.
+    fn release(&mut self, channel: &str) -> bool {
+        let empty = self
+            .channels
+            .get(channel)
+            .is_none_or(|listeners| listeners.is_empty());
+        if empty {
+            self.channels.remove(channel);
+        }
+        empty && self.subscribed.remove(channel)
+    }
+
+    /// Hands `msg` to everybody listening on `channel`, dropping closed
+    /// connections and the `evicted` account. Returns whether nobody is left.
+    fn deliver(&mut self, channel: &str, msg: &TalkProtocol, evicted: Option<Uuid>) -> bool {
+        let Some(listeners) = self.channels.get_mut(channel) else {
+            return false;
+        };
+        listeners.retain(|_, listener| {
+            listener.tx.send(msg.clone()).is_ok()
+                && (evicted.is_none() || listener.account != evicted)
+        });
+        listeners.is_empty()
+    }
+}
+
+/// The one Redis subscriber of this node. Every channel is subscribed once,
+/// as long as at least one connection listens on it, and messages are fanned
+/// out to the listening connections from here.
+#[derive(Clone)]
+pub struct Subscriber {
+    /// Held while the registry's subscriptions change, so Redis always ends
+    /// up subscribed to exactly the channels someone listens on.
+    con: Arc<TMutex<ClusterConnectionAsync>>,
+    registry: Arc<Mutex<Registry>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Subscriber {
+    pub async fn connect() -> RedisResult<Self> {
+        let (con, rx) = create_redis_async_pubsub_connection().await?;
+        let subscriber = Subscriber {
+            con: Arc::new(TMutex::new(con)),
+            registry: Arc::new(Mutex::new(Registry::default())),
+            next_id: Arc::new(AtomicU64::new(0)),
+        };
+        tokio::spawn(subscriber.clone().dispatch(rx));
+        Ok(subscriber)
+    }
+
+    /// Identifies a connection in the registry.
+    pub fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Starts forwarding `channel` to `tx`, returning once Redis delivers it.
+    /// Nothing is forwarded if subscribing fails, joining again retries.
+    pub async fn join(
+        &self,
+        channel: &str,
+        id: u64,
+        tx: TUnboundedSender<TalkProtocol>,
+        account: Option<Uuid>,
+    ) -> RedisResult<()> {
+        let mut con = self.con.lock().await;
+        let first = self
+            .registry
+            .lock()
+            .unwrap()
+            .add(channel, id, Listener { tx, account });
+        if first {
+            println!("[REDIS] Subscribing to {}", channel);
+            let subscribed = con.ssubscribe(channel).await;
+            let mut registry = self.registry.lock().unwrap();
+            if let Err(e) = subscribed {
+                registry.remove(channel, id);
+                return Err(e);
+            }
+            registry.subscribed.insert(channel.to_string());
+        }
+        Ok(())
+    }
+
+    /// Stops forwarding `channel` to the connection, unsubscribing the node
+    /// once nobody listens anymore.
+    pub async fn leave(&self, channel: &str, id: u64) -> RedisResult<()> {
+        self.registry.lock().unwrap().remove(channel, id);
+        self.release(channel).await
+    }
+
+    /// Unsubscribes from `channel` if it has no listeners left.
+    async fn release(&self, channel: &str) -> RedisResult<()> {
+        let mut con = self.con.lock().await;
+        let unused = self.registry.lock().unwrap().release(channel);
+        if unused {
+            println!("[REDIS] Unsubscribing from {}", channel);
+            con.sunsubscribe(channel).await?;
+        }
+        Ok(())
+    }
+
+    /// Updates who is logged in on the connection, in every channel it
+    /// listens on.
+    pub fn set_account(&self, id: u64, account: Option<Uuid>) {
+        let mut registry = self.registry.lock().unwrap();
+        for listener in registry
+            .channels
+            .values_mut()
+            .filter_map(|listeners| listeners.get_mut(&id))
+        {
+            listener.account = account;
+        }
+    }
+
+    async fn dispatch(self, mut rx: TUnboundedReceiver<PushInfo>) {
+        while let Some(message) = rx.recv().await {
+            if message.kind != redis::PushKind::SMessage {
+                continue;
+            }
+            let Some(channel) = extract_channel(&message.data) else {
+                continue;
+            };
+            let Some(payload) = extract_binary_payload_from_message(message.data) else {
+                continue;
+            };
+            let Ok(deserialized) = bincode::deserialize::<TalkProtocol>(&payload) else {
+                eprintln!("Failed to deserialize message from Redis");
+                continue;
+            };
+            println!("[REDIS] Received on {} {:?}", channel, deserialized);
+
+            // kicked and banned accounts stop hearing from the room right
+            // away, on whatever node they are connected to
+            let evicted = match &deserialized {
+                TalkProtocol::Moderation {
+                    room_id,
+                    action,
+                    target,
+                    ..
+                } if action.evicts() && channel == room_id.to_string() => Some(target.uuid),
+                _ => None,
+            };
+
+            let emptied = self
+                .registry
+                .lock()
+                .unwrap()
+                .deliver(&channel, &deserialized, evicted);
+            if emptied {
+                let subscriber = self.clone();
+                tokio::spawn(async move {
+                    let _ = subscriber.release(&channel).await;
+                });
+            }
+        }
+    }
+}
+
+fn extract_channel(data: &[Value]) -> Option<String> {
+    // SMessage data format: [channel, binary_payload]
+    match data.first()? {
+        Value::BulkString(channel) => String::from_utf8(channel.clone()).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    fn listener(account: Option<Uuid>) -> (Listener, TUnboundedReceiver<TalkProtocol>) {
+        let (tx, rx) = unbounded_channel();
+        (Listener { tx, account }, rx)
+    }
+
+    #[test]
+    fn channels_are_subscribed_once_and_released_with_the_last_listener() {
+        let mut registry = Registry::default();
+        let (first, _first_rx) = listener(None);
+        let (second, _second_rx) = listener(None);
+        assert!(registry.add("1", 0, first));
+        registry.subscribed.insert("1".to_string());
+        assert!(!registry.add("1", 1, second));
+
+        registry.remove("1", 0);
+        assert!(!registry.release("1"));
+        registry.remove("1", 1);
+        assert!(registry.release("1"));
+        assert!(!registry.release("1"));
+        assert!(registry.channels.is_empty());
+    }
+
+    #[test]
+    fn the_next_join_retries_a_failed_subscription() {
+        let mut registry = Registry::default();
+        let (first, _first_rx) = listener(None);
+        let (second, _second_rx) = listener(None);
+        assert!(registry.add("1", 0, first));
+        // Redis refused, so `join` takes the listener back out
+        registry.remove("1", 0);
+        assert!(registry.channels.is_empty());
+        assert!(registry.add("1", 1, second));
+    }
+
+    #[test]
+    fn closed_connections_and_evicted_accounts_stop_listening() {
+        let mut registry = Registry::default();
+        let evicted = Uuid::new_v4();
+        let (staying, mut staying_rx) = listener(Some(Uuid::new_v4()));
+        let (closed, closed_rx) = listener(None);
+        let (kicked, mut kicked_rx) = listener(Some(evicted));
+        drop(closed_rx);
+        registry.add("1", 0, staying);
+        registry.add("1", 1, closed);
+        registry.add("1", 2, kicked);
+
+        let msg = TalkProtocol::ListRooms;
+        assert!(!registry.deliver("1", &msg, Some(evicted)));
+        assert!(staying_rx.try_recv().is_ok());
+        // The account still hears why it is gone
+        assert!(kicked_rx.try_recv().is_ok());
+        assert_eq!(registry.channels["1"].len(), 1);
+
+        drop(staying_rx);
+        assert!(registry.deliver("1", &msg, None));
+        assert!(!registry.deliver("2", &msg, None));
+    }
+}
@@ -0,0 +1,175 @@
+use crate::database::{pool::PgPool, queries::*};
+use anyhow::Result;
+use shared::Retention;
+use std::collections::HashMap;
+use std::env;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often all rooms are checked for expired messages.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Rows deleted per statement, few enough to keep every lock short.
+const PRUNE_BATCH_SIZE: i64 = 500;
+
+/// Gives inserts and readers their turn between two batches.
+const BATCH_PAUSE: Duration = Duration::from_millis(50);
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Limits for every room, configured as `RETENTION_MAX_AGE_DAYS` and
+/// `RETENTION_MAX_MESSAGES`. Unset keeps everything.
+pub fn global() -> Retention {
+    static GLOBAL: OnceLock<Retention> = OnceLock::new();
+    *GLOBAL.get_or_init(|| {
+        let positive = |name: &str| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .filter(|value| *value > 0)
+        };
+        Retention {
+            max_age_secs: positive("RETENTION_MAX_AGE_DAYS")
+                .map(|days| days.saturating_mul(SECS_PER_DAY)),
+            max_messages: positive("RETENTION_MAX_MESSAGES"),
+        }
+    })
+}
+
+/// The stricter of both limits, each on its own.
+fn effective(room: Retention, global: Retention) -> Retention {
+    fn stricter(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+    Retention {
+        max_age_secs: stricter(room.max_age_secs, global.max_age_secs),
+        max_messages: stricter(room.max_messages, global.max_messages),
+    }
+}
+
+/// Runs for the lifetime of the server. Every node prunes, batches skip rows
+/// another node is already deleting.
+pub async fn prune_periodically(pg_pool: PgPool) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = prune(&pg_pool).await {
+            eprintln!("[RETENTION] Pruning failed: {:?}", e);
+        }
+    }
+}
+
+async fn prune(pg_pool: &PgPool) -> Result<()> {
+    let (room_ids, own) = pg_pool
+        .run(|conn| {
+            let own: HashMap<i32, Retention> = get_rooms_with_retention(conn)?
+                .into_iter()
+                .map(|room| {
+                    let retention = Retention {
+                        max_age_secs: room.retention_max_age_secs.map(|secs| secs as u64),
+                        max_messages: room.retention_max_messages.map(|count| count as u64),
+                    };
+                    (room.id, retention)
+                })
+                .collect();
+            Ok((get_message_room_ids(conn)?, own))
+        })
+        .await?;
+
+    for room_id in room_ids {
+        let retention = effective(own.get(&room_id).copied().unwrap_or_default(), global());
+        let pruned = prune_room(room_id, retention, pg_pool).await?;
+        if pruned > 0 {
+            println!("[RETENTION] Pruned {} messages of room {}", pruned, room_id);
+        }
+    }
+    Ok(())
+}
+
+async fn prune_room(room_id: i32, retention: Retention, pg_pool: &PgPool) -> Result<usize> {
+    if retention == Retention::default() {
+        return Ok(0);
+    }
+    let older_than = retention.max_age_secs.map(|secs| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        now.saturating_sub((secs as i64).saturating_mul(1000))
+    });
+    // Fixed up front, so messages posted meanwhile don't move the cut
+    let up_to_seq = match retention.max_messages {
+        Some(keep) => {
+            let keep = keep.min(i64::MAX as u64) as i64;
+            pg_pool
+                .run(|conn| Ok(get_seq_beyond(conn, room_id, keep)?))
+                .await?
+        }
+        None => None,
+    };
+    if older_than.is_none() && up_to_seq.is_none() {
+        return Ok(0);
+    }
+
+    let mut pruned = 0;
+    loop {
+        let deleted = pg_pool
+            .run(|conn| {
+                Ok(prune_messages(
+                    conn,
+                    room_id,
+                    older_than,
+                    up_to_seq,
+                    PRUNE_BATCH_SIZE,
+                )?)
+            })
+            .await?;
+        pruned += deleted;
+        if (deleted as i64) < PRUNE_BATCH_SIZE {
+            return Ok(pruned);
+        }
+        tokio::time::sleep(BATCH_PAUSE).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retention(max_age_secs: Option<u64>, max_messages: Option<u64>) -> Retention {
+        Retention {
+            max_age_secs,
+            max_messages,
+        }
+    }
+
+    #[test]
+    fn unset_limits_keep_everything() {
+        assert_eq!(
+            effective(Retention::default(), Retention::default()),
+            Retention::default()
+        );
+    }
+
+    #[test]
+    fn a_limit_set_on_one_side_applies() {
+        assert_eq!(
+            effective(retention(Some(60), None), retention(None, Some(100))),
+            retention(Some(60), Some(100))
+        );
+    }
+
+    #[test]
+    fn the_stricter_limit_wins_each_on_its_own() {
+        assert_eq!(
+            effective(
+                retention(Some(60), Some(1000)),
+                retention(Some(3600), Some(100))
+            ),
+            retention(Some(60), Some(100))
+        );
+    }
+}
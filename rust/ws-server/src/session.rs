@@ -0,0 +1,154 @@
+use crate::error::RequestError;
+use shared::{
+    Capabilities, ErrorCode, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, TalkProtocol,
+    is_supported_version,
+};
+use uuid::Uuid;
+
+/// The account a connection is logged in as.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub uuid: Uuid,
+    pub username: String,
+    /// May edit and delete messages of other users.
+    pub is_moderator: bool,
+}
+
+/// Per-connection state negotiated with the client.
+#[derive(Debug, Default)]
+pub struct Session {
+    pub protocol_version: Option<u16>,
+    pub capabilities: Capabilities,
+    pub identity: Option<Identity>,
+    /// Id of the stored session the client logged in with, if any.
+    pub token_id: Option<Uuid>,
+    pub room: Option<i32>,
+    /// Tells the connection apart from other connections of the same
+    /// account, in presence and the `users` table.
+    pub connection: Uuid,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            connection: Uuid::new_v4(),
+            ..Self::default()
+        }
+    }
+
+    pub fn is_negotiated(&self) -> bool {
+        self.protocol_version.is_some()
+    }
+
+    /// Revision whose shapes the client speaks, the current one until the
+    /// handshake says otherwise.
+    pub fn wire_version(&self) -> u16 {
+        self.protocol_version.unwrap_or(PROTOCOL_VERSION)
+    }
+
+    /// Answers a client's `Hello` with either a `Welcome` or the `Error` to
+    /// send before closing the connection.
+    ///
+    /// Newer clients are downgraded to our version, older ones are accepted
+    /// as long as they are not below `MIN_PROTOCOL_VERSION`.
+    pub fn negotiate(
+        &mut self,
+        version: u16,
+        capabilities: Capabilities,
+    ) -> Result<TalkProtocol, RequestError> {
+        if self.is_negotiated() {
+            return Err(RequestError::new(
+                ErrorCode::ProtocolViolation,
+                "Handshake was already completed",
+            ));
+        }
+        if !is_supported_version(version) {
+            return Err(unsupported_version(version));
+        }
+
+        let version = version.min(PROTOCOL_VERSION);
+        let capabilities = capabilities.intersection(Capabilities::supported());
+        self.protocol_version = Some(version);
+        self.capabilities = capabilities;
+
+        Ok(TalkProtocol::Welcome {
+            version,
+            capabilities,
+        })
+    }
+
+    /// Accepts a client that skipped the handshake, if revision 0 is still
+    /// supported.
+    pub fn negotiate_legacy(&mut self) -> Result<(), RequestError> {
+        if !is_supported_version(0) {
+            return Err(unsupported_version(0));
+        }
+        self.protocol_version = Some(0);
+        self.capabilities = Capabilities::LEGACY;
+        Ok(())
+    }
+
+    /// Returns the logged in identity, making sure the client does not claim
+    /// to be somebody else.
+    pub fn authorize(&self, claimed_uuid: Option<Uuid>) -> Result<Identity, RequestError> {
+        let Some(identity) = &self.identity else {
+            return Err(RequestError::new(
+                ErrorCode::Unauthorized,
+                "You need to /login or /register first",
+            ));
+        };
+        if claimed_uuid.is_some_and(|uuid| uuid != identity.uuid) {
+            return Err(RequestError::new(
+                ErrorCode::Unauthorized,
+                "You can only act as yourself",
+            ));
+        }
+        Ok(identity.clone())
+    }
+
+    /// Makes sure the client joined `room_id` before acting in it.
+    pub fn ensure_in_room(&self, room_id: i32) -> Result<(), RequestError> {
+        if self.room != Some(room_id) {
+            return Err(RequestError::new(
+                ErrorCode::NotInRoom,
+                format!("You are not in room {}", room_id),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Leaves the room if `msg` kicks or bans this account from it, returning
+    /// the room that was left. The subscriber has stopped listening already.
+    pub fn evicted_by(&mut self, msg: &TalkProtocol) -> Option<i32> {
+        let TalkProtocol::Moderation {
+            room_id,
+            action,
+            target,
+            ..
+        } = msg
+        else {
+            return None;
+        };
+        let uuid = self.identity.as_ref()?.uuid;
+        if !action.evicts() || target.uuid != uuid || self.room != Some(*room_id) {
+            return None;
+        }
+        self.room = None;
+        Some(*room_id)
+    }
+
+    /// Whether the client negotiated everything needed to decode `msg`.
+    pub fn accepts(&self, msg: &TalkProtocol) -> bool {
+        self.capabilities.contains(msg.required_capability())
+    }
+}
+
+fn unsupported_version(version: u16) -> RequestError {
+    RequestError::new(
+        ErrorCode::UnsupportedVersion,
+        format!(
+            "Protocol version {} is not supported, the server requires at least {}",
+            version, MIN_PROTOCOL_VERSION
+        ),
+    )
+}
@@ -0,0 +1,60 @@
+use std::env;
+use std::time::Duration;
+use tokio::signal;
+
+/// Docker kills a container 10 seconds after asking it to stop.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// Suggested to clients before they reconnect, in milliseconds. Long enough
+/// for the load balancer to notice the node is gone.
+pub const RECONNECT_AFTER_MILLIS: u64 = 2000;
+
+/// How long connections may take to drain before they are dropped,
+/// configured as `SHUTDOWN_TIMEOUT_SECS`.
+pub fn timeout() -> Duration {
+    parse_timeout(env::var("SHUTDOWN_TIMEOUT_SECS").ok())
+}
+
+fn parse_timeout(configured: Option<String>) -> Duration {
+    configured
+        .and_then(|secs| secs.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TIMEOUT)
+}
+
+/// Resolves on ctrl-c or SIGTERM, which `docker compose` sends on restarts.
+pub async fn signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("Failed to listen for ctrl-c");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeout_falls_back_to_the_default() {
+        assert_eq!(
+            parse_timeout(Some("30".to_string())),
+            Duration::from_secs(30)
+        );
+        assert_eq!(parse_timeout(Some("0".to_string())), Duration::ZERO);
+        assert_eq!(parse_timeout(Some("soon".to_string())), DEFAULT_TIMEOUT);
+        assert_eq!(parse_timeout(None), DEFAULT_TIMEOUT);
+    }
+}
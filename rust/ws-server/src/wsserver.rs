@@ -1,280 +1,2020 @@
+use crate::auth::{
+    MIN_PASSWORD_LENGTH, hash_password, resumable, session_ttl_secs, sign_token, verify_password,
+    verify_token,
+};
+use crate::codec::{Frame, MAX_REJECTED_FRAMES, decode_frame, malformed, websocket_config};
 use crate::database::{
-    connection::establish_connection,
-    models::{NewMessage, NewUser},
+    models::{
+        Account, DirectMessageRecord, InviteRecord, Message as StoredMessage, NewAccount,
+        NewDirectMessage, NewMessage, NewMessageEdit, NewRoom, NewRoomMember, NewSessionRecord,
+        NewUser, ReactionRecord, RoomRecord, SanctionRecord,
+    },
+    pool::PgPool,
     queries::*,
 };
+use crate::error::{RequestError, error_reply};
+use crate::ratelimit::{self, ConnectionLimiter, check_slow_mode};
+use crate::redis::presence::{
+    HEARTBEAT_INTERVAL, mark_present, present_count, present_members, remove_presence,
+};
 use crate::redis::*;
-use anyhow::Result;
+use crate::retention;
+use crate::session::{Identity, Session};
+use crate::shutdown::{self, RECONNECT_AFTER_MILLIS};
+use anyhow::{Result, bail};
 use diesel::PgConnection;
-use futures_util::{SinkExt, StreamExt, stream::TryStreamExt};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use futures_util::{SinkExt, StreamExt, stream::SplitSink};
 use redis::Commands;
-use shared::{TalkMessage, TalkProtocol};
-use std::{env, net::SocketAddr, sync::Arc};
+use shared::{
+    Capabilities, DirectMessage, ErrorCode, MAX_FETCH_LIMIT, MAX_MESSAGE_LENGTH,
+    MAX_REACTION_LENGTH, MAX_ROOM_NAME_LENGTH, MAX_SEARCH_LIMIT, MAX_SLOW_MODE_SECS,
+    MAX_TOPIC_LENGTH, MAX_USERNAME_LENGTH, Member, ModerationAction, Reaction, Recipient,
+    Retention, Room, RoomRole, RoomSummary, SearchHit, TalkMessage, TalkProtocol, Visibility,
+};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio::sync::Mutex as TMutex;
-use tokio::sync::mpsc::UnboundedSender;
-use tokio::sync::{mpsc::unbounded_channel, oneshot};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::{mpsc::unbounded_channel, oneshot, watch};
+use tokio::task::JoinSet;
 use tokio::{
     net::{TcpListener, TcpStream},
     runtime::Handle,
 };
-use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, frame::coding::CloseCode};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
 use uuid::Uuid;
 
-type SharedPostgres = Arc<TMutex<PgConnection>>;
+type WsSink = SplitSink<WebSocketStream<TcpStream>, Message>;
+
+// `protocol_type` of stored rows, as returned by `TalkProtocol::to_i16`.
+const JOINED_PROTOCOL_TYPE: i16 = 0;
+const LEFT_PROTOCOL_TYPE: i16 = 1;
+const POST_PROTOCOL_TYPE: i16 = 4;
+
+/// How long an invite code can be redeemed, in milliseconds.
+const INVITE_TTL_MILLIS: u64 = 24 * 60 * 60 * 1000;
+/// Hex digits of a random uuid, short enough to be typed.
+const INVITE_CODE_LENGTH: usize = 12;
+
+// `kind` of stored sanctions.
+const BAN_SANCTION: i16 = 0;
+const MUTE_SANCTION: i16 = 1;
+
+/// Rank of server-wide moderators, above the owner of any room.
+const GLOBAL_MODERATOR_RANK: i16 = 3;
 
 pub async fn handle_connection(
     raw_stream: TcpStream,
     addr: SocketAddr,
     shared_redis: SharedRedis,
-    pg_conn: SharedPostgres,
+    async_redis: AsyncRedis,
+    subscriber: Subscriber,
+    pg_pool: PgPool,
+    mut shutdown: watch::Receiver<bool>,
 ) -> Result<()> {
     println!("Incoming TCP connection from: {}", addr);
 
-    let ws_stream = tokio_tungstenite::accept_async(raw_stream).await?;
+    let ws_stream =
+        tokio_tungstenite::accept_async_with_config(raw_stream, Some(websocket_config())).await?;
     println!("WebSocket connection established: {}", addr);
 
-    let (tx, mut rx) = unbounded_channel();
-    let (room_tx, room_rx) = unbounded_channel::<(i32, oneshot::Sender<()>)>();
-
-    let (mut outgoing, incoming) = ws_stream.split();
-
-    // Spawn Redis subscriber
-    tokio::spawn(subscribe_to_redis(tx.clone(), room_rx));
-
-    // Process incoming messages
-    let message_handler = async {
-        incoming
-            .try_for_each(|msg| async {
-                let deserialize_msg: TalkProtocol =
-                    bincode::deserialize(&msg.into_data()).expect("deserializing");
-                let _ = handle_message(
-                    deserialize_msg,
-                    &room_tx,
-                    tx.clone(),
-                    &shared_redis,
-                    &pg_conn,
-                )
-                .await;
-                Ok(())
-            })
-            .await
-    };
+    let (tx, mut rx) = unbounded_channel::<TalkProtocol>();
+    let (subscription_tx, subscription_rx) = unbounded_channel::<SubscriptionRequest>();
+
+    let (mut outgoing, mut incoming) = ws_stream.split();
+    let mut session = Session::new();
+    let mut limiter = ConnectionLimiter::new();
+    let mut rejected_frames = 0;
+
+    // Follow this connection's channels through the node's subscriber
+    tokio::spawn(subscribe_to_redis(subscriber, tx.clone(), subscription_rx));
+
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+
+    // However the connection ends, it must not linger in its room
+    let result = async {
+        loop {
+            tokio::select! {
+                // Process incoming messages
+                frame = incoming.next() => {
+                    let frame = match frame {
+                        None => break,
+                        Some(Ok(frame)) => frame,
+                        Some(Err(WsError::Capacity(e))) => {
+                            let _ = reject(&mut outgoing, &session, malformed(e.to_string())).await;
+                            break;
+                        }
+                        Some(Err(e)) => return Err(e.into()),
+                    };
+                    let deserialize_msg = match decode_frame(frame, session.wire_version()) {
+                        Ok(Frame::Protocol(msg)) => msg,
+                        Ok(Frame::Control) => continue,
+                        Ok(Frame::Close) => break,
+                        Err(error) => {
+                            rejected_frames += 1;
+                            eprintln!(
+                                "[SERVER] Rejected frame #{} from {}: {:?}",
+                                rejected_frames, addr, error
+                            );
+                            if rejected_frames >= MAX_REJECTED_FRAMES {
+                                let error = malformed("Too many malformed frames".to_string());
+                                reject(&mut outgoing, &session, error).await?;
+                                break;
+                            }
+                            outgoing.send(encode(&error.into(), &session)?).await?;
+                            continue;
+                        }
+                    };
+
+                    if let TalkProtocol::Hello { version, capabilities } = deserialize_msg {
+                        match session.negotiate(version, capabilities) {
+                            Ok(welcome) => outgoing.send(encode(&welcome, &session)?).await?,
+                            Err(error) => {
+                                reject(&mut outgoing, &session, error).await?;
+                                break;
+                            }
+                        }
+                        continue;
+                    }
+                    if !session.is_negotiated()
+                        && let Err(error) = session.negotiate_legacy()
+                    {
+                        reject(&mut outgoing, &session, error).await?;
+                        break;
+                    }
 
-    // Forward Redis messages to WebSocket
-    let redis_forwarder = async {
-        while let Some(msg) = rx.recv().await {
-            outgoing.send(msg).await?;
+                    let account = session.identity.as_ref().map(|identity| identity.uuid);
+                    if let Err(error) =
+                        ratelimit::check(&mut limiter, account, &deserialize_msg, &async_redis).await
+                    {
+                        let _ = tx.send(error_reply(error));
+                        continue;
+                    }
+
+                    if let Err(error) = handle_message(
+                        deserialize_msg,
+                        &mut session,
+                        &subscription_tx,
+                        tx.clone(),
+                        &shared_redis,
+                        &async_redis,
+                        &pg_pool,
+                    )
+                    .await
+                    {
+                        let _ = tx.send(error_reply(error));
+                    }
+                }
+                // Forward Redis messages to WebSocket
+                Some(msg) = rx.recv() => {
+                    // Kicked or banned, leave the room as if the client asked to
+                    if let Some(room_id) = session.evicted_by(&msg)
+                        && let Some(identity) = &session.identity
+                        && let Err(e) =
+                            leave_room(identity, session.connection, room_id, &shared_redis, &pg_pool).await
+                    {
+                        eprintln!("[SERVER] Could not leave room {} after eviction: {:?}", room_id, e);
+                    }
+                    if session.accepts(&msg) {
+                        outgoing.send(encode(&msg, &session)?).await?;
+                    }
+                }
+                // Keep our presence in the current room from expiring
+                _ = heartbeat.tick() => {
+                    if let (Some(room_id), Some(identity)) = (session.room, &session.identity)
+                        && let Err(e) =
+                            mark_present(&shared_redis, room_id, identity.uuid, session.connection).await
+                    {
+                        eprintln!("[SERVER] Presence heartbeat failed: {:?}", e);
+                    }
+                }
+                // The server is stopping, send the client on its way
+                _ = shutdown.changed() => {
+                    drain(&mut outgoing, &mut rx, &mut session, &shared_redis, &pg_pool).await?;
+                    break;
+                }
+            }
         }
-        Ok(())
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+
+    if let (Some(room_id), Some(identity)) = (session.room, &session.identity) {
+        let _ = remove_presence(&shared_redis, room_id, identity.uuid, session.connection).await;
+        let _ = delete_user(&pg_pool, session.connection).await;
+    }
+    println!("{} disconnected", addr);
+    result
+}
+
+/// Lets the room know the user left, then sends what is still queued for the
+/// client and the shutdown notice and closes the connection.
+async fn drain(
+    outgoing: &mut WsSink,
+    rx: &mut UnboundedReceiver<TalkProtocol>,
+    session: &mut Session,
+    shared_redis: &SharedRedis,
+    pg_pool: &PgPool,
+) -> Result<()> {
+    // First, the client may well be gone already. If leaving fails, the
+    // cleanup after the connection loop still takes care of the room.
+    if let (Some(room_id), Some(identity)) = (session.room, &session.identity) {
+        match leave_room(identity, session.connection, room_id, shared_redis, pg_pool).await {
+            Ok(()) => session.room = None,
+            Err(e) => eprintln!(
+                "[SERVER] Could not leave room {} on shutdown: {:?}",
+                room_id, e
+            ),
+        }
+    }
+
+    while let Ok(msg) = rx.try_recv() {
+        if session.accepts(&msg) {
+            outgoing.feed(encode(&msg, session)?).await?;
+        }
+    }
+    let notice = TalkProtocol::ServerShuttingDown {
+        reconnect_after: RECONNECT_AFTER_MILLIS,
     };
+    if session.accepts(&notice) {
+        outgoing.feed(encode(&notice, session)?).await?;
+    }
 
-    // Run both tasks concurrently
-    tokio::select! {
-        result = message_handler => result,
-        result = redis_forwarder => result,
-    }?;
+    outgoing
+        .send(Message::Close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: "Server shutting down".into(),
+        })))
+        .await?;
+    Ok(())
+}
 
-    println!("{} disconnected", addr);
+/// `msg` as it may appear in the log, without passwords and session tokens.
+fn redacted(msg: &TalkProtocol) -> Cow<'_, TalkProtocol> {
+    const REDACTED: &str = "<redacted>";
+    match msg {
+        TalkProtocol::Register { username, .. } => Cow::Owned(TalkProtocol::Register {
+            username: username.clone(),
+            password: REDACTED.to_string(),
+        }),
+        TalkProtocol::Login { username, .. } => Cow::Owned(TalkProtocol::Login {
+            username: username.clone(),
+            password: REDACTED.to_string(),
+        }),
+        TalkProtocol::Resume { .. } => Cow::Owned(TalkProtocol::Resume {
+            token: REDACTED.to_string(),
+        }),
+        _ => Cow::Borrowed(msg),
+    }
+}
+
+/// Encodes `msg` in the shapes of the revision the client negotiated.
+fn encode(msg: &TalkProtocol, session: &Session) -> Result<Message> {
+    Ok(Message::Binary(
+        msg.serialize_for(session.wire_version())?.into(),
+    ))
+}
+
+/// Sends a final `Error` and closes the connection with a policy violation.
+async fn reject(outgoing: &mut WsSink, session: &Session, error: RequestError) -> Result<()> {
+    let mut reason = error.message.clone();
+    // Close frames only have room for 123 bytes of reason
+    while reason.len() > 123 {
+        reason.pop();
+    }
+    outgoing.send(encode(&error.into(), session)?).await?;
+    outgoing
+        .send(Message::Close(Some(CloseFrame {
+            code: CloseCode::Policy,
+            reason: reason.into(),
+        })))
+        .await?;
     Ok(())
 }
 
 async fn handle_message(
     msg: TalkProtocol,
-    room_tx: &UnboundedSender<(i32, oneshot::Sender<()>)>,
-    tx: UnboundedSender<Message>,
+    session: &mut Session,
+    subscription_tx: &UnboundedSender<SubscriptionRequest>,
+    tx: UnboundedSender<TalkProtocol>,
     shared_redis: &SharedRedis,
-    pg_conn: &SharedPostgres,
+    async_redis: &AsyncRedis,
+    pg_pool: &PgPool,
 ) -> Result<()> {
-    println!("[SERVER] Received {:?}", msg);
-    match &msg {
-        TalkProtocol::JoinRoom {
-            room_id,
-            uuid,
-            username,
-            unixtime,
-        } => {
-            handle_join(room_id, room_tx).await?;
+    println!("[SERVER] Received {:?}", redacted(&msg));
 
-            let response = TalkProtocol::UserJoined {
-                uuid: *uuid,
-                username: username.clone(),
-                room_id: *room_id,
-                unixtime: *unixtime,
-            };
-            publish_message(shared_redis, &response, room_id).await?;
-            persist_user(pg_conn, room_id, uuid).await?;
+    // Everything but logging in requires an account, and the uuid a client
+    // claims is never trusted over the one bound to its session.
+    let identity = match &msg {
+        TalkProtocol::Register { .. }
+        | TalkProtocol::Login { .. }
+        | TalkProtocol::Resume { .. } => None,
+        _ => Some(session.authorize(claimed_uuid(&msg))?),
+    };
 
-            let message_to_persist = TalkMessage {
-                uuid: *uuid,
-                username: username.clone(),
-                text: "".to_string(),
-                room_id: *room_id,
-                unixtime: *unixtime,
-            };
-            persist_message(
-                pg_conn,
-                &message_to_persist,
-                response.to_i16().expect("TalkProtocol type conversion"),
+    match &msg {
+        // Events are persisted before they are published, so everybody sees
+        // the time and sequence number the server assigned.
+        TalkProtocol::JoinRoom { room_id, .. } => {
+            let identity = identity.expect("authorized");
+            // Private rooms are checked before the connection ever hears
+            // anything from the room's channel.
+            let room = accessible_room(*room_id, &identity, pg_pool).await?;
+            ensure_not_sanctioned(*room_id, &identity, BAN_SANCTION, pg_pool).await?;
+            subscribe(subscription_tx, Subscription::Room(Some(*room_id))).await?;
+            if let Some(old_room) = session.room.filter(|old_room| old_room != room_id) {
+                remove_presence(shared_redis, old_room, identity.uuid, session.connection).await?;
+            }
+            session.room = Some(*room_id);
+            mark_present(shared_redis, *room_id, identity.uuid, session.connection).await?;
+            if let Some(room) = room {
+                let _ = tx.send(TalkProtocol::RoomInfo { room });
+            }
+            persist_user(pg_pool, *room_id, identity.uuid, session.connection).await?;
+
+            let message = persist_message(
+                pg_pool,
+                &room_event(&identity, *room_id),
+                JOINED_PROTOCOL_TYPE,
             )
             .await?;
-        }
-        TalkProtocol::LeaveRoom {
-            room_id,
-            uuid,
-            unixtime,
-            username,
-        } => {
-            let response = TalkProtocol::UserLeft {
-                uuid: *uuid,
-                username: username.clone(),
-                room_id: *room_id,
-                unixtime: *unixtime,
+            let response = TalkProtocol::UserJoined {
+                id: message.id,
+                uuid: message.uuid,
+                username: message.username,
+                room_id: message.room_id,
+                unixtime: message.unixtime,
+                seq: message.seq,
             };
             publish_message(shared_redis, &response, room_id).await?;
-            delete_user(pg_conn, uuid).await?;
-
-            let message_to_persist = TalkMessage {
-                uuid: *uuid,
-                username: username.clone(),
-                text: "".to_string(),
-                room_id: *room_id,
-                unixtime: *unixtime,
-            };
-            persist_message(
-                pg_conn,
-                &message_to_persist,
-                response.to_i16().expect("TalkProtocol type conversion"),
+        }
+        TalkProtocol::LeaveRoom { room_id, .. } => {
+            let identity = identity.expect("authorized");
+            session.ensure_in_room(*room_id)?;
+            session.room = None;
+            leave_room(
+                &identity,
+                session.connection,
+                *room_id,
+                shared_redis,
+                pg_pool,
             )
             .await?;
         }
         TalkProtocol::PostMessage { message } => {
-            publish_message(shared_redis, &msg, &message.room_id).await?;
-            persist_message(
-                pg_conn,
-                message,
-                msg.to_i16().expect("TalkProtocol type conversion"),
-            )
-            .await?;
+            let identity = identity.expect("authorized");
+            session.ensure_in_room(message.room_id)?;
+            ensure_not_sanctioned(message.room_id, &identity, MUTE_SANCTION, pg_pool).await?;
+            enforce_slow_mode(message.room_id, &identity, async_redis, pg_pool).await?;
+            validate_message_text(&message.text)?;
+            if let Some(reply_to) = message.reply_to {
+                ensure_reply_target(pg_pool, reply_to, message.room_id).await?;
+            }
+            let message = TalkMessage {
+                uuid: identity.uuid,
+                username: identity.username,
+                ..message.clone()
+            };
+            let message = persist_message(pg_pool, &message, POST_PROTOCOL_TYPE).await?;
+            let room_id = message.room_id;
+            let response = TalkProtocol::PostMessage { message };
+            publish_message(shared_redis, &response, &room_id).await?;
         }
         TalkProtocol::Fetch {
             room_id,
             limit,
-            fetch_before,
+            before_id,
+            after_id,
         } => {
-            let messages = handle_fetch(room_id, limit, fetch_before, pg_conn).await?;
-            let response = TalkProtocol::History { text: messages };
-            let _ = tx.send(Message::Binary(response.serialize().unwrap().into()));
-        }
-        TalkProtocol::ChangeName {
-            uuid,
-            username,
-            unixtime,
-            old_username,
-        } => {
-            let response = TalkProtocol::UsernameChanged {
-                uuid: *uuid,
-                username: username.clone(),
-                old_username: old_username.clone(),
-                unixtime: *unixtime,
+            session.ensure_in_room(*room_id)?;
+            let (messages, has_more) =
+                handle_fetch(room_id, limit, *before_id, *after_id, pg_pool).await?;
+            let response = TalkProtocol::History {
+                text: messages,
+                has_more,
+                before_id: *before_id,
+                after_id: *after_id,
             };
+            let _ = tx.send(response);
+        }
+        TalkProtocol::ChangeName { username, .. } => {
+            let identity = identity.expect("authorized");
+            validate_username(username)?;
+            pg_pool
+                .run(|conn| {
+                    ensure_name_free(conn, username, identity.uuid)?;
+                    Ok(update_display_name(conn, identity.uuid, username)?)
+                })
+                .await?;
+            session.identity = Some(Identity {
+                username: username.clone(),
+                ..identity.clone()
+            });
 
-            let room_id = {
-                let mut conn = pg_conn.lock().await;
-                get_room_id_by_uuid(&mut conn, *uuid)
-                    .ok()
-                    .and_then(|results| results.first().map(|r| r.room_id))
+            let response = TalkProtocol::UsernameChanged {
+                uuid: identity.uuid,
+                username: username.clone(),
+                old_username: identity.username,
+                unixtime: unix_timestamp_millis(),
             };
 
-            if let Some(room_id) = room_id {
+            let room_ids = pg_pool
+                .run(|conn| Ok(get_room_ids_by_uuid(conn, identity.uuid)?))
+                .await?;
+            for room_id in room_ids {
                 publish_message(shared_redis, &response, &room_id).await?;
             }
         }
+        TalkProtocol::Register { username, password } => {
+            handle_register(username, password, session, &tx, pg_pool).await?;
+            subscribe_user(session, subscription_tx).await?;
+        }
+        TalkProtocol::Login { username, password } => {
+            handle_login(username, password, session, &tx, pg_pool).await?;
+            subscribe_user(session, subscription_tx).await?;
+        }
+        TalkProtocol::Resume { token } => {
+            handle_resume(token, session, &tx, pg_pool).await?;
+            subscribe_user(session, subscription_tx).await?;
+        }
+        TalkProtocol::Logout => {
+            handle_logout(session, subscription_tx, shared_redis, pg_pool).await?;
+            subscribe_user(session, subscription_tx).await?;
+        }
+        TalkProtocol::SendDirect { recipient, text } => {
+            let identity = identity.expect("authorized");
+            validate_message_text(text)?;
+            let message = handle_send_direct(recipient, text, &identity, pg_pool).await?;
+            let (sender, recipient) = (message.sender, message.recipient);
+            let response = TalkProtocol::DirectMessage { message };
+            publish_direct(shared_redis, &response, recipient).await?;
+            if sender != recipient {
+                publish_direct(shared_redis, &response, sender).await?;
+            }
+        }
+        TalkProtocol::FetchDirect { before_id, limit } => {
+            let identity = identity.expect("authorized");
+            let (messages, has_more) =
+                handle_fetch_direct(*before_id, limit, &identity, pg_pool).await?;
+            let _ = tx.send(TalkProtocol::DirectHistory { messages, has_more });
+        }
+        TalkProtocol::EditMessage { message_id, text } => {
+            let identity = identity.expect("authorized");
+            validate_message_text(text)?;
+            let (room_id, response) =
+                handle_edit(*message_id, text, &identity, session, pg_pool).await?;
+            publish_message(shared_redis, &response, &room_id).await?;
+        }
+        TalkProtocol::DeleteMessage { message_id } => {
+            let identity = identity.expect("authorized");
+            let (room_id, response) =
+                handle_delete(*message_id, &identity, session, pg_pool).await?;
+            publish_message(shared_redis, &response, &room_id).await?;
+        }
+        TalkProtocol::Typing { room_id, .. } => {
+            let identity = identity.expect("authorized");
+            session.ensure_in_room(*room_id)?;
+            let response = TalkProtocol::Typing {
+                room_id: *room_id,
+                uuid: identity.uuid,
+                username: identity.username,
+            };
+            publish_message(shared_redis, &response, room_id).await?;
+        }
+        TalkProtocol::CreateRoom {
+            name,
+            topic,
+            visibility,
+        } => {
+            let identity = identity.expect("authorized");
+            let room = handle_create_room(name, topic, *visibility, &identity, pg_pool).await?;
+            let _ = tx.send(TalkProtocol::RoomInfo { room });
+        }
+        TalkProtocol::SetTopic { room_id, topic } => {
+            let identity = identity.expect("authorized");
+            session.ensure_in_room(*room_id)?;
+            let room = handle_set_topic(*room_id, topic, &identity, pg_pool).await?;
+            publish_message(shared_redis, &TalkProtocol::RoomInfo { room }, room_id).await?;
+        }
+        TalkProtocol::SetSlowMode { room_id, seconds } => {
+            let identity = identity.expect("authorized");
+            session.ensure_in_room(*room_id)?;
+            let room = handle_set_slow_mode(*room_id, *seconds, &identity, pg_pool).await?;
+            publish_message(shared_redis, &TalkProtocol::RoomInfo { room }, room_id).await?;
+        }
+        TalkProtocol::SetRetention { room_id, retention } => {
+            let identity = identity.expect("authorized");
+            session.ensure_in_room(*room_id)?;
+            let room = handle_set_retention(*room_id, *retention, &identity, pg_pool).await?;
+            publish_message(shared_redis, &TalkProtocol::RoomInfo { room }, room_id).await?;
+        }
+        TalkProtocol::ResolveRoom { name } => {
+            let identity = identity.expect("authorized");
+            let room = pg_pool
+                .run(|conn| {
+                    Ok(match get_room_by_name(conn, &name.trim().to_lowercase())? {
+                        Some(room) if can_access(conn, &room, &identity)? => Some(room),
+                        // Private rooms don't exist for outsiders
+                        _ => None,
+                    })
+                })
+                .await?;
+            let Some(room) = room else {
+                bail!(RequestError::new(
+                    ErrorCode::RoomNotFound,
+                    format!("There is no room called {}", name.trim()),
+                ));
+            };
+            let _ = tx.send(TalkProtocol::RoomInfo {
+                room: room_info(room),
+            });
+        }
+        TalkProtocol::CreateInvite { room_id } => {
+            let identity = identity.expect("authorized");
+            session.ensure_in_room(*room_id)?;
+            let response = handle_create_invite(*room_id, &identity, pg_pool).await?;
+            let _ = tx.send(response);
+        }
+        TalkProtocol::RedeemInvite { code } => {
+            let identity = identity.expect("authorized");
+            let room = handle_redeem_invite(code, &identity, pg_pool).await?;
+            let _ = tx.send(TalkProtocol::InviteRedeemed { room });
+        }
+        TalkProtocol::Kick { room_id, .. }
+        | TalkProtocol::Ban { room_id, .. }
+        | TalkProtocol::Mute { room_id, .. }
+        | TalkProtocol::Unban { room_id, .. }
+        | TalkProtocol::SetRole { room_id, .. } => {
+            let identity = identity.expect("authorized");
+            let response = handle_moderation(&msg, &identity, pg_pool).await?;
+            publish_message(shared_redis, &response, room_id).await?;
+        }
+        TalkProtocol::ListRooms => {
+            let rooms = handle_list_rooms(shared_redis, pg_pool).await?;
+            let _ = tx.send(TalkProtocol::RoomList { rooms });
+        }
+        TalkProtocol::Search {
+            room_id,
+            query,
+            limit,
+        } => {
+            session.ensure_in_room(*room_id)?;
+            let hits = handle_search(*room_id, query, *limit, pg_pool).await?;
+            let _ = tx.send(TalkProtocol::SearchResults {
+                room_id: *room_id,
+                query: query.clone(),
+                hits,
+            });
+        }
+        TalkProtocol::ListMembers { room_id } => {
+            session.ensure_in_room(*room_id)?;
+            let members = handle_list_members(*room_id, shared_redis, pg_pool).await?;
+            let _ = tx.send(TalkProtocol::Members {
+                room_id: *room_id,
+                members,
+            });
+        }
+        TalkProtocol::AddReaction { message_id, emoji }
+        | TalkProtocol::RemoveReaction { message_id, emoji } => {
+            let identity = identity.expect("authorized");
+            let add = matches!(msg, TalkProtocol::AddReaction { .. });
+            let (room_id, response) =
+                handle_reaction(*message_id, emoji, add, &identity, session, pg_pool).await?;
+            publish_message(shared_redis, &response, &room_id).await?;
+        }
         // Server -> Client events typically don't need handling here
         TalkProtocol::UserJoined { .. }
         | TalkProtocol::UserLeft { .. }
         | TalkProtocol::History { .. }
         | TalkProtocol::UsernameChanged { .. }
         | TalkProtocol::LocalError { .. }
-        | TalkProtocol::Error { .. } => {
+        | TalkProtocol::Error { .. }
+        | TalkProtocol::Hello { .. }
+        | TalkProtocol::Welcome { .. }
+        | TalkProtocol::Authenticated { .. }
+        | TalkProtocol::SessionToken { .. }
+        | TalkProtocol::SessionExpired
+        | TalkProtocol::MessageEdited { .. }
+        | TalkProtocol::MessageDeleted { .. }
+        | TalkProtocol::ReactionsChanged { .. }
+        | TalkProtocol::Members { .. }
+        | TalkProtocol::DirectMessage { .. }
+        | TalkProtocol::DirectHistory { .. }
+        | TalkProtocol::RoomInfo { .. }
+        | TalkProtocol::RoomList { .. }
+        | TalkProtocol::Invite { .. }
+        | TalkProtocol::InviteRedeemed { .. }
+        | TalkProtocol::Moderation { .. }
+        | TalkProtocol::SearchResults { .. }
+        | TalkProtocol::ServerShuttingDown { .. } => {
             // These are usually sent from server to client, not received
-            eprintln!("Unexpected server-to-client message received");
+            bail!(RequestError::new(
+                ErrorCode::ProtocolViolation,
+                "Unexpected server-to-client message received",
+            ));
         }
     }
     Ok(())
 }
 
-async fn handle_join(
-    room_id: &i32,
-    room_tx: &UnboundedSender<(i32, oneshot::Sender<()>)>,
+/// The uuid a client claims to act as, if the message carries one.
+fn claimed_uuid(msg: &TalkProtocol) -> Option<Uuid> {
+    match msg {
+        TalkProtocol::JoinRoom { uuid, .. }
+        | TalkProtocol::LeaveRoom { uuid, .. }
+        | TalkProtocol::ChangeName { uuid, .. }
+        | TalkProtocol::Typing { uuid, .. } => Some(*uuid),
+        TalkProtocol::PostMessage { message } => Some(message.uuid),
+        _ => None,
+    }
+}
+
+async fn handle_register(
+    username: &str,
+    password: &str,
+    session: &mut Session,
+    tx: &UnboundedSender<TalkProtocol>,
+    pg_pool: &PgPool,
+) -> Result<()> {
+    ensure_logged_out(session)?;
+    let username = username.trim();
+    validate_username(username)?;
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        bail!(RequestError::new(
+            ErrorCode::InvalidInput,
+            format!(
+                "The password needs at least {} characters",
+                MIN_PASSWORD_LENGTH
+            ),
+        ));
+    }
+
+    let account = NewAccount {
+        uuid: Uuid::new_v4(),
+        username: username.to_string(),
+        display_name: username.to_string(),
+        password_hash: hash_password(password.to_string()).await?,
+        created_at: unix_timestamp(),
+    };
+    let identity = Identity {
+        uuid: account.uuid,
+        username: account.display_name.clone(),
+        is_moderator: false,
+    };
+
+    pg_pool
+        .run(|conn| {
+            ensure_name_free(conn, username, account.uuid)?;
+            match insert_account(conn, account) {
+                Ok(_) => Ok(()),
+                Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                    bail!(RequestError::new(
+                        ErrorCode::UsernameTaken,
+                        format!("The username '{}' is already taken", username),
+                    ));
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await?;
+
+    authenticate(session, tx, identity);
+    issue_session(session, tx, pg_pool).await
+}
+
+async fn handle_login(
+    username: &str,
+    password: &str,
+    session: &mut Session,
+    tx: &UnboundedSender<TalkProtocol>,
+    pg_pool: &PgPool,
+) -> Result<()> {
+    ensure_logged_out(session)?;
+    let account = pg_pool
+        .run(|conn| Ok(get_account_by_username(conn, username.trim())?))
+        .await?;
+
+    let verified = match &account {
+        Some(account) => {
+            verify_password(password.to_string(), account.password_hash.clone()).await?
+        }
+        None => false,
+    };
+    match account {
+        Some(account) if verified => {
+            let identity = Identity {
+                uuid: account.uuid,
+                username: account.display_name,
+                is_moderator: account.is_moderator,
+            };
+            authenticate(session, tx, identity);
+            issue_session(session, tx, pg_pool).await?;
+        }
+        _ => bail!(RequestError::new(
+            ErrorCode::InvalidCredentials,
+            "Wrong username or password",
+        )),
+    }
+    Ok(())
+}
+
+async fn handle_resume(
+    token: &str,
+    session: &mut Session,
+    tx: &UnboundedSender<TalkProtocol>,
+    pg_pool: &PgPool,
+) -> Result<()> {
+    ensure_logged_out(session)?;
+    let active = match verify_token(token) {
+        Some((session_id, account_uuid)) => pg_pool
+            .run(|conn| Ok(get_session(conn, session_id)?))
+            .await?
+            .filter(|(record, _)| resumable(record, account_uuid, unix_timestamp())),
+        None => None,
+    };
+
+    match active {
+        Some((record, account)) => {
+            session.token_id = Some(record.id);
+            let identity = Identity {
+                uuid: account.uuid,
+                username: account.display_name,
+                is_moderator: account.is_moderator,
+            };
+            authenticate(session, tx, identity);
+        }
+        None => {
+            let _ = tx.send(TalkProtocol::SessionExpired);
+        }
+    }
+    Ok(())
+}
+
+/// Leaves the current room first, so whoever logs in next on this connection
+/// has to join it again.
+async fn handle_logout(
+    session: &mut Session,
+    subscription_tx: &UnboundedSender<SubscriptionRequest>,
+    shared_redis: &SharedRedis,
+    pg_pool: &PgPool,
+) -> Result<()> {
+    if let (Some(room_id), Some(identity)) = (session.room.take(), &session.identity) {
+        subscribe(subscription_tx, Subscription::Room(None)).await?;
+        leave_room(identity, session.connection, room_id, shared_redis, pg_pool).await?;
+    }
+    if let Some(token_id) = session.token_id.take() {
+        pg_pool
+            .run(|conn| Ok(revoke_session(conn, token_id)?))
+            .await?;
+    }
+    session.identity = None;
+    Ok(())
+}
+
+/// Stores a new session for the logged in account and hands its token to the
+/// client, so it can `Resume` after reconnecting.
+async fn issue_session(
+    session: &mut Session,
+    tx: &UnboundedSender<TalkProtocol>,
+    pg_pool: &PgPool,
+) -> Result<()> {
+    if !session.capabilities.contains(Capabilities::SESSION_RESUME) {
+        return Ok(());
+    }
+    let Some(identity) = &session.identity else {
+        return Ok(());
+    };
+
+    let now = unix_timestamp();
+    let record = NewSessionRecord {
+        id: Uuid::new_v4(),
+        account_uuid: identity.uuid,
+        created_at: now,
+        expires_at: now + session_ttl_secs(),
+    };
+    let token = sign_token(record.id, record.account_uuid);
+    let expires_at = record.expires_at as u64;
+    session.token_id = Some(record.id);
+    pg_pool
+        .run(|conn| {
+            delete_expired_sessions(conn, now)?;
+            insert_session(conn, record)?;
+            Ok(())
+        })
+        .await?;
+
+    let _ = tx.send(TalkProtocol::SessionToken { token, expires_at });
+    Ok(())
+}
+
+fn authenticate(session: &mut Session, tx: &UnboundedSender<TalkProtocol>, identity: Identity) {
+    let _ = tx.send(TalkProtocol::Authenticated {
+        uuid: identity.uuid,
+        username: identity.username.clone(),
+    });
+    session.identity = Some(identity);
+}
+
+fn ensure_logged_out(session: &Session) -> Result<(), RequestError> {
+    if session.identity.is_some() {
+        return Err(RequestError::new(
+            ErrorCode::ProtocolViolation,
+            "You are already logged in",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_username(username: &str) -> Result<(), RequestError> {
+    if username.trim().is_empty() || username.chars().count() > MAX_USERNAME_LENGTH {
+        return Err(RequestError::new(
+            ErrorCode::InvalidInput,
+            format!(
+                "Usernames need between 1 and {} characters",
+                MAX_USERNAME_LENGTH
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Moderators and direct messages pick accounts by name, so nobody may take
+/// a name another account logs in with or is shown as.
+fn ensure_name_free(conn: &mut PgConnection, name: &str, account_uuid: Uuid) -> Result<()> {
+    if name_in_use(conn, name, account_uuid)? {
+        bail!(RequestError::new(
+            ErrorCode::UsernameTaken,
+            format!("The username '{}' is already taken", name),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_message_text(text: &str) -> Result<(), RequestError> {
+    if text.trim().is_empty() {
+        return Err(RequestError::new(
+            ErrorCode::InvalidInput,
+            "Messages must not be empty",
+        ));
+    }
+    if text.chars().count() > MAX_MESSAGE_LENGTH {
+        return Err(RequestError::new(
+            ErrorCode::MessageTooLong,
+            format!("Messages are limited to {} characters", MAX_MESSAGE_LENGTH),
+        ));
+    }
+    Ok(())
+}
+
+fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn unix_timestamp_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// The row stored for a user joining or leaving `room_id`.
+fn room_event(identity: &Identity, room_id: i32) -> TalkMessage {
+    TalkMessage {
+        uuid: identity.uuid,
+        username: identity.username.clone(),
+        id: 0,
+        text: "".to_string(),
+        room_id,
+        unixtime: 0,
+        seq: 0,
+        edited_at: None,
+        deleted_at: None,
+        reply_to: None,
+        reactions: Vec::new(),
+    }
+}
+
+/// Takes the connection out of the room and publishes that the user left.
+async fn leave_room(
+    identity: &Identity,
+    connection: Uuid,
+    room_id: i32,
+    shared_redis: &SharedRedis,
+    pg_pool: &PgPool,
+) -> Result<()> {
+    remove_presence(shared_redis, room_id, identity.uuid, connection).await?;
+    delete_user(pg_pool, connection).await?;
+
+    let message =
+        persist_message(pg_pool, &room_event(identity, room_id), LEFT_PROTOCOL_TYPE).await?;
+    let response = TalkProtocol::UserLeft {
+        id: message.id,
+        uuid: message.uuid,
+        username: message.username,
+        room_id: message.room_id,
+        unixtime: message.unixtime,
+        seq: message.seq,
+    };
+    publish_message(shared_redis, &response, &room_id).await
+}
+
+/// Switches the Redis subscriber of this connection over, returning once it
+/// listens on the new channel.
+async fn subscribe(
+    subscription_tx: &UnboundedSender<SubscriptionRequest>,
+    subscription: Subscription,
 ) -> Result<()> {
     let (ack_tx, ack_rx) = oneshot::channel();
-    room_tx.send((*room_id, ack_tx))?;
-    ack_rx.await?;
+    subscription_tx.send((subscription, ack_tx))?;
+    ack_rx.await??;
     Ok(())
 }
 
+/// Follows the direct messages of whoever is logged in now.
+async fn subscribe_user(
+    session: &Session,
+    subscription_tx: &UnboundedSender<SubscriptionRequest>,
+) -> Result<()> {
+    let uuid = session.identity.as_ref().map(|identity| identity.uuid);
+    subscribe(subscription_tx, Subscription::User(uuid)).await
+}
+
+async fn handle_send_direct(
+    recipient: &Recipient,
+    text: &str,
+    identity: &Identity,
+    pg_pool: &PgPool,
+) -> Result<DirectMessage> {
+    pg_pool
+        .run(|conn| {
+            let Some(account) = find_account(conn, recipient)? else {
+                bail!(RequestError::new(
+                    ErrorCode::UserNotFound,
+                    "There is no such user",
+                ));
+            };
+            let stored = insert_direct_message(
+                conn,
+                NewDirectMessage {
+                    sender_uuid: identity.uuid,
+                    sender_name: identity.username.clone(),
+                    recipient_uuid: account.uuid,
+                    recipient_name: account.display_name,
+                    message: text.to_string(),
+                    time: unix_timestamp_millis() as i64,
+                },
+            )?;
+            Ok(stored_direct_message(stored))
+        })
+        .await
+}
+
+fn find_account(
+    conn: &mut PgConnection,
+    recipient: &Recipient,
+) -> Result<Option<Account>, DieselError> {
+    match recipient {
+        Recipient::Username(name) => get_account_by_name(conn, name.trim()),
+        Recipient::Uuid(uuid) => get_account_by_uuid(conn, *uuid),
+    }
+}
+
+async fn handle_fetch_direct(
+    before_id: Option<u64>,
+    limit: &i64,
+    identity: &Identity,
+    pg_pool: &PgPool,
+) -> Result<(Vec<DirectMessage>, bool)> {
+    validate_fetch_limit(*limit)?;
+    pg_pool
+        .run(|conn| {
+            let mut history = get_direct_history(
+                conn,
+                identity.uuid,
+                before_id.map(|id| id as i64),
+                limit + 1,
+            )?;
+            let has_more = history.len() as i64 > *limit;
+            if has_more {
+                history.remove(0);
+            }
+            Ok((
+                history.into_iter().map(stored_direct_message).collect(),
+                has_more,
+            ))
+        })
+        .await
+}
+
+fn stored_direct_message(message: DirectMessageRecord) -> DirectMessage {
+    DirectMessage {
+        id: message.id as u64,
+        sender: message.sender_uuid,
+        sender_name: message.sender_name,
+        recipient: message.recipient_uuid,
+        recipient_name: message.recipient_name,
+        text: message.message,
+        unixtime: message.time as u64,
+    }
+}
+
+async fn handle_create_room(
+    name: &str,
+    topic: &str,
+    visibility: Visibility,
+    identity: &Identity,
+    pg_pool: &PgPool,
+) -> Result<Room> {
+    let name = name.trim().to_lowercase();
+    validate_room_name(&name)?;
+    validate_topic(topic)?;
+    let now = unix_timestamp_millis() as i64;
+    pg_pool
+        .run(|conn| {
+            let inserted = insert_room(
+                conn,
+                NewRoom {
+                    name: name.clone(),
+                    topic: topic.trim().to_string(),
+                    created_by: Some(identity.uuid),
+                    created_at: now,
+                    visibility: visibility.to_i16(),
+                },
+            );
+            match inserted {
+                Ok(room) => {
+                    set_room_role(
+                        conn,
+                        NewRoomMember {
+                            room_id: room.id,
+                            account_uuid: identity.uuid,
+                            joined_at: now,
+                            role: RoomRole::Owner.to_i16(),
+                        },
+                    )?;
+                    Ok(room_info(room))
+                }
+                Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                    bail!(RequestError::new(
+                        ErrorCode::RoomExists,
+                        format!("A room called {} already exists", name),
+                    ))
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await
+}
+
+/// Only moderators of the room may change its topic.
+async fn handle_set_topic(
+    room_id: i32,
+    topic: &str,
+    identity: &Identity,
+    pg_pool: &PgPool,
+) -> Result<Room> {
+    validate_topic(topic)?;
+    pg_pool
+        .run(|conn| {
+            ensure_room_role(conn, room_id, identity, RoomRole::Moderator, "topic")?;
+            let room = update_topic(conn, room_id, topic.trim())?;
+            Ok(room_info(room))
+        })
+        .await
+}
+
+async fn handle_set_slow_mode(
+    room_id: i32,
+    seconds: u32,
+    identity: &Identity,
+    pg_pool: &PgPool,
+) -> Result<Room> {
+    if seconds > MAX_SLOW_MODE_SECS {
+        bail!(RequestError::new(
+            ErrorCode::InvalidInput,
+            format!("Slow mode is limited to {} seconds", MAX_SLOW_MODE_SECS),
+        ));
+    }
+    pg_pool
+        .run(|conn| {
+            ensure_room_role(conn, room_id, identity, RoomRole::Moderator, "slow mode")?;
+            let room = update_slow_mode(conn, room_id, seconds as i32)?;
+            Ok(room_info(room))
+        })
+        .await
+}
+
+async fn handle_set_retention(
+    room_id: i32,
+    retention: Retention,
+    identity: &Identity,
+    pg_pool: &PgPool,
+) -> Result<Room> {
+    let max_age_secs = validate_retention_limit(retention.max_age_secs, "age")?;
+    let max_messages = validate_retention_limit(retention.max_messages, "number of messages")?;
+    pg_pool
+        .run(|conn| {
+            ensure_room_role(conn, room_id, identity, RoomRole::Owner, "retention")?;
+            let room = update_retention(conn, room_id, max_age_secs, max_messages)?;
+            Ok(room_info(room))
+        })
+        .await
+}
+
+/// Settings of a room are only kept for named rooms and only changed by
+/// members with at least `role`.
+fn ensure_room_role(
+    conn: &mut PgConnection,
+    room_id: i32,
+    identity: &Identity,
+    role: RoomRole,
+    setting: &str,
+) -> Result<()> {
+    if get_room(conn, room_id)?.is_none() {
+        bail!(RequestError::new(
+            ErrorCode::RoomNotFound,
+            format!(
+                "Room {} has no name, so it has no {} either",
+                room_id, setting
+            ),
+        ));
+    }
+    if moderation_rank(conn, room_id, identity.uuid, identity.is_moderator)? < role.to_i16() {
+        let who = match role {
+            RoomRole::Owner => "owners",
+            _ => "moderators",
+        };
+        bail!(RequestError::new(
+            ErrorCode::Forbidden,
+            format!("Only {} of the room can change its {}", who, setting),
+        ));
+    }
+    Ok(())
+}
+
+/// Moderators aren't slowed down by slow mode.
+async fn enforce_slow_mode(
+    room_id: i32,
+    identity: &Identity,
+    async_redis: &AsyncRedis,
+    pg_pool: &PgPool,
+) -> Result<()> {
+    let seconds = pg_pool
+        .run(|conn| {
+            Ok(match get_room(conn, room_id)? {
+                Some(room)
+                    if room.slow_mode_secs > 0
+                        && moderation_rank(
+                            conn,
+                            room_id,
+                            identity.uuid,
+                            identity.is_moderator,
+                        )? < RoomRole::Moderator.to_i16() =>
+                {
+                    Some(room.slow_mode_secs as u64)
+                }
+                _ => None,
+            })
+        })
+        .await?;
+    let Some(seconds) = seconds else {
+        return Ok(());
+    };
+    check_slow_mode(
+        async_redis,
+        room_id,
+        identity.uuid,
+        Duration::from_secs(seconds),
+    )
+    .await
+}
+
+/// Private rooms are open to whoever created them, moderators and everybody
+/// who redeemed an invite.
+fn can_access(
+    conn: &mut PgConnection,
+    room: &RoomRecord,
+    identity: &Identity,
+) -> Result<bool, DieselError> {
+    if open_to(room, identity) {
+        return Ok(true);
+    }
+    is_room_member(conn, room.id, identity.uuid)
+}
+
+/// `can_access` for everybody who needs no invite.
+fn open_to(room: &RoomRecord, identity: &Identity) -> bool {
+    room.visibility != Visibility::Private.to_i16()
+        || room.created_by == Some(identity.uuid)
+        || identity.is_moderator
+}
+
+/// The room behind `room_id` if it has a name, numbered rooms are open to all.
+async fn accessible_room(
+    room_id: i32,
+    identity: &Identity,
+    pg_pool: &PgPool,
+) -> Result<Option<Room>> {
+    pg_pool
+        .run(|conn| {
+            let Some(room) = get_room(conn, room_id)? else {
+                return Ok(None);
+            };
+            if !can_access(conn, &room, identity)? {
+                bail!(RequestError::new(
+                    ErrorCode::Forbidden,
+                    format!(
+                        "#{} is private, ask somebody in it for an invite",
+                        room.name
+                    ),
+                ));
+            }
+            Ok(Some(room_info(room)))
+        })
+        .await
+}
+
+async fn handle_create_invite(
+    room_id: i32,
+    identity: &Identity,
+    pg_pool: &PgPool,
+) -> Result<TalkProtocol> {
+    pg_pool
+        .run(|conn| {
+            let Some(room) = get_room(conn, room_id)? else {
+                bail!(RequestError::new(
+                    ErrorCode::RoomNotFound,
+                    format!(
+                        "Room {} has no name, everybody can join it already",
+                        room_id
+                    ),
+                ));
+            };
+            let invite = new_invite(room_id, identity, unix_timestamp_millis());
+            let reply = TalkProtocol::Invite {
+                code: invite.code.clone(),
+                room: room_info(room),
+                expires_at: invite.expires_at as u64,
+            };
+            insert_invite(conn, invite)?;
+            Ok(reply)
+        })
+        .await
+}
+
+/// A fresh code for `room_id`, valid for `INVITE_TTL_MILLIS` from `now`.
+fn new_invite(room_id: i32, identity: &Identity, now: u64) -> InviteRecord {
+    InviteRecord {
+        code: Uuid::new_v4().simple().to_string()[..INVITE_CODE_LENGTH].to_string(),
+        room_id,
+        created_by: identity.uuid,
+        created_at: now as i64,
+        expires_at: (now + INVITE_TTL_MILLIS) as i64,
+    }
+}
+
+async fn handle_redeem_invite(code: &str, identity: &Identity, pg_pool: &PgPool) -> Result<Room> {
+    let now = unix_timestamp_millis();
+    pg_pool
+        .run(|conn| {
+            let room = match get_invite(conn, code.trim(), now as i64)? {
+                Some(invite) => get_room(conn, invite.room_id)?,
+                None => None,
+            };
+            let Some(room) = room else {
+                bail!(RequestError::new(
+                    ErrorCode::InvalidInvite,
+                    "This invite code doesn't exist or has expired",
+                ));
+            };
+            insert_room_member(
+                conn,
+                NewRoomMember {
+                    room_id: room.id,
+                    account_uuid: identity.uuid,
+                    joined_at: now as i64,
+                    role: RoomRole::Member.to_i16(),
+                },
+            )?;
+            Ok(room_info(room))
+        })
+        .await
+}
+
+/// Where somebody stands in a room, as the `RoomRole` it has there.
+fn moderation_rank(
+    conn: &mut PgConnection,
+    room_id: i32,
+    account_uuid: Uuid,
+    is_global_moderator: bool,
+) -> Result<i16, DieselError> {
+    if is_global_moderator {
+        return Ok(GLOBAL_MODERATOR_RANK);
+    }
+    Ok(get_room_role(conn, room_id, account_uuid)?.unwrap_or(RoomRole::Member.to_i16()))
+}
+
+/// Whether somebody of `rank` may act on somebody of `target_rank`, which
+/// takes at least `required` and a rank above the target's.
+fn outranks(rank: i16, target_rank: i16, required: RoomRole) -> bool {
+    rank >= required.to_i16() && rank > target_rank
+}
+
+/// Moderators act on members, owners on moderators as well. Server-wide
+/// moderators outrank everybody, which also lets them act in numbered rooms
+/// that have no owner.
+async fn handle_moderation(
+    msg: &TalkProtocol,
+    identity: &Identity,
+    pg_pool: &PgPool,
+) -> Result<TalkProtocol> {
+    let (room_id, target, action, duration_secs) = match msg {
+        TalkProtocol::Kick { room_id, target } => {
+            (*room_id, target, ModerationAction::Kicked, None)
+        }
+        TalkProtocol::Ban {
+            room_id,
+            target,
+            duration_secs,
+        } => (*room_id, target, ModerationAction::Banned, *duration_secs),
+        TalkProtocol::Mute {
+            room_id,
+            target,
+            duration_secs,
+        } => (*room_id, target, ModerationAction::Muted, *duration_secs),
+        TalkProtocol::Unban { room_id, target } => {
+            (*room_id, target, ModerationAction::Unbanned, None)
+        }
+        TalkProtocol::SetRole {
+            room_id,
+            target,
+            role,
+        } => (*room_id, target, ModerationAction::RoleChanged(*role), None),
+        _ => unreachable!("not a moderation request"),
+    };
+    let now = unix_timestamp_millis();
+    let until = sanction_until(now, duration_secs)?;
+
+    pg_pool
+        .run(|conn| {
+            let Some(account) = find_account(conn, target)? else {
+                bail!(RequestError::new(
+                    ErrorCode::UserNotFound,
+                    "There is no such user",
+                ));
+            };
+            let rank = moderation_rank(conn, room_id, identity.uuid, identity.is_moderator)?;
+            let target_rank = moderation_rank(conn, room_id, account.uuid, account.is_moderator)?;
+            if !outranks(rank, target_rank, required_role(action)) {
+                bail!(RequestError::new(
+                    ErrorCode::Forbidden,
+                    format!(
+                        "You can't do that to {} in room {}",
+                        account.display_name, room_id
+                    ),
+                ));
+            }
+
+            match action {
+                ModerationAction::Kicked => {}
+                ModerationAction::Banned | ModerationAction::Muted => {
+                    let kind = match action {
+                        ModerationAction::Banned => BAN_SANCTION,
+                        _ => MUTE_SANCTION,
+                    };
+                    upsert_sanction(
+                        conn,
+                        SanctionRecord {
+                            room_id,
+                            account_uuid: account.uuid,
+                            kind,
+                            issued_by: Some(identity.uuid),
+                            created_at: now as i64,
+                            expires_at: until.map(|until| until as i64),
+                        },
+                    )?;
+                }
+                ModerationAction::Unbanned => {
+                    delete_sanctions(conn, room_id, account.uuid)?;
+                }
+                ModerationAction::RoleChanged(RoomRole::Owner) => {
+                    bail!(RequestError::new(
+                        ErrorCode::InvalidInput,
+                        "Rooms have a single owner",
+                    ));
+                }
+                ModerationAction::RoleChanged(role) => {
+                    if get_room(conn, room_id)?.is_none() {
+                        bail!(RequestError::new(
+                            ErrorCode::RoomNotFound,
+                            format!("Room {} has no name, so it has no roles either", room_id),
+                        ));
+                    }
+                    set_room_role(
+                        conn,
+                        NewRoomMember {
+                            room_id,
+                            account_uuid: account.uuid,
+                            joined_at: now as i64,
+                            role: role.to_i16(),
+                        },
+                    )?;
+                }
+            }
+
+            Ok(TalkProtocol::Moderation {
+                room_id,
+                action,
+                target: Member {
+                    uuid: account.uuid,
+                    username: account.display_name,
+                },
+                by: Member {
+                    uuid: identity.uuid,
+                    username: identity.username.clone(),
+                },
+                until,
+                unixtime: now,
+            })
+        })
+        .await
+}
+
+/// Only owners hand out roles, moderators do the rest.
+fn required_role(action: ModerationAction) -> RoomRole {
+    match action {
+        ModerationAction::RoleChanged(_) => RoomRole::Owner,
+        _ => RoomRole::Moderator,
+    }
+}
+
+/// When a sanction of `duration_secs` issued at `now` ends, in milliseconds.
+/// It has to fit `room_sanctions.expires_at`.
+fn sanction_until(now: u64, duration_secs: Option<u64>) -> Result<Option<u64>, RequestError> {
+    duration_secs
+        .map(|secs| {
+            secs.checked_mul(1000)
+                .and_then(|millis| now.checked_add(millis))
+                .filter(|until| *until <= i64::MAX as u64)
+                .ok_or_else(|| {
+                    RequestError::new(ErrorCode::InvalidInput, "That duration is too long")
+                })
+        })
+        .transpose()
+}
+
+/// Fails if the account is banned from the room (`BAN_SANCTION`) or muted in
+/// it (`MUTE_SANCTION`) right now.
+async fn ensure_not_sanctioned(
+    room_id: i32,
+    identity: &Identity,
+    kind: i16,
+    pg_pool: &PgPool,
+) -> Result<()> {
+    pg_pool
+        .run(|conn| check_sanction(conn, room_id, identity, kind))
+        .await
+}
+
+/// `ensure_not_sanctioned` on a connection that is already checked out.
+fn check_sanction(
+    conn: &mut PgConnection,
+    room_id: i32,
+    identity: &Identity,
+    kind: i16,
+) -> Result<()> {
+    let now = unix_timestamp_millis() as i64;
+    let Some(sanction) = get_active_sanction(conn, room_id, identity.uuid, kind, now)? else {
+        return Ok(());
+    };
+    let (code, what) = match kind {
+        BAN_SANCTION => (ErrorCode::Forbidden, "banned from"),
+        _ => (ErrorCode::Muted, "muted in"),
+    };
+    let remaining = match sanction.expires_at {
+        Some(expires_at) => format!(" for another {} minutes", (expires_at - now) / 60_000 + 1),
+        None => String::new(),
+    };
+    bail!(RequestError::new(
+        code,
+        format!("You are {} room {}{}", what, room_id, remaining),
+    ))
+}
+
+fn room_info(room: RoomRecord) -> Room {
+    Room {
+        id: room.id,
+        name: room.name,
+        topic: room.topic,
+        created_by: room.created_by,
+        created_at: room.created_at as u64,
+        visibility: Visibility::from_i16(room.visibility).unwrap_or(Visibility::Public),
+        slow_mode_secs: room.slow_mode_secs.max(0) as u32,
+        retention: Retention {
+            max_age_secs: room.retention_max_age_secs.map(|secs| secs as u64),
+            max_messages: room.retention_max_messages.map(|count| count as u64),
+        },
+    }
+}
+
+/// Names are lower case so that they can be looked up case-insensitively, and
+/// never purely numeric so that they can't be confused with room ids.
+fn validate_room_name(name: &str) -> Result<(), RequestError> {
+    let valid = !name.is_empty()
+        && name.chars().count() <= MAX_ROOM_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && !name.chars().all(|c| c.is_ascii_digit());
+    if !valid {
+        return Err(RequestError::new(
+            ErrorCode::InvalidInput,
+            format!(
+                "Room names need between 1 and {} letters, digits, - or _ and can't be just a number",
+                MAX_ROOM_NAME_LENGTH
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_topic(topic: &str) -> Result<(), RequestError> {
+    if topic.chars().count() > MAX_TOPIC_LENGTH {
+        return Err(RequestError::new(
+            ErrorCode::InvalidInput,
+            format!("Topics are limited to {} characters", MAX_TOPIC_LENGTH),
+        ));
+    }
+    Ok(())
+}
+
+/// Public rooms with how many people are in them and when they were last
+/// used, the busiest first.
+async fn handle_list_rooms(
+    shared_redis: &SharedRedis,
+    pg_pool: &PgPool,
+) -> Result<Vec<RoomSummary>> {
+    let (rooms, activity) = pg_pool
+        .run(|conn| {
+            let rooms = get_public_rooms(conn)?;
+            let ids: Vec<i32> = rooms.iter().map(|room| room.id).collect();
+            let activity: HashMap<i32, i64> = get_last_activity(conn, &ids)?
+                .into_iter()
+                .filter_map(|(room_id, time)| Some((room_id, time?)))
+                .collect();
+            Ok((rooms, activity))
+        })
+        .await?;
+    let mut summaries = Vec::with_capacity(rooms.len());
+    for room in rooms {
+        let members = present_count(shared_redis, room.id).await? as u32;
+        summaries.push(RoomSummary {
+            last_activity: activity.get(&room.id).map(|time| *time as u64),
+            members,
+            room: room_info(room),
+        });
+    }
+    sort_directory(&mut summaries);
+    Ok(summaries)
+}
+
+/// The busiest rooms first, ties go to the one used last.
+fn sort_directory(summaries: &mut [RoomSummary]) {
+    summaries.sort_by(|a, b| {
+        b.members
+            .cmp(&a.members)
+            .then(b.last_activity.cmp(&a.last_activity))
+    });
+}
+
+async fn handle_list_members(
+    room_id: i32,
+    shared_redis: &SharedRedis,
+    pg_pool: &PgPool,
+) -> Result<Vec<Member>> {
+    let present = present_members(shared_redis, room_id).await?;
+    let names = pg_pool
+        .run(|conn| Ok(get_display_names(conn, &present)?))
+        .await?;
+    let mut members: Vec<Member> = names
+        .into_iter()
+        .map(|(uuid, username)| Member { uuid, username })
+        .collect();
+    members.sort_by_key(|member| member.username.to_lowercase());
+    Ok(members)
+}
+
 async fn handle_fetch(
     room_id: &i32,
     limit: &i64,
-    fetch_before: &u64,
-    pg_conn: &SharedPostgres,
-) -> Result<Vec<TalkProtocol>> {
-    let mut conn = pg_conn.lock().await;
-    let history = get_history(&mut conn, room_id, limit, fetch_before)?;
-    let message_list: Vec<TalkProtocol> = history
-        .iter()
-        .map(|e| {
-            TalkProtocol::from_i16(
-                e.protocol_type,
-                e.room_id,
-                e.uuid,
-                e.username.clone(),
-                e.time as u64,
-                e.message.clone(),
+    before_id: Option<u64>,
+    after_id: Option<u64>,
+    pg_pool: &PgPool,
+) -> Result<(Vec<TalkProtocol>, bool)> {
+    validate_fetch_limit(*limit)?;
+
+    pg_pool
+        .run(|conn| {
+            let before_seq = cursor_seq(conn, *room_id, before_id)?;
+            let after_seq = cursor_seq(conn, *room_id, after_id)?;
+            // One extra row tells whether there is another page.
+            let mut history = get_history(conn, room_id, &(limit + 1), before_seq, after_seq)?;
+            let has_more = history.len() as i64 > *limit;
+            if has_more {
+                if after_seq.is_some() {
+                    history.pop();
+                } else {
+                    history.remove(0);
+                }
+            }
+
+            let ids: Vec<i64> = history.iter().map(|e| e.id).collect();
+            let mut reactions = group_reactions(get_reactions(conn, &ids)?);
+
+            let message_list: Vec<TalkProtocol> = history
+                .into_iter()
+                .map(|e| {
+                    let protocol_type = e.protocol_type;
+                    let reactions = reactions.remove(&e.id).unwrap_or_default();
+                    let message = TalkMessage {
+                        reactions,
+                        ..stored_message(e)
+                    };
+                    TalkProtocol::from_i16(protocol_type, message)
+                        .expect("Type conversion to Talkprotocol from DB")
+                })
+                .collect();
+            Ok((message_list, has_more))
+        })
+        .await
+}
+
+async fn handle_search(
+    room_id: i32,
+    query: &str,
+    limit: i64,
+    pg_pool: &PgPool,
+) -> Result<Vec<SearchHit>> {
+    let query = query.trim();
+    validate_search(query, limit)?;
+    pg_pool
+        .run(|conn| {
+            let found = search_messages(conn, room_id, query, limit)?;
+            let ids: Vec<i64> = found.iter().map(|record| record.message.id).collect();
+            let mut reactions = group_reactions(get_reactions(conn, &ids)?);
+            Ok(found
+                .into_iter()
+                .map(|record| {
+                    let reactions = reactions.remove(&record.message.id).unwrap_or_default();
+                    SearchHit {
+                        message: TalkMessage {
+                            reactions,
+                            ..stored_message(record.message)
+                        },
+                        snippet: record.snippet,
+                        rank: record.rank,
+                    }
+                })
+                .collect())
+        })
+        .await
+}
+
+/// Resolves a message id sent as `Fetch` cursor to its position in the room.
+fn cursor_seq(
+    conn: &mut PgConnection,
+    room_id: i32,
+    message_id: Option<u64>,
+) -> Result<Option<i64>> {
+    let Some(message_id) = message_id else {
+        return Ok(None);
+    };
+    match get_message_seq(conn, room_id, message_id as i64)? {
+        Some(seq) => Ok(Some(seq)),
+        None => bail!(RequestError::new(
+            ErrorCode::InvalidInput,
+            format!("Message {} does not exist in room {}", message_id, room_id),
+        )),
+    }
+}
+
+async fn handle_edit(
+    message_id: u64,
+    text: &str,
+    identity: &Identity,
+    session: &Session,
+    pg_pool: &PgPool,
+) -> Result<(i32, TalkProtocol)> {
+    pg_pool
+        .run(|conn| {
+            let stored = editable_message(conn, message_id, identity, session)?;
+            // An edit posts new text just like a message does
+            check_sanction(conn, stored.room_id, identity, MUTE_SANCTION)?;
+            let edited_at = unix_timestamp_millis();
+            edit_message(
+                conn,
+                NewMessageEdit {
+                    message_id: stored.id,
+                    previous_text: stored.message,
+                    edited_at: edited_at as i64,
+                    edited_by: identity.uuid,
+                },
+                text,
+            )?;
+            Ok((
+                stored.room_id,
+                TalkProtocol::MessageEdited {
+                    message_id,
+                    room_id: stored.room_id,
+                    text: text.to_string(),
+                    edited_at,
+                },
+            ))
+        })
+        .await
+}
+
+async fn handle_delete(
+    message_id: u64,
+    identity: &Identity,
+    session: &Session,
+    pg_pool: &PgPool,
+) -> Result<(i32, TalkProtocol)> {
+    pg_pool
+        .run(|conn| {
+            let stored = editable_message(conn, message_id, identity, session)?;
+            let deleted_at = unix_timestamp_millis();
+            delete_message(conn, stored.id, deleted_at as i64)?;
+            Ok((
+                stored.room_id,
+                TalkProtocol::MessageDeleted {
+                    message_id,
+                    room_id: stored.room_id,
+                    deleted_at,
+                },
+            ))
+        })
+        .await
+}
+
+/// Looks up a chat message `identity` is allowed to change: their own, or
+/// those of members ranked below them for moderators and owners of the
+/// room, like `handle_moderation` does.
+fn editable_message(
+    conn: &mut PgConnection,
+    message_id: u64,
+    identity: &Identity,
+    session: &Session,
+) -> Result<StoredMessage> {
+    let stored = chat_message(conn, message_id, session)?;
+    if stored.uuid == identity.uuid {
+        return Ok(stored);
+    }
+    let rank = moderation_rank(conn, stored.room_id, identity.uuid, identity.is_moderator)?;
+    let author_is_moderator =
+        get_account_by_uuid(conn, stored.uuid)?.is_some_and(|author| author.is_moderator);
+    let author_rank = moderation_rank(conn, stored.room_id, stored.uuid, author_is_moderator)?;
+    if !outranks(rank, author_rank, RoomRole::Moderator) {
+        bail!(RequestError::new(
+            ErrorCode::Forbidden,
+            "You can only change your own messages and those of members ranked below you",
+        ));
+    }
+    Ok(stored)
+}
+
+/// Looks up a chat message that still exists in the room the client is in.
+fn chat_message(
+    conn: &mut PgConnection,
+    message_id: u64,
+    session: &Session,
+) -> Result<StoredMessage> {
+    let stored = get_message(conn, message_id as i64)?
+        .filter(|stored| stored.protocol_type == POST_PROTOCOL_TYPE && stored.deleted_at.is_none())
+        .ok_or_else(|| {
+            RequestError::new(
+                ErrorCode::MessageNotFound,
+                format!("Message {} does not exist", message_id),
             )
-            .expect("Type conversion to Talkprotocol from DB")
+        })?;
+    session.ensure_in_room(stored.room_id)?;
+    Ok(stored)
+}
+
+async fn handle_reaction(
+    message_id: u64,
+    emoji: &str,
+    add: bool,
+    identity: &Identity,
+    session: &Session,
+    pg_pool: &PgPool,
+) -> Result<(i32, TalkProtocol)> {
+    validate_reaction(emoji)?;
+    pg_pool
+        .run(|conn| {
+            let stored = chat_message(conn, message_id, session)?;
+            if add {
+                check_sanction(conn, stored.room_id, identity, MUTE_SANCTION)?;
+                insert_reaction(
+                    conn,
+                    ReactionRecord {
+                        message_id: stored.id,
+                        account_uuid: identity.uuid,
+                        emoji: emoji.to_string(),
+                        created_at: unix_timestamp_millis() as i64,
+                    },
+                )?;
+            } else {
+                delete_reaction(conn, stored.id, identity.uuid, emoji)?;
+            }
+            let mut reactions = group_reactions(get_reactions(conn, &[stored.id])?);
+            Ok((
+                stored.room_id,
+                TalkProtocol::ReactionsChanged {
+                    message_id,
+                    room_id: stored.room_id,
+                    reactions: reactions.remove(&stored.id).unwrap_or_default(),
+                },
+            ))
         })
-        .collect();
-    Ok(message_list)
+        .await
+}
+
+fn validate_fetch_limit(limit: i64) -> Result<(), RequestError> {
+    if !(1..=MAX_FETCH_LIMIT).contains(&limit) {
+        return Err(RequestError::new(
+            ErrorCode::InvalidInput,
+            format!("Fetch limit must be between 1 and {}", MAX_FETCH_LIMIT),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_search(query: &str, limit: i64) -> Result<(), RequestError> {
+    if query.is_empty() || query.chars().count() > MAX_MESSAGE_LENGTH {
+        return Err(RequestError::new(
+            ErrorCode::InvalidInput,
+            format!(
+                "Searches need between 1 and {} characters",
+                MAX_MESSAGE_LENGTH
+            ),
+        ));
+    }
+    if !(1..=MAX_SEARCH_LIMIT).contains(&limit) {
+        return Err(RequestError::new(
+            ErrorCode::InvalidInput,
+            format!("Search limit must be between 1 and {}", MAX_SEARCH_LIMIT),
+        ));
+    }
+    Ok(())
+}
+
+/// Stored as `BIGINT`, `None` lifts the limit.
+fn validate_retention_limit(limit: Option<u64>, what: &str) -> Result<Option<i64>, RequestError> {
+    match limit.map(i64::try_from) {
+        None => Ok(None),
+        Some(Ok(limit)) if limit > 0 => Ok(Some(limit)),
+        Some(_) => Err(RequestError::new(
+            ErrorCode::InvalidInput,
+            format!("The {} to keep must be a positive number", what),
+        )),
+    }
+}
+
+fn validate_reaction(emoji: &str) -> Result<(), RequestError> {
+    if emoji.is_empty()
+        || emoji.chars().count() > MAX_REACTION_LENGTH
+        || emoji.chars().any(char::is_whitespace)
+    {
+        return Err(RequestError::new(
+            ErrorCode::InvalidInput,
+            format!(
+                "Reactions need between 1 and {} characters without spaces",
+                MAX_REACTION_LENGTH
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Groups reaction rows by message, keeping the order they are given in.
+fn group_reactions(records: Vec<ReactionRecord>) -> HashMap<i64, Vec<Reaction>> {
+    let mut grouped: HashMap<i64, Vec<Reaction>> = HashMap::new();
+    for record in records {
+        let reactions = grouped.entry(record.message_id).or_default();
+        match reactions.iter_mut().find(|r| r.emoji == record.emoji) {
+            Some(reaction) => reaction.users.push(record.account_uuid),
+            None => reactions.push(Reaction {
+                emoji: record.emoji,
+                users: vec![record.account_uuid],
+            }),
+        }
+    }
+    grouped
+}
+
+async fn ensure_reply_target(pg_pool: &PgPool, message_id: u64, room_id: i32) -> Result<()> {
+    pg_pool
+        .run(|conn| {
+            let exists = get_message(conn, message_id as i64)?
+                .is_some_and(|parent| is_reply_target(&parent, room_id));
+            if !exists {
+                bail!(RequestError::new(
+                    ErrorCode::MessageNotFound,
+                    format!("Message {} does not exist in room {}", message_id, room_id),
+                ));
+            }
+            Ok(())
+        })
+        .await
+}
+
+/// Replies may only point at chat messages of the same room that still exist.
+fn is_reply_target(parent: &StoredMessage, room_id: i32) -> bool {
+    parent.room_id == room_id
+        && parent.protocol_type == POST_PROTOCOL_TYPE
+        && parent.deleted_at.is_none()
+}
+
+pub fn stored_message(message: StoredMessage) -> TalkMessage {
+    TalkMessage {
+        id: message.id as u64,
+        uuid: message.uuid,
+        username: message.username,
+        text: message.message,
+        room_id: message.room_id,
+        unixtime: message.time as u64,
+        seq: message.seq as u64,
+        edited_at: message.edited_at.map(|time| time as u64),
+        deleted_at: message.deleted_at.map(|time| time as u64),
+        reply_to: message.reply_to.map(|id| id as u64),
+        reactions: Vec::new(),
+    }
 }
 
 // Helper functions for DB operations
+
+/// Stores `msg` with the current server time and returns it as stored,
+/// including the sequence number it was assigned.
 async fn persist_message(
-    pg_conn: &SharedPostgres,
+    pg_pool: &PgPool,
     msg: &TalkMessage,
     protocol_type_message: i16,
-) -> Result<()> {
-    let mut conn = pg_conn.lock().await;
-    insert_message(
-        &mut conn,
-        NewMessage {
-            room_id: msg.room_id,
-            message: msg.text.clone(),
-            time: msg.unixtime as i64,
-            uuid: msg.uuid,
-            username: msg.username.clone(),
-            protocol_type: protocol_type_message,
-        },
-    )?;
-    Ok(())
+) -> Result<TalkMessage> {
+    pg_pool
+        .run(|conn| {
+            let stored = insert_message(
+                conn,
+                NewMessage {
+                    room_id: msg.room_id,
+                    message: msg.text.clone(),
+                    time: unix_timestamp_millis() as i64,
+                    uuid: msg.uuid,
+                    username: msg.username.clone(),
+                    protocol_type: protocol_type_message,
+                    reply_to: msg.reply_to.map(|id| id as i64),
+                },
+            )?;
+            Ok(stored_message(stored))
+        })
+        .await
 }
 
-async fn persist_user(pg_conn: &SharedPostgres, room_id: &i32, uuid: &Uuid) -> Result<()> {
-    let mut conn = pg_conn.lock().await;
-    insert_user(
-        &mut conn,
-        NewUser {
-            uuid: *uuid,
-            room_id: *room_id,
-        },
-    )?;
-    Ok(())
+async fn persist_user(pg_pool: &PgPool, room_id: i32, uuid: Uuid, connection: Uuid) -> Result<()> {
+    pg_pool
+        .run(|conn| {
+            insert_user(
+                conn,
+                NewUser {
+                    uuid,
+                    room_id,
+                    connection,
+                },
+            )?;
+            Ok(())
+        })
+        .await
 }
 
-async fn delete_user(pg_conn: &SharedPostgres, user_uuid: &Uuid) -> Result<()> {
-    let mut conn = pg_conn.lock().await;
-    delete_user_by_uuid(&mut conn, *user_uuid)?;
-    Ok(())
+async fn delete_user(pg_pool: &PgPool, connection: Uuid) -> Result<()> {
+    pg_pool
+        .run(|conn| {
+            delete_user_by_connection(conn, connection)?;
+            Ok(())
+        })
+        .await
 }
 
 async fn publish_message(
@@ -288,6 +2028,12 @@ async fn publish_message(
     Ok(())
 }
 
+async fn publish_direct(shared_redis: &SharedRedis, msg: &TalkProtocol, uuid: Uuid) -> Result<()> {
+    let mut conn = shared_redis.lock().await;
+    let _: () = conn.spublish(user_channel(uuid), msg.serialize()?)?;
+    Ok(())
+}
+
 pub async fn start_ws_server() -> Result<(), std::io::Error> {
     let addr = env::args()
         .nth(1)
@@ -302,19 +2048,310 @@ pub async fn start_ws_server() -> Result<(), std::io::Error> {
         .await
         .expect("Redis connection failed");
     let shared_con: SharedRedis = Arc::new(TMutex::new(redis_con));
+    let async_redis = create_redis_async_connection()
+        .await
+        .expect("Redis connection failed");
+
+    let subscriber = Subscriber::connect()
+        .await
+        .expect("Redis pubsub connection failed");
+
+    let pg_pool = PgPool::from_env().expect("Postgres connection failed");
+    tokio::spawn(retention::prune_periodically(pg_pool.clone()));
 
-    let pg_conn = Arc::new(TMutex::new(establish_connection()));
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let stop = shutdown::signal();
+    tokio::pin!(stop);
+    let mut connections = JoinSet::new();
 
-    while let Ok((stream, addr)) = listener.accept().await {
-        let rd_clone = Arc::clone(&shared_con);
-        let pg_clone = Arc::clone(&pg_conn);
-        tokio::spawn(handle_connection(stream, addr, rd_clone, pg_clone));
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let Ok((stream, addr)) = accepted else {
+                    break;
+                };
+                let rd_clone = Arc::clone(&shared_con);
+                connections.spawn(handle_connection(
+                    stream,
+                    addr,
+                    rd_clone,
+                    async_redis.clone(),
+                    subscriber.clone(),
+                    pg_pool.clone(),
+                    shutdown_rx.clone(),
+                ));
 
-        let metrics = Handle::current().metrics();
+                let metrics = Handle::current().metrics();
 
-        let n = metrics.num_alive_tasks();
-        println!("Server has {} active connections", n);
+                let n = metrics.num_alive_tasks();
+                println!("Server has {} active connections", n);
+            }
+            // Reap finished connections
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut stop => break,
+        }
+    }
+
+    // No new connections, the load balancer moves on to the other nodes
+    drop(listener);
+    println!("Shutting down, draining {} connections", connections.len());
+    let _ = shutdown_tx.send(true);
+    let drained = tokio::time::timeout(shutdown::timeout(), async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        eprintln!(
+            "[SERVER] Dropping {} connections that didn't drain in time",
+            connections.len()
+        );
+        connections.shutdown().await;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_stay_out_of_the_log() {
+        let msg = TalkProtocol::Login {
+            username: "alice".to_string(),
+            password: "hunter22".to_string(),
+        };
+        let logged = format!("{:?}", redacted(&msg));
+        assert!(logged.contains("alice"));
+        assert!(!logged.contains("hunter22"));
+    }
+
+    #[test]
+    fn session_tokens_stay_out_of_the_log() {
+        let token = sign_token(Uuid::new_v4(), Uuid::new_v4());
+        let msg = TalkProtocol::Resume {
+            token: token.clone(),
+        };
+        assert!(!format!("{:?}", redacted(&msg)).contains(&token));
+    }
+
+    #[test]
+    fn only_those_ranked_above_the_author_change_a_message() {
+        let member = RoomRole::Member.to_i16();
+        let moderator = RoomRole::Moderator.to_i16();
+        let owner = RoomRole::Owner.to_i16();
+        assert!(!outranks(member, member, RoomRole::Moderator));
+        assert!(outranks(moderator, member, RoomRole::Moderator));
+        assert!(!outranks(moderator, moderator, RoomRole::Moderator));
+        assert!(outranks(owner, moderator, RoomRole::Moderator));
+        assert!(!outranks(owner, GLOBAL_MODERATOR_RANK, RoomRole::Moderator));
+        assert!(outranks(GLOBAL_MODERATOR_RANK, owner, RoomRole::Moderator));
+    }
+
+    fn stored(room_id: i32, protocol_type: i16) -> StoredMessage {
+        StoredMessage {
+            id: 1,
+            time: 0,
+            username: "alice".to_string(),
+            message: "hello".to_string(),
+            room_id,
+            uuid: Uuid::new_v4(),
+            protocol_type,
+            seq: 1,
+            edited_at: None,
+            deleted_at: None,
+            reply_to: None,
+        }
+    }
+
+    fn record(id: i32, name: &str) -> RoomRecord {
+        RoomRecord {
+            id,
+            name: name.to_string(),
+            topic: String::new(),
+            created_by: None,
+            created_at: 0,
+            visibility: Visibility::Public.to_i16(),
+            slow_mode_secs: 0,
+            retention_max_age_secs: None,
+            retention_max_messages: None,
+        }
+    }
+
+    #[test]
+    fn replies_point_at_chat_messages_still_in_the_room() {
+        assert!(is_reply_target(&stored(1, POST_PROTOCOL_TYPE), 1));
+        assert!(!is_reply_target(&stored(2, POST_PROTOCOL_TYPE), 1));
+        // the "joined the room" notice stored for `UserJoined`
+        assert!(!is_reply_target(&stored(1, 0), 1));
+        let deleted = StoredMessage {
+            deleted_at: Some(1),
+            ..stored(1, POST_PROTOCOL_TYPE)
+        };
+        assert!(!is_reply_target(&deleted, 1));
+    }
+
+    #[test]
+    fn reactions_are_grouped_per_message_and_emoji_in_order() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let reaction = |message_id, account_uuid, emoji: &str| ReactionRecord {
+            message_id,
+            account_uuid,
+            emoji: emoji.to_string(),
+            created_at: 0,
+        };
+        let mut grouped = group_reactions(vec![
+            reaction(1, alice, "👍"),
+            reaction(1, bob, "🎉"),
+            reaction(1, bob, "👍"),
+            reaction(2, alice, "👍"),
+        ]);
+        let first = grouped.remove(&1).unwrap();
+        assert_eq!(first.len(), 2);
+        assert_eq!(
+            (first[0].emoji.as_str(), &first[0].users),
+            ("👍", &vec![alice, bob])
+        );
+        assert_eq!(
+            (first[1].emoji.as_str(), &first[1].users),
+            ("🎉", &vec![bob])
+        );
+        assert_eq!(grouped.remove(&2).unwrap()[0].users, vec![alice]);
+        assert!(grouped.is_empty());
+    }
+
+    #[test]
+    fn reactions_are_short_and_without_spaces() {
+        assert!(validate_reaction("👍").is_ok());
+        assert!(validate_reaction(":+1:").is_ok());
+        assert!(validate_reaction("").is_err());
+        assert!(validate_reaction("a b").is_err());
+        assert!(validate_reaction(&"x".repeat(MAX_REACTION_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn room_names_are_words_but_not_numbers() {
+        for name in ["general", "rust-lang", "off_topic", "room2"] {
+            assert!(validate_room_name(name).is_ok(), "{}", name);
+        }
+        let too_long = "a".repeat(MAX_ROOM_NAME_LENGTH + 1);
+        for name in [
+            "",
+            "42",
+            "with space",
+            "#general",
+            "ünicode",
+            too_long.as_str(),
+        ] {
+            assert!(validate_room_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rooms_with_unknown_visibility_are_listed_as_public() {
+        let room = room_info(RoomRecord {
+            visibility: 7,
+            slow_mode_secs: -1,
+            retention_max_messages: Some(500),
+            ..record(1000, "general")
+        });
+        assert_eq!(room.visibility, Visibility::Public);
+        assert_eq!(room.slow_mode_secs, 0);
+        assert_eq!(room.retention.max_messages, Some(500));
+    }
+
+    #[test]
+    fn the_directory_lists_the_busiest_rooms_first() {
+        let summary = |id, members, last_activity| RoomSummary {
+            room: room_info(record(id, "room")),
+            members,
+            last_activity,
+        };
+        let mut summaries = vec![
+            summary(1, 0, None),
+            summary(2, 3, Some(10)),
+            summary(3, 0, Some(20)),
+            summary(4, 3, Some(30)),
+        ];
+        sort_directory(&mut summaries);
+        let ids: Vec<i32> = summaries.iter().map(|summary| summary.room.id).collect();
+        assert_eq!(ids, [4, 2, 3, 1]);
+    }
+
+    fn identity(is_moderator: bool) -> Identity {
+        Identity {
+            uuid: Uuid::new_v4(),
+            username: "alice".to_string(),
+            is_moderator,
+        }
+    }
+
+    #[test]
+    fn private_rooms_need_an_invite_unless_created_or_moderated() {
+        let member = identity(false);
+        let creator = identity(false);
+        let private = RoomRecord {
+            visibility: Visibility::Private.to_i16(),
+            created_by: Some(creator.uuid),
+            ..record(1000, "private")
+        };
+        assert!(open_to(&record(1001, "public"), &member));
+        assert!(!open_to(&private, &member));
+        assert!(open_to(&private, &creator));
+        assert!(open_to(&private, &identity(true)));
+    }
+
+    #[test]
+    fn invites_expire_a_day_after_they_were_created() {
+        let creator = identity(false);
+        let invite = new_invite(1000, &creator, 5_000);
+        assert_eq!(invite.created_at, 5_000);
+        assert_eq!(invite.expires_at, 5_000 + 24 * 60 * 60 * 1000);
+        assert_eq!(invite.created_by, creator.uuid);
+        assert_eq!(invite.code.len(), INVITE_CODE_LENGTH);
+        assert_ne!(invite.code, new_invite(1000, &creator, 5_000).code);
+    }
+
+    #[test]
+    fn moderators_act_on_members_and_owners_on_moderators() {
+        let member = RoomRole::Member.to_i16();
+        let moderator = RoomRole::Moderator.to_i16();
+        let owner = RoomRole::Owner.to_i16();
+        let kick = required_role(ModerationAction::Kicked);
+        let promote = required_role(ModerationAction::RoleChanged(RoomRole::Moderator));
+
+        assert!(!outranks(member, member, kick));
+        assert!(outranks(moderator, member, kick));
+        assert!(!outranks(moderator, moderator, kick));
+        assert!(!outranks(moderator, member, promote));
+        assert!(outranks(owner, moderator, kick));
+        assert!(outranks(owner, member, promote));
+        // Server-wide moderators are the only ones in numbered rooms
+        let global = GLOBAL_MODERATOR_RANK;
+        assert!(outranks(global, member, promote));
+        assert!(!outranks(global, global, kick));
+    }
+
+    #[test]
+    fn searches_need_a_query_and_a_bounded_limit() {
+        assert!(validate_search("hello world", 20).is_ok());
+        assert!(validate_search("", 20).is_err());
+        assert!(validate_search(&"a".repeat(MAX_MESSAGE_LENGTH + 1), 20).is_err());
+        assert!(validate_search("hello", 0).is_err());
+        assert!(validate_search("hello", MAX_SEARCH_LIMIT + 1).is_err());
+    }
+
+    #[test]
+    fn sanctions_end_after_their_duration() {
+        assert_eq!(sanction_until(1_000, None).unwrap(), None);
+        assert_eq!(sanction_until(1_000, Some(60)).unwrap(), Some(61_000));
+    }
+
+    #[test]
+    fn sanctions_past_the_stored_range_are_rejected() {
+        for duration_secs in [u64::MAX, u64::MAX / 1000, i64::MAX as u64 / 1000] {
+            let error = sanction_until(1_000, Some(duration_secs)).unwrap_err();
+            assert_eq!(error.code, ErrorCode::InvalidInput);
+        }
+    }
+}